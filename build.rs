@@ -1,40 +1,41 @@
 use cc;
 
 fn main() {
-    fn parse_env(key: &str, default: bool) -> bool {
-        use std::env::{var, VarError};
-
-        match var(key) {
-            Ok(val) => {
-                match &val as &str {
-                    "0" => false,
-                    "1" => true,
-                    _ => default
-                }
-            },
-            Err(VarError::NotPresent) => default,
-            Err(VarError::NotUnicode(_)) => panic!("Environment variable is not unicode: {}", key),
-        }
+    fn feature(name: &str) -> bool {
+        let key = format!("CARGO_FEATURE_{}", name.to_uppercase().replace('-', "_"));
+        std::env::var_os(key).is_some()
     }
 
-    let defensive = parse_env("XM_DEFENSIVE", true);
-    let strings = parse_env("XM_STRINGS", true);
-    let libxmize_delta_samples = parse_env("XM_LIBXMIZE_DELTA_SAMPLES", true);
-    let linear_interpolation = parse_env("XM_LINEAR_INTERPOLATION", true);
-    let ramping = parse_env("XM_RAMPING", true);
-    let debug = parse_env("XM_DEBUG", false);
-    let big_endian = parse_env("XM_BIG_ENDIAN", false);
+    let defensive = feature("defensive");
+    let strings = feature("strings");
+    let libxmize_delta_samples = feature("libxmize-delta-samples");
+    let linear_interpolation = feature("linear-interpolation");
+    let ramping = feature("ramping");
+    let debug = feature("debug");
+    let big_endian = feature("big-endian");
+    let arena = feature("arena");
+
+    // The pure-Rust engine replaces libxm, unless both are asked for
+    if feature("pure-rust") && !feature("libxm-reference") {
+        return;
+    }
+    if std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("wasm32") {
+        panic!("libxm can't be built for wasm32, enable the `pure-rust` (or `wasm`) feature");
+    }
 
     fn on_off(value: bool) -> Option<&'static str> {
         Some(if value { "1" } else { "0" })
     }
 
-    cc::Build::new()
+    let mut build = cc::Build::new();
+    build
         .file("libxm/src/context.c")
         .file("libxm/src/load.c")
         .file("libxm/src/play.c")
         .file("libxm/src/xm.c")
+        .file("src/backend/xm_rs.c")
         .include("libxm/include")
+        .include("libxm/src")
         .define("XM_DEFENSIVE", on_off(defensive))
         .define("XM_STRINGS", on_off(strings))
         .define("XM_LIBXMIZE_DELTA_SAMPLES", on_off(libxmize_delta_samples))
@@ -42,6 +43,13 @@ fn main() {
         .define("XM_RAMPING", on_off(ramping))
         .define("XM_DEBUG", on_off(debug))
         .define("XM_BIG_ENDIAN", on_off(big_endian))
-        .flag("--std=c11")
-        .compile("libxm.a");
+        .flag("--std=c11");
+    if arena {
+        // Allocate with Rust (see src/arena.rs)
+        build
+            .define("malloc", Some("xm_rs_malloc"))
+            .define("calloc", Some("xm_rs_calloc"))
+            .define("free", Some("xm_rs_free"));
+    }
+    build.compile("libxm.a");
 }
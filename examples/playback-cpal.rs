@@ -1,41 +1,11 @@
 use getopts::Options;
+use libxm::integrations::cpal;
 use libxm::XMContext;
 use std::fs::File;
 use std::io::Read;
 use std::env;
-use std::sync::mpsc::Sender;
-
-use cpal::{
-    traits::{DeviceTrait, HostTrait, StreamTrait},
-    StreamConfig,
-};
-
-struct MyCallback {
-    xm: XMContext,
-    last_loop_count: u8,
-    loop_tx: Sender<()>
-}
-
-impl MyCallback {
-    fn callback(&mut self, out: &mut [f32]) {
-        self.xm.generate_samples(out);
-
-        let loop_count = self.xm.loop_count();
-
-        if loop_count != self.last_loop_count {
-            self.last_loop_count = loop_count;
-
-            // Signal the driver that a loop has occured
-            self.loop_tx.send(()).unwrap();
-        }
-    }
-}
 
 fn play_audio(contents: &[u8], rate: u32, max_loops: u8) {
-    use std::sync::mpsc::channel;
-
-    let (loop_tx, loop_rx) = channel();
-
     let mut xm = XMContext::new(&contents, rate).unwrap();
     xm.set_max_loop_count(max_loops);
 
@@ -50,33 +20,11 @@ fn play_audio(contents: &[u8], rate: u32, max_loops: u8) {
     println!("Patterns: {}", xm.number_of_patterns());
     println!("Instruments: {}", xm.number_of_instruments());
 
-    let host = cpal::default_host();
-    let device = host.default_output_device().unwrap();
-    let config = StreamConfig {
-        channels: 2,
-        sample_rate: cpal::SampleRate(rate),
-        buffer_size: cpal::BufferSize::Default
-    };
-
-    let mut my_callback = MyCallback {
-        xm,
-        last_loop_count: 0,
-        loop_tx
-    };
-
-    let cb = move |output: &mut [f32], _: &cpal::OutputCallbackInfo| {
-        my_callback.callback(output);
-    };
-    let ecb = move |err| {
-        println!("ERROR: {:?}", err);
-    };
-
-    let stream = device.build_output_stream(&config, cb, ecb, None).unwrap();
-    stream.play().unwrap();
+    let player = cpal::play(xm).unwrap();
 
     for _ in 0..max_loops {
         // Block until the song has looped
-        loop_rx.recv().unwrap();
+        player.loops().recv().unwrap();
     }
 }
 
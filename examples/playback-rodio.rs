@@ -0,0 +1,71 @@
+use getopts::Options;
+use libxm::integrations::rodio::XmSource;
+use libxm::XMContext;
+use std::fs::File;
+use std::io::Read;
+use std::env;
+
+fn play_audio(contents: &[u8], rate: u32, max_loops: u8) {
+    let mut xm = XMContext::new(&contents, rate).unwrap();
+    xm.set_max_loop_count(max_loops);
+
+    if let Some(module_name) = xm.module_name() {
+        println!("Module name: {}", String::from_utf8_lossy(module_name));
+    }
+    if let Some(tracker_name) = xm.tracker_name() {
+        println!("Tracker: {}", String::from_utf8_lossy(tracker_name));
+    }
+    println!("Channels: {}", xm.number_of_channels());
+    println!("Module length: {}", xm.module_length());
+    println!("Patterns: {}", xm.number_of_patterns());
+    println!("Instruments: {}", xm.number_of_instruments());
+
+    let (_stream, handle) = rodio::OutputStream::try_default().unwrap();
+    let sink = rodio::Sink::try_new(&handle).unwrap();
+
+    // The source ends once the song has looped `max_loops` times
+    sink.append(XmSource::new(xm));
+    sink.sleep_until_end();
+}
+
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!("Usage: {} [options] FILE", program);
+    print!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optopt("r", "rate", "Set the output rate", "RATE");
+    opts.optopt("l", "loops", "Set the maximum number of loops", "LOOPS");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => { m }
+        Err(f) => { panic!("{:?}", f) }
+    };
+
+    let input = if !matches.free.is_empty() {
+        matches.free[0].clone()
+    } else {
+        print_usage(&program, opts);
+        return;
+    };
+
+    let rate = match matches.opt_str("r") {
+        Some(s) => s.parse().unwrap(),
+        None => 48000
+    };
+
+    let max_loops = match matches.opt_str("l") {
+        Some(s) => s.parse().unwrap(),
+        None => 1
+    };
+
+    let mut contents = Vec::new();
+    File::open(&input).unwrap().read_to_end(&mut contents).unwrap();
+
+    play_audio(&contents, rate, max_loops);
+}
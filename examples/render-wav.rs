@@ -0,0 +1,102 @@
+use getopts::Options;
+use libxm::render::{render_to_wav, RenderOptions, SampleFormat};
+use libxm::XMContext;
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Read};
+use std::time::Duration;
+
+fn render(contents: &[u8], output: &str, rate: u32, options: RenderOptions) {
+    let mut xm = XMContext::new(&contents, rate).unwrap();
+
+    if let Some(module_name) = xm.module_name() {
+        println!("Module name: {}", String::from_utf8_lossy(module_name));
+    }
+
+    let writer = BufWriter::new(File::create(output).unwrap());
+    let frames = render_to_wav(&mut xm, writer, options).unwrap();
+
+    println!(
+        "Wrote {} ({:.1} seconds)",
+        output,
+        frames as f64 / rate as f64
+    );
+}
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!("Usage: {} [options] FILE OUTPUT", program);
+    print!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optopt("r", "rate", "Set the output rate", "RATE");
+    opts.optopt("l", "loops", "Set the maximum number of loops", "LOOPS");
+    opts.optopt(
+        "f",
+        "format",
+        "Set the sample format (f32, i16, i24)",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "fade",
+        "Fade out for this many seconds after the last loop",
+        "SECONDS",
+    );
+    opts.optflag("t", "trim", "Trim trailing silence");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            panic!("{:?}", f)
+        }
+    };
+
+    let (input, output) = if matches.free.len() >= 2 {
+        (matches.free[0].clone(), matches.free[1].clone())
+    } else {
+        print_usage(&program, opts);
+        return;
+    };
+
+    let rate = match matches.opt_str("r") {
+        Some(s) => s.parse().unwrap(),
+        None => 48000,
+    };
+
+    let max_loops = match matches.opt_str("l") {
+        Some(s) => s.parse().unwrap(),
+        None => 1,
+    };
+
+    let format = match matches.opt_str("f").as_deref() {
+        Some("f32") => SampleFormat::F32,
+        Some("i16") | None => SampleFormat::I16,
+        Some("i24") => SampleFormat::I24,
+        Some(s) => panic!("Unknown sample format: {}", s),
+    };
+
+    let fade_out = match matches.opt_str("fade") {
+        Some(s) => Duration::from_secs_f64(s.parse().unwrap()),
+        None => Duration::from_secs(0),
+    };
+
+    let options = RenderOptions {
+        format,
+        max_loops,
+        fade_out,
+        trim_silence: matches.opt_present("t"),
+    };
+
+    let mut contents = Vec::new();
+    File::open(&input)
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+
+    render(&contents, &output, rate, options);
+}
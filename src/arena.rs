@@ -0,0 +1,140 @@
+//! The allocator of libxm.
+//!
+//! With the `arena` feature, libxm is built with `malloc`, `calloc` and
+//! `free` renamed to the functions below, so that it allocates with the
+//! global Rust allocator (there may be no C allocator without `std`), or in
+//! a memory arena supplied with `XMContext::new_in()`.
+//!
+//! libxm only allocates when a context is created, and frees when it is
+//! freed. Creating a context holds a lock, during which allocations go to
+//! the arena given to `lock()`, if any.
+
+use crate::ffi::{c_void, size_t};
+use alloc::alloc::{alloc, dealloc, Layout};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+/// Alignment of every block. libxm aligns its own data to 16 bytes.
+const ALIGN: usize = 16;
+/// Every block starts with its size, padded to keep the alignment.
+const HEADER: usize = ALIGN;
+/// The size stored in the header of blocks taken from an arena.
+const IN_ARENA: usize = usize::MAX;
+
+/// A memory arena supplied by the caller.
+#[derive(Copy, Clone)]
+pub(crate) struct Arena {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Arena {
+    pub(crate) fn new(memory: &'static mut [u8]) -> Arena {
+        Arena {
+            ptr: memory.as_mut_ptr(),
+            len: memory.len(),
+        }
+    }
+}
+
+static LOCKED: AtomicBool = AtomicBool::new(false);
+static ARENA: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+static ARENA_LEN: AtomicUsize = AtomicUsize::new(0);
+static ARENA_USED: AtomicUsize = AtomicUsize::new(0);
+// What an arena would need for the allocations made under the lock
+static NEEDED: AtomicUsize = AtomicUsize::new(0);
+
+/// Holds the allocator until dropped.
+pub(crate) struct Lock(());
+
+impl Lock {
+    /// The size of an arena that could hold the allocations made so far.
+    pub(crate) fn needed(&self) -> usize {
+        // The arena itself may not be aligned
+        NEEDED.load(Ordering::Relaxed) + ALIGN - 1
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        ARENA.store(core::ptr::null_mut(), Ordering::Relaxed);
+        LOCKED.store(false, Ordering::Release);
+    }
+}
+
+/// Takes the allocator, to create a context. Allocations go to `arena` if
+/// given, to the global allocator otherwise.
+pub(crate) fn lock(arena: Option<Arena>) -> Lock {
+    while LOCKED
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+
+    let (ptr, len) = arena.map_or((core::ptr::null_mut(), 0), |arena| (arena.ptr, arena.len));
+    ARENA.store(ptr, Ordering::Relaxed);
+    ARENA_LEN.store(len, Ordering::Relaxed);
+    ARENA_USED.store(0, Ordering::Relaxed);
+    NEEDED.store(0, Ordering::Relaxed);
+
+    Lock(())
+}
+
+#[no_mangle]
+unsafe extern "C" fn xm_rs_malloc(size: size_t) -> *mut c_void {
+    let total = match size.checked_add(HEADER + ALIGN - 1) {
+        Some(total) => total & !(ALIGN - 1),
+        None => return core::ptr::null_mut(),
+    };
+    NEEDED.fetch_add(total, Ordering::Relaxed);
+
+    let arena = ARENA.load(Ordering::Relaxed);
+    let (block, tag) = if arena.is_null() {
+        let layout = match Layout::from_size_align(total, ALIGN) {
+            Ok(layout) => layout,
+            Err(_) => return core::ptr::null_mut(),
+        };
+        (alloc(layout), total)
+    } else {
+        let used = ARENA_USED.load(Ordering::Relaxed);
+        let start = arena.add(used).align_offset(ALIGN) + used;
+        let end = match start.checked_add(total) {
+            Some(end) if end <= ARENA_LEN.load(Ordering::Relaxed) => end,
+            _ => return core::ptr::null_mut(),
+        };
+        ARENA_USED.store(end, Ordering::Relaxed);
+        (arena.add(start), IN_ARENA)
+    };
+
+    if block.is_null() {
+        return core::ptr::null_mut();
+    }
+    (block as *mut usize).write(tag);
+    block.add(HEADER) as *mut c_void
+}
+
+#[no_mangle]
+unsafe extern "C" fn xm_rs_calloc(count: size_t, size: size_t) -> *mut c_void {
+    let size = match count.checked_mul(size) {
+        Some(size) => size,
+        None => return core::ptr::null_mut(),
+    };
+    let ptr = xm_rs_malloc(size);
+    if !ptr.is_null() {
+        core::ptr::write_bytes(ptr as *mut u8, 0, size);
+    }
+    ptr
+}
+
+#[no_mangle]
+unsafe extern "C" fn xm_rs_free(ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+    let block = (ptr as *mut u8).sub(HEADER);
+    let tag = (block as *const usize).read();
+    if tag != IN_ARENA {
+        // Arenas belong to the caller
+        dealloc(block, Layout::from_size_align_unchecked(tag, ALIGN));
+    }
+}
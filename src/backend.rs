@@ -0,0 +1,279 @@
+//! The players `XMContext` plays modules with.
+//!
+//! libxm is built unless the `pure-rust` feature is enabled without
+//! `libxm-reference`; the pure-Rust engine is built with `pure-rust`. Both
+//! implement `Player`. What only the engine can do has a default
+//! implementation returning `XMError::Unsupported`.
+
+#[cfg(any(not(feature = "pure-rust"), feature = "libxm-reference"))]
+mod libxm;
+
+use crate::mixer::Mixer;
+use crate::module::Cell;
+use crate::{Backend, ChannelState, Interpolation, PlayingSpeed, Position, SampleData, XMError};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+/// A module being played.
+///
+/// Channel, instrument and sample numbers are checked by `XMContext` before
+/// they are passed on.
+pub(crate) trait Player: Send + Sync {
+    /// Which backend this is.
+    fn backend(&self) -> Backend;
+
+    /// Plays the module into `output`, in stereo.
+    fn generate_samples(&mut self, output: &mut [f32]);
+
+    fn set_max_loop_count(&mut self, loopcnt: u8);
+
+    fn loop_count(&self) -> u8;
+
+    /// None if names aren't kept in memory.
+    fn module_name(&self) -> Option<&[u8]>;
+
+    /// None if names aren't kept in memory.
+    fn tracker_name(&self) -> Option<&[u8]>;
+
+    /// The number of channels of the module, without the free channels.
+    fn number_of_channels(&self) -> u16;
+
+    fn module_length(&self) -> u16;
+
+    fn number_of_patterns(&self) -> u16;
+
+    fn number_of_rows(&self, pattern: u16) -> u16;
+
+    fn number_of_instruments(&self) -> u16;
+
+    fn number_of_samples(&self, instrument: u16) -> u16;
+
+    fn sample_data(&self, instrument: u16, sample: u16) -> SampleData<'_>;
+
+    fn playing_speed(&self) -> PlayingSpeed;
+
+    fn position(&self) -> Position;
+
+    fn latest_trigger_of_instrument(&self, instrument: u16) -> u64;
+
+    fn latest_trigger_of_sample(&self, instrument: u16, sample: u16) -> u64;
+
+    fn latest_trigger_of_channel(&self, channel: u16) -> u64;
+
+    fn channel_state(&self, channel: u16) -> ChannelState;
+
+    fn seek(&mut self, pot: u8, row: u8, tick: u16);
+
+    /// The number of the next tick, 0 if it plays a new row, and how many
+    /// frames play before it. Nothing changes but the sound until then.
+    fn next_tick(&self) -> (u16, usize);
+
+    /// The POT index and row number of the row the next row tick plays,
+    /// after jumps and breaks.
+    fn next_row(&self) -> (u8, u8);
+
+    /// The cell a channel plays until the next row: the cell of the
+    /// pattern, or one played by `play_live()`.
+    fn current_cell(&self, channel: u16) -> Cell;
+
+    /// Whether the note of a channel is held: it had no key off since it
+    /// was triggered.
+    fn is_sustained(&self, channel: u16) -> bool;
+
+    /// Returns whether the channel was muted.
+    fn mute_channel(&mut self, channel: u16, mute: bool) -> bool;
+
+    /// Returns whether the instrument was muted.
+    fn mute_instrument(&mut self, instrument: u16, mute: bool) -> bool;
+
+    /// Goes back to the start of the module. The interpolation, the free
+    /// channels and the mixer settings are kept, everything else is reset.
+    /// On error, the player is left unchanged, except libxm in an arena,
+    /// which frees its context first and then has none to play.
+    fn restart(&mut self) -> Result<(), XMError>;
+
+    /// Creates a player of the same module, at its start, like `restart()`
+    /// but leaving this one as it is.
+    fn try_new(&self) -> Result<Box<dyn Player>, XMError>;
+
+    /// Sets the BPM, until the module sets it with an Fxx effect.
+    fn set_bpm(&mut self, bpm: u16);
+
+    /// Sets the tempo (ticks per row), until the module sets it with an Fxx
+    /// effect.
+    fn set_tempo(&mut self, tempo: u16);
+
+    /// Whether `try_clone()` copies the player.
+    fn can_copy(&self) -> bool {
+        false
+    }
+
+    /// Copies the player, with its whole playback state. None if it can't
+    /// (libxm): the state is played again from the start instead.
+    fn try_clone(&self) -> Result<Option<Box<dyn Player>>, XMError> {
+        Ok(None)
+    }
+
+    fn set_interpolation(&mut self, interpolation: Interpolation) -> Result<(), XMError> {
+        if interpolation == Interpolation::compiled() {
+            Ok(())
+        } else {
+            Err(XMError::Unsupported(
+                "libxm only supports the interpolation it was compiled with",
+            ))
+        }
+    }
+
+    fn set_speed_multiplier(&mut self, _multiplier: f32) -> Result<(), XMError> {
+        Err(XMError::Unsupported(
+            "libxm can't change the speed without the pitch",
+        ))
+    }
+
+    fn set_pitch_multiplier(&mut self, _multiplier: f32) -> Result<(), XMError> {
+        Err(XMError::Unsupported(
+            "libxm can't change the pitch without the speed",
+        ))
+    }
+
+    fn set_transpose(&mut self, _semitones: i8) -> Result<(), XMError> {
+        Err(XMError::Unsupported("libxm can't transpose"))
+    }
+
+    /// Adds `count` free channels after the others.
+    fn reserve_channels(&mut self, _count: u16) -> Result<(), XMError> {
+        Err(XMError::Unsupported("libxm has no free channels"))
+    }
+
+    /// Plays a note on the next tick, as if it was read in the pattern with
+    /// `volume` (from 0 to 64) in the volume column.
+    fn note_on(
+        &mut self,
+        channel: u16,
+        instrument: u16,
+        note: u8,
+        volume: u8,
+    ) -> Result<(), XMError> {
+        // Cells can't hold more than 255 instruments
+        let instrument = u8::try_from(instrument)
+            .map_err(|_| XMError::Unsupported("instruments above 255 can't be played"))?;
+        self.play_live(
+            channel,
+            Cell {
+                note,
+                instrument,
+                volume_column: 0x10 + volume.min(0x40),
+                ..Cell::default()
+            },
+        );
+        Ok(())
+    }
+
+    /// Releases the note of a channel on the next tick, as if a key off was
+    /// read in the pattern.
+    fn note_off(&mut self, channel: u16) {
+        self.play_live(
+            channel,
+            Cell {
+                note: Cell::KEY_OFF,
+                ..Cell::default()
+            },
+        );
+    }
+
+    /// Plays a cell in a channel on the next tick, as read by
+    /// `delayed_to_tick()`, in place of the cell of the pattern until the
+    /// next row. On the first tick of a row, it only replaces a cell
+    /// without an effect; otherwise, it waits for the next tick.
+    fn play_live(&mut self, channel: u16, cell: Cell);
+
+    /// Plays the module into `master`, and gives what each channel
+    /// contributes to every frame to `channel_output`: the index of the
+    /// frame, the index of the channel, the left and right values and the
+    /// panning the channel was mixed with.
+    #[cfg(feature = "pure-rust")]
+    fn generate_samples_per_channel(
+        &mut self,
+        _master: &mut [f32],
+        _channel_output: &mut dyn FnMut(usize, usize, f32, f32, f32),
+    ) -> Result<(), XMError> {
+        Err(XMError::Unsupported(
+            "libxm can't render channels separately",
+        ))
+    }
+
+    /// Applies mixer settings on top of what the module plays.
+    fn set_mixer(&mut self, mixer: &Mixer) -> Result<(), XMError>;
+}
+
+/// Gets how a cell played by `note_on()` or `note_off()` is read on a tick:
+/// with a note delay (EDx) to that tick. That is how libxm can be made to
+/// play it, and the engine plays it the same way.
+pub(crate) fn delayed_to_tick(cell: Cell, tick: u8) -> Cell {
+    Cell {
+        effect_type: 0xE,
+        effect_param: 0xD0 | tick.min(0x0F),
+        ..cell
+    }
+}
+
+/// Gets how many frames play before the next tick, from the number of
+/// samples left in the current one. Both players start a tick when a frame
+/// starts with none left, and count one per frame.
+pub(crate) fn frames_before_tick(remaining_samples_in_tick: f32) -> usize {
+    if remaining_samples_in_tick <= 0.0 {
+        return 0;
+    }
+    let whole = remaining_samples_in_tick as usize;
+    if (whole as f32) < remaining_samples_in_tick {
+        whole + 1
+    } else {
+        whole
+    }
+}
+
+/// Loads a module (in the XM format) with a backend. Only libxm can use an
+/// arena.
+pub(crate) fn create(
+    backend: Backend,
+    mod_data: &Arc<[u8]>,
+    rate: u32,
+    #[cfg(feature = "arena")] arena: Option<&'static mut [u8]>,
+) -> Result<Box<dyn Player>, XMError> {
+    match backend {
+        #[cfg(any(not(feature = "pure-rust"), feature = "libxm-reference"))]
+        Backend::Libxm => Ok(Box::new(libxm::Libxm::new(
+            Arc::clone(mod_data),
+            rate,
+            #[cfg(feature = "arena")]
+            arena.map(crate::arena::Arena::new),
+        )?)),
+        #[cfg(feature = "pure-rust")]
+        Backend::PureRust => {
+            #[cfg(feature = "arena")]
+            if arena.is_some() {
+                return Err(XMError::Unsupported(
+                    "the pure-Rust engine can't allocate in an arena",
+                ));
+            }
+            Ok(Box::new(crate::engine::Context::new(mod_data, rate)?))
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(XMError::Unsupported("this backend isn't built")),
+    }
+}
+
+/// Gets the size of the arena libxm needs to play a module (in the XM
+/// format).
+#[cfg(feature = "arena")]
+pub(crate) fn memory_needed(mod_data: &[u8], rate: u32) -> Result<usize, XMError> {
+    #[cfg(any(not(feature = "pure-rust"), feature = "libxm-reference"))]
+    {
+        libxm::Libxm::memory_needed(mod_data, rate)
+    }
+    #[cfg(all(feature = "pure-rust", not(feature = "libxm-reference")))]
+    {
+        let _ = (mod_data, rate);
+        Err(XMError::Unsupported("libxm isn't built"))
+    }
+}
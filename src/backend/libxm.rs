@@ -0,0 +1,548 @@
+//! libxm, through its C API.
+
+use super::{delayed_to_tick, Player};
+#[cfg(feature = "arena")]
+use crate::arena;
+use crate::mixer::Mixer;
+use crate::module::{self, Cell};
+use crate::{ffi, Backend, ChannelState, PlayingSpeed, Position, SampleData, XMError};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// A libxm context.
+pub(crate) struct Libxm {
+    raw: *mut ffi::xm_context_t,
+    // What the context was created from, to create it again
+    mod_data: Arc<[u8]>,
+    rate: u32,
+    // The memory of the context, if given to `XMContext::new_in()`
+    #[cfg(feature = "arena")]
+    arena: Option<arena::Arena>,
+    // Per channel
+    live: Box<[Live]>,
+    // Applied on top of what libxm plays
+    mixer: Mixer,
+    // Follows `mixer.master_volume` a little on every generated sample
+    master_volume: f32,
+    // The volumes the channels were mixed with on the frame of the latest
+    // tick, per channel
+    #[cfg(feature = "ramping")]
+    volumes: Box<[[f32; 2]]>,
+}
+
+/// How much the master volume changes per sample, like the volumes of the
+/// channels in libxm.
+#[cfg(feature = "ramping")]
+const VOLUME_RAMP: f32 = 1.0 / 128.0;
+
+// libxm contexts don't refer to anything outside of their own memory and
+// the live cells of `Libxm`, and the methods taking `&self` only read from
+// them.
+unsafe impl Send for Libxm {}
+unsafe impl Sync for Libxm {}
+
+/// A cell played by `note_on()` or `note_off()` in a channel. libxm reads
+/// cells from the pattern data on the first tick of a row, and from the
+/// channel afterwards: the cell is put in one or the other right before its
+/// tick.
+#[derive(Copy, Clone, Default)]
+struct Live {
+    // Waiting for its tick
+    pending: Option<Cell>,
+    // What the channel plays until the next row, once the cell is played
+    slot: ffi::xm_pattern_slot_t,
+    // The cell of the pattern `slot` is written over for a row tick, and
+    // what it held
+    replaced: Option<(*mut ffi::xm_pattern_slot_t, ffi::xm_pattern_slot_t)>,
+}
+
+impl Libxm {
+    pub(crate) fn new(
+        mod_data: Arc<[u8]>,
+        rate: u32,
+        #[cfg(feature = "arena")] arena: Option<arena::Arena>,
+    ) -> Result<Libxm, XMError> {
+        let raw = {
+            #[cfg(feature = "arena")]
+            let _lock = arena::lock(arena);
+            create_raw(&mod_data, rate)?
+        };
+        let mut libxm = Libxm {
+            raw,
+            mod_data,
+            rate,
+            #[cfg(feature = "arena")]
+            arena,
+            live: Box::default(),
+            mixer: Mixer::default(),
+            master_volume: 1.0,
+            #[cfg(feature = "ramping")]
+            volumes: Box::default(),
+        };
+
+        let num_channels = libxm.number_of_channels() as usize;
+        libxm.live = try_boxed(Live::default(), num_channels)?;
+        #[cfg(feature = "ramping")]
+        {
+            libxm.volumes = try_boxed([0.0; 2], num_channels)?;
+        }
+        Ok(libxm)
+    }
+
+    #[cfg(feature = "arena")]
+    pub(crate) fn memory_needed(mod_data: &[u8], rate: u32) -> Result<usize, XMError> {
+        let lock = arena::lock(None);
+        let raw = create_raw(mod_data, rate)?;
+        unsafe { ffi::xm_free_context(raw) };
+        Ok(lock.needed())
+    }
+
+    /// Puts the live cells in place for a tick, which the next frame
+    /// starts with.
+    fn start_live_cells(&mut self, tick: u16) {
+        let next_row = (tick == 0).then(|| self.next_row());
+
+        for (i, live) in self.live.iter_mut().enumerate() {
+            let cell = match live.pending {
+                Some(cell) => cell,
+                None => continue,
+            };
+            let channel = i as u16 + 1;
+
+            match (next_row, u8::try_from(tick)) {
+                (Some((pattern_index, row)), _) => {
+                    let replaced =
+                        unsafe { ffi::xm_rs_get_slot(self.raw, pattern_index, row, channel) };
+                    if replaced.is_null() {
+                        continue;
+                    }
+                    // Only a cell without an effect is replaced
+                    let original = unsafe { *replaced };
+                    if original.effect_type != 0 || original.effect_param != 0 {
+                        continue;
+                    }
+                    live.slot = slot(delayed_to_tick(cell, 0));
+                    unsafe { *replaced = live.slot };
+                    live.replaced = Some((replaced, original));
+                }
+                (None, Ok(tick)) => {
+                    live.slot = slot(delayed_to_tick(cell, tick));
+                    unsafe { ffi::xm_rs_set_current_slot(self.raw, channel, &mut live.slot, tick) };
+                }
+                // The note delay can't hold the tick
+                (None, Err(_)) => continue,
+            }
+            live.pending = None;
+        }
+    }
+
+    /// Computes the volumes of the channels again with the mixer settings,
+    /// right after the frame of a tick if `after_tick`.
+    #[cfg(feature = "ramping")]
+    fn mix_channels(&mut self, after_tick: bool) {
+        let before = if after_tick {
+            self.volumes.as_ptr() as *const f32
+        } else {
+            core::ptr::null()
+        };
+
+        for i in 0..self.volumes.len() {
+            // libxm's own panning if negative
+            let panning = self.mixer.panning(i).unwrap_or(-1.0);
+            unsafe {
+                ffi::xm_rs_mix_channel(
+                    self.raw,
+                    i as u16 + 1,
+                    before,
+                    self.mixer.volume(i),
+                    panning,
+                    self.mixer.stereo_separation,
+                )
+            };
+        }
+    }
+
+    /// Applies the master volume to generated samples.
+    fn apply_master_volume(&mut self, output: &mut [f32]) {
+        let goal = self.mixer.master_volume;
+        if self.master_volume == 1.0 && goal == 1.0 {
+            return;
+        }
+
+        for frame in output.chunks_exact_mut(2) {
+            #[cfg(feature = "ramping")]
+            if self.master_volume > goal {
+                self.master_volume = (self.master_volume - VOLUME_RAMP).max(goal);
+            } else if self.master_volume < goal {
+                self.master_volume = (self.master_volume + VOLUME_RAMP).min(goal);
+            }
+            #[cfg(not(feature = "ramping"))]
+            {
+                self.master_volume = goal;
+            }
+            frame[0] *= self.master_volume;
+            frame[1] *= self.master_volume;
+        }
+    }
+
+    /// Resets what goes with the context after it is created again.
+    fn restarted(&mut self) {
+        self.live.fill(Live::default());
+        self.master_volume = self.mixer.master_volume;
+        #[cfg(feature = "ramping")]
+        self.mix_channels(false);
+    }
+
+    /// Puts back the cells of the pattern replaced for a row tick, once it
+    /// is played. The channels play the live cells until the next row.
+    fn end_live_cells(&mut self) {
+        for (i, live) in self.live.iter_mut().enumerate() {
+            if let Some((replaced, original)) = live.replaced.take() {
+                unsafe {
+                    *replaced = original;
+                    ffi::xm_rs_set_current_slot(self.raw, i as u16 + 1, &mut live.slot, 0);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Libxm {
+    fn drop(&mut self) {
+        // Null after a failed restart
+        if self.raw.is_null() {
+            return;
+        }
+        unsafe { ffi::xm_free_context(self.raw) };
+    }
+}
+
+impl Player for Libxm {
+    fn backend(&self) -> Backend {
+        Backend::Libxm
+    }
+
+    fn generate_samples(&mut self, output: &mut [f32]) {
+        let frames = output.len() / 2;
+        let mut offset = 0;
+        let mixes_channels = self.mixer.mixes_channels();
+
+        // Up to the tick of the last live cell, or to the end if the
+        // channels are mixed after each tick, frame by frame on ticks
+        while offset < frames
+            && (mixes_channels || self.live.iter().any(|live| live.pending.is_some()))
+        {
+            let (tick, before_tick) = self.next_tick();
+            if before_tick > 0 {
+                let end = frames.min(offset + before_tick);
+                generate(self.raw, &mut output[2 * offset..2 * end]);
+                offset = end;
+                continue;
+            }
+
+            #[cfg(feature = "ramping")]
+            if mixes_channels {
+                let volumes = self.volumes.as_mut_ptr() as *mut f32;
+                unsafe { ffi::xm_rs_get_actual_volumes(self.raw, volumes) };
+            }
+            self.start_live_cells(tick);
+            generate(self.raw, &mut output[2 * offset..2 * offset + 2]);
+            self.end_live_cells();
+            #[cfg(feature = "ramping")]
+            if mixes_channels {
+                self.mix_channels(true);
+            }
+            offset += 1;
+        }
+
+        generate(self.raw, &mut output[2 * offset..]);
+        self.apply_master_volume(output);
+    }
+
+    fn set_max_loop_count(&mut self, loopcnt: u8) {
+        unsafe { ffi::xm_set_max_loop_count(self.raw, loopcnt) };
+    }
+
+    fn loop_count(&self) -> u8 {
+        unsafe { ffi::xm_get_loop_count(self.raw) }
+    }
+
+    fn module_name(&self) -> Option<&[u8]> {
+        unsafe { c_str(ffi::xm_get_module_name(self.raw)) }
+    }
+
+    fn tracker_name(&self) -> Option<&[u8]> {
+        unsafe { c_str(ffi::xm_get_tracker_name(self.raw)) }
+    }
+
+    fn number_of_channels(&self) -> u16 {
+        unsafe { ffi::xm_get_number_of_channels(self.raw) }
+    }
+
+    fn module_length(&self) -> u16 {
+        unsafe { ffi::xm_get_module_length(self.raw) }
+    }
+
+    fn number_of_patterns(&self) -> u16 {
+        unsafe { ffi::xm_get_number_of_patterns(self.raw) }
+    }
+
+    fn number_of_rows(&self, pattern: u16) -> u16 {
+        unsafe { ffi::xm_get_number_of_rows(self.raw, pattern) }
+    }
+
+    fn number_of_instruments(&self) -> u16 {
+        unsafe { ffi::xm_get_number_of_instruments(self.raw) }
+    }
+
+    fn number_of_samples(&self, instrument: u16) -> u16 {
+        unsafe { ffi::xm_get_number_of_samples(self.raw, instrument) }
+    }
+
+    fn sample_data(&self, instrument: u16, sample: u16) -> SampleData<'_> {
+        let (mut length, mut bits) = (0, 0);
+        let ptr = unsafe {
+            ffi::xm_get_sample_waveform(self.raw, instrument, sample, &mut length, &mut bits)
+        };
+
+        if ptr.is_null() || length == 0 {
+            if bits == 16 {
+                SampleData::Bits16(&[])
+            } else {
+                SampleData::Bits8(&[])
+            }
+        } else if bits == 16 {
+            SampleData::Bits16(unsafe { core::slice::from_raw_parts(ptr as *const i16, length) })
+        } else {
+            SampleData::Bits8(unsafe { core::slice::from_raw_parts(ptr as *const i8, length) })
+        }
+    }
+
+    fn playing_speed(&self) -> PlayingSpeed {
+        let (mut bpm, mut tempo) = (0, 0);
+        unsafe { ffi::xm_get_playing_speed(self.raw, &mut bpm, &mut tempo) };
+        PlayingSpeed { bpm, tempo }
+    }
+
+    fn position(&self) -> Position {
+        let (mut pattern_index, mut pattern, mut row) = (0, 0, 0);
+        let mut samples = 0;
+        unsafe {
+            ffi::xm_get_position(
+                self.raw,
+                &mut pattern_index,
+                &mut pattern,
+                &mut row,
+                &mut samples,
+            )
+        };
+
+        Position {
+            pattern_index,
+            pattern,
+            row,
+            samples,
+        }
+    }
+
+    fn latest_trigger_of_instrument(&self, instrument: u16) -> u64 {
+        unsafe { ffi::xm_get_latest_trigger_of_instrument(self.raw, instrument) }
+    }
+
+    fn latest_trigger_of_sample(&self, instrument: u16, sample: u16) -> u64 {
+        unsafe { ffi::xm_get_latest_trigger_of_sample(self.raw, instrument, sample) }
+    }
+
+    fn latest_trigger_of_channel(&self, channel: u16) -> u64 {
+        unsafe { ffi::xm_get_latest_trigger_of_channel(self.raw, channel) }
+    }
+
+    fn channel_state(&self, channel: u16) -> ChannelState {
+        unsafe {
+            if ffi::xm_is_channel_active(self.raw, channel) {
+                ChannelState {
+                    active: true,
+                    instrument: ffi::xm_get_instrument_of_channel(self.raw, channel),
+                    frequency: ffi::xm_get_frequency_of_channel(self.raw, channel),
+                    volume: ffi::xm_get_volume_of_channel(self.raw, channel),
+                    panning: ffi::xm_get_panning_of_channel(self.raw, channel),
+                }
+            } else {
+                ChannelState {
+                    active: false,
+                    instrument: 0,
+                    frequency: 0.0,
+                    volume: 0.0,
+                    panning: 0.5,
+                }
+            }
+        }
+    }
+
+    fn seek(&mut self, pot: u8, row: u8, tick: u16) {
+        unsafe { ffi::xm_seek(self.raw, pot, row, tick) };
+    }
+
+    fn next_tick(&self) -> (u16, usize) {
+        let (mut tick, mut remaining) = (0, 0.0);
+        unsafe { ffi::xm_rs_get_next_tick(self.raw, &mut tick, &mut remaining) };
+        (tick, super::frames_before_tick(remaining))
+    }
+
+    fn next_row(&self) -> (u8, u8) {
+        let (mut pattern_index, mut row) = (0, 0);
+        unsafe { ffi::xm_rs_get_next_row(self.raw, &mut pattern_index, &mut row) };
+        (pattern_index, row)
+    }
+
+    fn current_cell(&self, channel: u16) -> Cell {
+        let slot = unsafe { ffi::xm_rs_get_current_slot(self.raw, channel) };
+        Cell {
+            note: slot.note,
+            instrument: slot.instrument,
+            volume_column: slot.volume_column,
+            effect_type: slot.effect_type,
+            effect_param: slot.effect_param,
+        }
+    }
+
+    fn is_sustained(&self, channel: u16) -> bool {
+        unsafe { ffi::xm_rs_is_sustained(self.raw, channel) }
+    }
+
+    fn mute_channel(&mut self, channel: u16, mute: bool) -> bool {
+        unsafe { ffi::xm_mute_channel(self.raw, channel, mute) }
+    }
+
+    fn mute_instrument(&mut self, instrument: u16, mute: bool) -> bool {
+        unsafe { ffi::xm_mute_instrument(self.raw, instrument, mute) }
+    }
+
+    fn restart(&mut self) -> Result<(), XMError> {
+        #[cfg(feature = "arena")]
+        if self.arena.is_some() {
+            // The arena only holds one context, free this one first. The
+            // new one makes the same allocations as the first one did in
+            // the same arena, but if creating it fails anyway, there is no
+            // context left.
+            unsafe { ffi::xm_free_context(self.raw) };
+            self.raw = core::ptr::null_mut();
+            let _lock = arena::lock(self.arena);
+            self.raw = create_raw(&self.mod_data, self.rate)?;
+            self.restarted();
+            return Ok(());
+        }
+
+        let raw = {
+            #[cfg(feature = "arena")]
+            let _lock = arena::lock(None);
+            create_raw(&self.mod_data, self.rate)?
+        };
+        unsafe { ffi::xm_free_context(self.raw) };
+        self.raw = raw;
+        self.restarted();
+        Ok(())
+    }
+
+    fn try_new(&self) -> Result<Box<dyn Player>, XMError> {
+        #[cfg(feature = "arena")]
+        if self.arena.is_some() {
+            return Err(XMError::Unsupported(
+                "an arena only holds one libxm context",
+            ));
+        }
+
+        Ok(Box::new(Libxm::new(
+            Arc::clone(&self.mod_data),
+            self.rate,
+            #[cfg(feature = "arena")]
+            None,
+        )?))
+    }
+
+    fn set_bpm(&mut self, bpm: u16) {
+        unsafe { ffi::xm_rs_set_bpm(self.raw, bpm) };
+    }
+
+    fn set_tempo(&mut self, tempo: u16) {
+        unsafe { ffi::xm_rs_set_tempo(self.raw, tempo) };
+    }
+
+    fn play_live(&mut self, channel: u16, cell: Cell) {
+        self.live[channel as usize - 1].pending = Some(cell);
+    }
+
+    /// The master volume changes right away, ramped if the `ramping`
+    /// feature is enabled. Channel volumes and pannings need the `ramping`
+    /// feature: libxm mixes the first frame of a tick before they can be
+    /// applied, and only its ramping can follow them from the next frame.
+    fn set_mixer(&mut self, mixer: &Mixer) -> Result<(), XMError> {
+        #[cfg(not(feature = "ramping"))]
+        if mixer.mixes_channels() {
+            return Err(XMError::Unsupported(
+                "libxm only mixes channels with the `ramping` feature",
+            ));
+        }
+
+        self.mixer.clone_from(mixer);
+        #[cfg(feature = "ramping")]
+        self.mix_channels(false);
+        Ok(())
+    }
+}
+
+fn generate(raw: *mut ffi::xm_context_t, output: &mut [f32]) {
+    unsafe { ffi::xm_generate_samples(raw, output.as_mut_ptr(), output.len() / 2) };
+}
+
+/// Creates a slice, or fails if there isn't enough memory.
+fn try_boxed<T: Clone>(value: T, len: usize) -> Result<Box<[T]>, XMError> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(len)
+        .map_err(|_| XMError::MemoryAllocationFailed)?;
+    vec.resize(len, value);
+    Ok(vec.into_boxed_slice())
+}
+
+/// Converts a cell to libxm's.
+fn slot(cell: Cell) -> ffi::xm_pattern_slot_t {
+    ffi::xm_pattern_slot_t {
+        note: cell.note,
+        instrument: cell.instrument,
+        volume_column: cell.volume_column,
+        effect_type: cell.effect_type,
+        effect_param: cell.effect_param,
+    }
+}
+
+/// Creates a libxm context. With the `arena` feature, it allocates as set
+/// by the lock on the allocator, which the caller holds.
+fn create_raw(mod_data: &[u8], rate: u32) -> Result<*mut ffi::xm_context_t, XMError> {
+    unsafe {
+        let mut raw: *mut ffi::xm_context = core::ptr::null_mut();
+
+        let mod_data_ptr = mod_data.as_ptr() as *const ffi::c_char;
+        let mod_data_len = mod_data.len() as ffi::size_t;
+
+        let result = ffi::xm_create_context_safe(&mut raw, mod_data_ptr, mod_data_len, rate);
+        match result {
+            0 => Ok(raw),
+            1 => Err(match module::check_sanity(mod_data) {
+                Err(err) => XMError::InvalidModule(err),
+                Ok(()) => XMError::ModuleDataNotSane,
+            }),
+            2 => Err(XMError::MemoryAllocationFailed),
+            _ => Err(XMError::Unknown(result)),
+        }
+    }
+}
+
+/// Gets the bytes of a C string, if the pointer isn't null.
+unsafe fn c_str<'a>(ptr: *const ffi::c_char) -> Option<&'a [u8]> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(core::ffi::CStr::from_ptr(ptr).to_bytes())
+    }
+}
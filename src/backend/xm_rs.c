@@ -0,0 +1,144 @@
+/* Controls and state libxm has no function for. They are compiled with
+ * libxm, with the same settings, since they use the fields of its context. */
+
+#include "xm_internal.h"
+#include <math.h>
+
+void xm_rs_set_bpm(xm_context_t* ctx, uint16_t bpm) {
+	ctx->bpm = bpm;
+}
+
+void xm_rs_set_tempo(xm_context_t* ctx, uint16_t tempo) {
+	ctx->tempo = tempo;
+}
+
+void xm_rs_get_next_tick(xm_context_t* ctx, uint16_t* tick, float* remaining_samples_in_tick) {
+	*tick = ctx->current_tick;
+	*remaining_samples_in_tick = ctx->remaining_samples_in_tick;
+}
+
+/* Works out the POT index and row the next row tick plays, after jumps and
+ * breaks, like xm_row() does. */
+void xm_rs_get_next_row(xm_context_t* ctx, uint8_t* pattern_index, uint8_t* row) {
+	uint8_t index = ctx->current_table_index;
+	uint8_t next_row = ctx->current_row;
+
+	if(ctx->position_jump || ctx->pattern_break) {
+		index = ctx->position_jump ? ctx->jump_dest : (uint8_t)(index + 1);
+		next_row = ctx->jump_row;
+
+		/* Loop if necessary */
+		if(index >= ctx->module.length) {
+			index = ctx->module.restart_position;
+			if(index >= ctx->module.length) {
+				index = 0;
+			}
+		}
+	}
+
+	if(next_row >= ctx->module.patterns[ctx->module.pattern_table[index]].num_rows) {
+		/* A pattern break past the end of the pattern */
+		next_row = 0;
+	}
+
+	*pattern_index = index;
+	*row = next_row;
+}
+
+/* Gets the cell of a channel in a row of the POT, NULL if there is none. */
+xm_pattern_slot_t* xm_rs_get_slot(xm_context_t* ctx, uint8_t pattern_index, uint8_t row, uint16_t channel) {
+	if(pattern_index >= ctx->module.length || channel < 1 || channel > ctx->module.num_channels) {
+		return NULL;
+	}
+
+	xm_pattern_t* pattern = ctx->module.patterns + ctx->module.pattern_table[pattern_index];
+	if(row >= pattern->num_rows) {
+		return NULL;
+	}
+	return pattern->slots + row * ctx->module.num_channels + channel - 1;
+}
+
+/* Gets the cell a channel plays until the next row. */
+xm_pattern_slot_t xm_rs_get_current_slot(xm_context_t* ctx, uint16_t channel) {
+	xm_channel_context_t* ch = ctx->channels + channel - 1;
+	if(ch->current == NULL) {
+		xm_pattern_slot_t empty = { 0 };
+		return empty;
+	}
+	return *ch->current;
+}
+
+/* Gets whether the note of a channel is held: it had no key off since it
+ * was triggered. */
+bool xm_rs_is_sustained(xm_context_t* ctx, uint16_t channel) {
+	return ctx->channels[channel - 1].sustained;
+}
+
+/* Makes a channel play a cell until the next row, as if the row had it,
+ * with the note delay of its EDx effect. The cell must stay in memory
+ * until then. */
+void xm_rs_set_current_slot(xm_context_t* ctx, uint16_t channel, xm_pattern_slot_t* slot, uint8_t note_delay_param) {
+	xm_channel_context_t* ch = ctx->channels + channel - 1;
+	ch->current = slot;
+	ch->note_delay_param = note_delay_param;
+}
+
+#if XM_RAMPING
+/* Gets the volumes each channel mixes the next frame with. */
+void xm_rs_get_actual_volumes(xm_context_t* ctx, float* volumes) {
+	for(uint16_t i = 0; i < ctx->module.num_channels; ++i) {
+		volumes[2 * i] = ctx->channels[i].actual_volume[0];
+		volumes[2 * i + 1] = ctx->channels[i].actual_volume[1];
+	}
+}
+
+/* Computes the volume of each side of a channel like xm_tick() does, with
+ * the mixer settings on top: a volume multiplier, a panning replacing the
+ * panning of the channel unless it is negative, and a stereo separation.
+ *
+ * After the frame of a tick, `before` has the volumes the frame was mixed
+ * with, to slide from them towards the new volumes instead of the volumes
+ * xm_tick() computed. It is NULL between ticks. */
+void xm_rs_mix_channel(xm_context_t* ctx, uint16_t channel, const float* before, float volume, float panning, float separation) {
+	xm_channel_context_t* ch = ctx->channels + channel - 1;
+
+	if(panning < 0.f) {
+		panning = ch->panning;
+	}
+	panning = panning +
+		(ch->panning_envelope_panning - .5f) * (.5f - fabsf(panning - .5f)) * 2.0f;
+	if(separation != 1.f) {
+		panning = .5f + (panning - .5f) * separation;
+	}
+
+	if(ch->tremor_on) {
+		volume = .0f;
+	} else {
+		float v = ch->volume + ch->tremolo_volume;
+		if(v > 1.f) v = 1.f;
+		if(v < 0.f) v = 0.f;
+		volume = v * ch->fadeout_volume * ch->volume_envelope_volume * volume;
+	}
+
+	ch->target_volume[0] = volume * sqrtf(1.f - panning);
+	ch->target_volume[1] = volume * sqrtf(panning);
+
+	if(before == NULL || (ctx->max_loop_count > 0 && ctx->loop_count >= ctx->max_loop_count)
+	   || ch->instrument == NULL || ch->sample == NULL || ch->sample_position < 0) {
+		/* Between ticks, or the frame didn't mix the channel */
+		return;
+	}
+	for(uint8_t side = 0; side < 2; ++side) {
+		float actual = before[2 * (channel - 1) + side];
+		float target = ch->target_volume[side];
+		if(actual > target) {
+			actual -= ctx->volume_ramp;
+			if(actual < target) actual = target;
+		} else if(actual < target) {
+			actual += ctx->volume_ramp;
+			if(actual > target) actual = target;
+		}
+		ch->actual_volume[side] = actual;
+	}
+}
+#endif
@@ -0,0 +1,720 @@
+//! A port of the libxm player to Rust.
+//!
+//! `XMContext` plays modules with this engine (`Backend::PureRust`) when the
+//! crate is built with the `pure-rust` feature. The engine follows libxm
+//! closely, including its quirks; `tests/compare.rs` compares both.
+
+mod play;
+
+use crate::backend::Player;
+use crate::mixer::Mixer;
+use crate::module::{self, Envelope, FrequencyType, Module, Pattern, SampleBuffer};
+use crate::{
+    math, try_to_vec, Backend, ChannelState, Interpolation, LoopType, PlayingSpeed, Position,
+    SampleData, XMError,
+};
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+#[cfg(feature = "ramping")]
+const SAMPLE_RAMPING_POINTS: usize = 0x20;
+
+/// A sample, as played by the engine.
+struct Sample {
+    data: SampleBuffer,
+    length: u32,
+    loop_start: u32,
+    loop_length: u32,
+    loop_end: u32,
+    loop_type: LoopType,
+    volume: f32,
+    finetune: i8,
+    panning: f32,
+    relative_note: i8,
+}
+
+impl Sample {
+    /// Gets a sample point, from -1.0 to 1.0.
+    #[inline]
+    fn at(&self, index: u32) -> f32 {
+        match self.data {
+            SampleBuffer::Bits8(ref data) => data[index as usize] as f32 / 128.0,
+            SampleBuffer::Bits16(ref data) => data[index as usize] as f32 / 32768.0,
+        }
+    }
+}
+
+/// An instrument, as played by the engine.
+struct Instrument {
+    sample_of_notes: [u8; module::NUM_NOTES],
+    volume_envelope: Envelope,
+    panning_envelope: Envelope,
+    vibrato_type: u8,
+    vibrato_sweep: u8,
+    vibrato_depth: u8,
+    vibrato_rate: u8,
+    volume_fadeout: u16,
+    samples: Vec<Sample>,
+    // Index of the first sample in `Context::sample_triggers`
+    first_sample: usize,
+}
+
+/// The playback state of a channel. Field names follow libxm.
+#[derive(Clone, Default)]
+struct Channel {
+    note: f32,
+    // The original note before effect modifications, as read in the pattern
+    orig_note: f32,
+    // Index of the instrument
+    instrument: Option<usize>,
+    // Indices of the instrument and the sample. The instrument can differ
+    // from `instrument` until the next note is triggered.
+    sample: Option<(usize, usize)>,
+    current: module::Cell,
+
+    sample_position: f32,
+    period: f32,
+    frequency: f32,
+    step: f32,
+    // For ping-pong samples: true is -->, false is <--
+    ping: bool,
+
+    // Ideally between 0 (muted) and 1 (loudest)
+    volume: f32,
+    // Between 0 (left) and 1 (right); 0.5 is centered
+    panning: f32,
+
+    autovibrato_ticks: u16,
+
+    sustained: bool,
+    fadeout_volume: f32,
+    volume_envelope_volume: f32,
+    panning_envelope_panning: f32,
+    volume_envelope_frame_count: u16,
+    panning_envelope_frame_count: u16,
+
+    autovibrato_note_offset: f32,
+
+    arp_in_progress: bool,
+    arp_note_offset: u8,
+    volume_slide_param: u8,
+    fine_volume_slide_param: u8,
+    global_volume_slide_param: u8,
+    panning_slide_param: u8,
+    portamento_up_param: u8,
+    portamento_down_param: u8,
+    fine_portamento_up_param: u8,
+    fine_portamento_down_param: u8,
+    extra_fine_portamento_up_param: u8,
+    extra_fine_portamento_down_param: u8,
+    tone_portamento_param: u8,
+    tone_portamento_target_period: f32,
+    multi_retrig_param: u8,
+    note_delay_param: u8,
+    // Where to restart a E6y loop
+    pattern_loop_origin: u8,
+    // How many loop passes have been done
+    pattern_loop_count: u8,
+    vibrato_in_progress: bool,
+    vibrato_waveform: u8,
+    // True if a new note retriggers the waveform
+    vibrato_waveform_retrigger: bool,
+    vibrato_param: u8,
+    // Position in the waveform
+    vibrato_ticks: u16,
+    vibrato_note_offset: f32,
+    tremolo_waveform: u8,
+    tremolo_waveform_retrigger: bool,
+    tremolo_param: u8,
+    tremolo_ticks: u8,
+    tremolo_volume: f32,
+    tremor_param: u8,
+    tremor_on: bool,
+
+    latest_trigger: u64,
+    muted: bool,
+
+    // Updated at the end of each tick, then approached a little on every
+    // generated sample to avoid clicks
+    #[cfg(feature = "ramping")]
+    target_volume: [f32; 2],
+    #[cfg(feature = "ramping")]
+    target_panning: f32,
+    #[cfg(feature = "ramping")]
+    frame_count: u32,
+    #[cfg(feature = "ramping")]
+    end_of_previous_sample: [f32; SAMPLE_RAMPING_POINTS],
+
+    actual_volume: [f32; 2],
+    // The panning `actual_volume` comes from, after the mixer settings
+    actual_panning: f32,
+
+    // A cell played by `note_on()` or `note_off()`, read on the next tick
+    live: Option<module::Cell>,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            ping: true,
+            vibrato_waveform_retrigger: true,
+            tremolo_waveform_retrigger: true,
+            volume: 1.0,
+            volume_envelope_volume: 1.0,
+            fadeout_volume: 1.0,
+            panning: 0.5,
+            panning_envelope_panning: 0.5,
+            #[cfg(feature = "ramping")]
+            target_panning: 0.5,
+            actual_panning: 0.5,
+            ..Channel::default()
+        }
+    }
+}
+
+/// What doesn't change while a module is played, shared by the contexts
+/// playing it.
+struct Song {
+    name: CString,
+    tracker_name: CString,
+    frequency_type: FrequencyType,
+    restart_position: u16,
+    tempo: u16,
+    bpm: u16,
+    pattern_table: Vec<u8>,
+    patterns: Vec<Pattern>,
+    instruments: Vec<Instrument>,
+    num_channels: usize,
+    num_samples: usize,
+}
+
+/// A module being played.
+pub(crate) struct Context {
+    song: Arc<Song>,
+    rate: u32,
+    interpolation: Interpolation,
+
+    tempo: u16,
+    bpm: u16,
+    // Multiplies the BPM, without changing the pitch
+    speed: f32,
+    pitch_multiplier: f32,
+    transpose: i8,
+    // Multiplies the frequency of every note: `pitch_multiplier` and
+    // `transpose` together
+    pitch: f32,
+    global_volume: f32,
+    amplification: f32,
+    mixer: Mixer,
+    // Follows `mixer.master_volume` a little on every generated sample
+    master_volume: f32,
+
+    // How much a channel's final volume is allowed to change per sample
+    #[cfg(feature = "ramping")]
+    volume_ramp: f32,
+
+    current_table_index: u8,
+    current_row: u8,
+    // Can go above 255, with high tempo and a pattern delay
+    current_tick: u16,
+    remaining_samples_in_tick: f32,
+    generated_samples: u64,
+
+    position_jump: bool,
+    pattern_break: bool,
+    jump_dest: u8,
+    jump_row: u8,
+
+    // Extra ticks to be played before going to the next row, for EEy
+    extra_ticks: u16,
+
+    // Indexed by [table index * 256 + row]
+    row_loop_count: Vec<u8>,
+    loop_count: u8,
+    max_loop_count: u8,
+
+    // State of the random waveform. libxm keeps it in a global; here every
+    // context has its own.
+    next_rand: u32,
+
+    // The channels of the module, then the free channels added by
+    // `reserve_channels()`, which the patterns don't play
+    channels: Vec<Channel>,
+    num_pattern_channels: usize,
+
+    // Per instrument, and per sample of all instruments in a row
+    instrument_triggers: Vec<u64>,
+    sample_triggers: Vec<u64>,
+    muted_instruments: Vec<bool>,
+}
+
+impl Context {
+    /// Loads a module.
+    ///
+    /// # Parameters
+    /// * `mod_data` - The contents of the module.
+    /// * `rate` - The play rate in Hz.
+    pub(crate) fn new(mod_data: &[u8], rate: u32) -> Result<Context, XMError> {
+        Context::from_module(Module::parse(mod_data)?, rate)
+    }
+
+    /// Plays a parsed module.
+    ///
+    /// # Parameters
+    /// * `module` - The module.
+    /// * `rate` - The play rate in Hz.
+    pub(crate) fn from_module(module: Module, rate: u32) -> Result<Context, XMError> {
+        let num_channels = module.num_channels() as usize;
+
+        let mut num_samples = 0;
+        let instruments = module
+            .instruments
+            .into_iter()
+            .map(|instrument| {
+                let first_sample = num_samples;
+                num_samples += instrument.samples.len();
+
+                Instrument {
+                    sample_of_notes: instrument.sample_of_notes,
+                    volume_envelope: instrument.volume_envelope,
+                    panning_envelope: instrument.panning_envelope,
+                    vibrato_type: instrument.vibrato_type,
+                    vibrato_sweep: instrument.vibrato_sweep,
+                    vibrato_depth: instrument.vibrato_depth,
+                    vibrato_rate: instrument.vibrato_rate,
+                    volume_fadeout: instrument.volume_fadeout,
+                    samples: instrument
+                        .samples
+                        .into_iter()
+                        .map(|sample| {
+                            let length = sample.data.len() as u32;
+                            let loop_start = sample.loop_start.min(length);
+                            let loop_length = sample.loop_length.min(length - loop_start);
+                            Sample {
+                                length,
+                                loop_start,
+                                loop_length,
+                                loop_end: loop_start + loop_length,
+                                loop_type: if loop_length == 0 {
+                                    LoopType::NoLoop
+                                } else {
+                                    sample.loop_type
+                                },
+                                volume: sample.volume as f32 / 64.0,
+                                finetune: sample.finetune,
+                                panning: sample.panning as f32 / 255.0,
+                                relative_note: sample.relative_note,
+                                data: sample.data,
+                            }
+                        })
+                        .collect(),
+                    first_sample,
+                }
+            })
+            .collect();
+
+        let song = Song {
+            name: c_string(module.header.name),
+            tracker_name: c_string(module.header.tracker_name),
+            frequency_type: module.header.frequency_type,
+            restart_position: module.header.restart_position,
+            tempo: module.header.tempo,
+            bpm: module.header.bpm,
+            pattern_table: module.pattern_table,
+            patterns: module.patterns,
+            instruments,
+            num_channels,
+            num_samples,
+        };
+        Context::from_song(Arc::new(song), rate)
+    }
+
+    /// Starts playing a song.
+    fn from_song(song: Arc<Song>, rate: u32) -> Result<Context, XMError> {
+        Ok(Context {
+            rate,
+            interpolation: Interpolation::compiled(),
+
+            tempo: song.tempo,
+            bpm: song.bpm,
+            speed: 1.0,
+            pitch_multiplier: 1.0,
+            transpose: 0,
+            pitch: 1.0,
+            global_volume: 1.0,
+            // Some bad modules may still clip
+            amplification: 0.25,
+            mixer: Mixer::default(),
+            master_volume: 1.0,
+
+            #[cfg(feature = "ramping")]
+            volume_ramp: 1.0 / 128.0,
+
+            current_table_index: 0,
+            current_row: 0,
+            current_tick: 0,
+            remaining_samples_in_tick: 0.0,
+            generated_samples: 0,
+
+            position_jump: false,
+            pattern_break: false,
+            jump_dest: 0,
+            jump_row: 0,
+            extra_ticks: 0,
+
+            row_loop_count: try_vec(0, module::MAX_PATTERN_TABLE_LENGTH * 256)?,
+            loop_count: 0,
+            max_loop_count: 0,
+
+            next_rand: 24492,
+
+            channels: try_vec(Channel::new(), song.num_channels)?,
+            num_pattern_channels: song.num_channels,
+
+            instrument_triggers: try_vec(0, song.instruments.len())?,
+            sample_triggers: try_vec(0, song.num_samples)?,
+            muted_instruments: try_vec(false, song.instruments.len())?,
+
+            song,
+        })
+    }
+
+    fn update_pitch(&mut self) {
+        self.pitch = self.pitch_multiplier * math::powf(2.0, self.transpose as f32 / 12.0);
+        // Playing notes change right away
+        for i in 0..self.channels.len() {
+            self.update_frequency(i);
+        }
+    }
+}
+
+impl Player for Context {
+    fn backend(&self) -> Backend {
+        Backend::PureRust
+    }
+
+    fn generate_samples(&mut self, output: &mut [f32]) {
+        self.generated_samples += output.len() as u64 / 2;
+        for frame in output.chunks_mut(2) {
+            let (left, right) = self.sample();
+            frame[0] = left;
+            frame[1] = right;
+        }
+    }
+
+    fn generate_samples_per_channel(
+        &mut self,
+        master: &mut [f32],
+        channel_output: &mut dyn FnMut(usize, usize, f32, f32, f32),
+    ) -> Result<(), XMError> {
+        self.generated_samples += master.len() as u64 / 2;
+        for (f, frame) in master.chunks_mut(2).enumerate() {
+            let (left, right) = self.sample_channels(|i, left, right, panning| {
+                channel_output(f, i, left, right, panning)
+            });
+            frame[0] = left;
+            frame[1] = right;
+        }
+        Ok(())
+    }
+
+    fn set_max_loop_count(&mut self, loopcnt: u8) {
+        self.max_loop_count = loopcnt;
+    }
+
+    fn loop_count(&self) -> u8 {
+        self.loop_count
+    }
+
+    fn module_name(&self) -> Option<&[u8]> {
+        Some(self.song.name.as_bytes()).filter(|_| cfg!(feature = "strings"))
+    }
+
+    fn tracker_name(&self) -> Option<&[u8]> {
+        Some(self.song.tracker_name.as_bytes()).filter(|_| cfg!(feature = "strings"))
+    }
+
+    fn number_of_channels(&self) -> u16 {
+        self.num_pattern_channels as u16
+    }
+
+    fn module_length(&self) -> u16 {
+        self.song.pattern_table.len() as u16
+    }
+
+    fn number_of_patterns(&self) -> u16 {
+        self.song.patterns.len() as u16
+    }
+
+    fn number_of_rows(&self, pattern: u16) -> u16 {
+        self.song.patterns[pattern as usize].rows.len() as u16
+    }
+
+    fn number_of_instruments(&self) -> u16 {
+        self.song.instruments.len() as u16
+    }
+
+    fn number_of_samples(&self, instrument: u16) -> u16 {
+        self.song.instruments[instrument as usize - 1].samples.len() as u16
+    }
+
+    fn sample_data(&self, instrument: u16, sample: u16) -> SampleData<'_> {
+        let sample = &self.song.instruments[instrument as usize - 1].samples[sample as usize];
+        match sample.data {
+            SampleBuffer::Bits8(ref data) => SampleData::Bits8(data),
+            SampleBuffer::Bits16(ref data) => SampleData::Bits16(data),
+        }
+    }
+
+    fn playing_speed(&self) -> PlayingSpeed {
+        PlayingSpeed {
+            bpm: self.bpm,
+            tempo: self.tempo,
+        }
+    }
+
+    fn position(&self) -> Position {
+        Position {
+            pattern_index: self.current_table_index,
+            pattern: self
+                .song
+                .pattern_table
+                .get(self.current_table_index as usize)
+                .copied()
+                .unwrap_or(0),
+            row: self.current_row,
+            samples: self.generated_samples,
+        }
+    }
+
+    fn latest_trigger_of_instrument(&self, instrument: u16) -> u64 {
+        self.instrument_triggers[instrument as usize - 1]
+    }
+
+    fn latest_trigger_of_sample(&self, instrument: u16, sample: u16) -> u64 {
+        let first = self.song.instruments[instrument as usize - 1].first_sample;
+        self.sample_triggers[first + sample as usize]
+    }
+
+    fn latest_trigger_of_channel(&self, channel: u16) -> u64 {
+        self.channels[channel as usize - 1].latest_trigger
+    }
+
+    fn channel_state(&self, channel: u16) -> ChannelState {
+        let ch = &self.channels[channel as usize - 1];
+        match ch.instrument {
+            Some(instrument) if ch.sample.is_some() && ch.sample_position >= 0.0 => ChannelState {
+                active: true,
+                instrument: instrument as u16 + 1,
+                frequency: ch.frequency,
+                volume: ch.volume * ch.volume_envelope_volume,
+                panning: ch.panning,
+            },
+            _ => ChannelState {
+                active: false,
+                instrument: 0,
+                frequency: 0.0,
+                volume: 0.0,
+                panning: 0.5,
+            },
+        }
+    }
+
+    /// Seeks like libxm's `xm_seek()`, without updating anything else.
+    fn seek(&mut self, pot: u8, row: u8, tick: u16) {
+        self.current_table_index = pot;
+        self.current_row = row;
+        self.current_tick = tick;
+        self.remaining_samples_in_tick = 0.0;
+    }
+
+    fn next_tick(&self) -> (u16, usize) {
+        (
+            self.current_tick,
+            crate::backend::frames_before_tick(self.remaining_samples_in_tick),
+        )
+    }
+
+    fn next_row(&self) -> (u8, u8) {
+        Context::next_row(self)
+    }
+
+    fn current_cell(&self, channel: u16) -> module::Cell {
+        self.channels[channel as usize - 1].current
+    }
+
+    fn is_sustained(&self, channel: u16) -> bool {
+        self.channels[channel as usize - 1].sustained
+    }
+
+    fn mute_channel(&mut self, channel: u16, mute: bool) -> bool {
+        let channel = &mut self.channels[channel as usize - 1];
+        core::mem::replace(&mut channel.muted, mute)
+    }
+
+    fn mute_instrument(&mut self, instrument: u16, mute: bool) -> bool {
+        core::mem::replace(&mut self.muted_instruments[instrument as usize - 1], mute)
+    }
+
+    fn restart(&mut self) -> Result<(), XMError> {
+        *self = self.fresh_context()?;
+        Ok(())
+    }
+
+    fn try_new(&self) -> Result<Box<dyn Player>, XMError> {
+        Ok(Box::new(self.fresh_context()?))
+    }
+
+    fn can_copy(&self) -> bool {
+        true
+    }
+
+    fn try_clone(&self) -> Result<Option<Box<dyn Player>>, XMError> {
+        Ok(Some(Box::new(self.try_clone_context()?)))
+    }
+
+    fn set_interpolation(&mut self, interpolation: Interpolation) -> Result<(), XMError> {
+        if let Interpolation::Sinc(0..=1) = interpolation {
+            return Err(XMError::Unsupported(
+                "sinc interpolation needs at least 2 taps",
+            ));
+        }
+        self.interpolation = interpolation;
+        Ok(())
+    }
+
+    /// Sets the BPM, until the module sets it with an Fxx effect.
+    fn set_bpm(&mut self, bpm: u16) {
+        self.bpm = bpm;
+    }
+
+    /// Sets the tempo (ticks per row), until the module sets it with an Fxx
+    /// effect.
+    fn set_tempo(&mut self, tempo: u16) {
+        self.tempo = tempo;
+    }
+
+    /// Plays faster (above 1) or slower (below 1), without changing the
+    /// pitch. Takes effect on the next tick.
+    fn set_speed_multiplier(&mut self, speed: f32) -> Result<(), XMError> {
+        self.speed = speed;
+        Ok(())
+    }
+
+    fn set_pitch_multiplier(&mut self, multiplier: f32) -> Result<(), XMError> {
+        self.pitch_multiplier = multiplier;
+        self.update_pitch();
+        Ok(())
+    }
+
+    fn set_transpose(&mut self, semitones: i8) -> Result<(), XMError> {
+        self.transpose = semitones;
+        self.update_pitch();
+        Ok(())
+    }
+
+    /// Adds `count` free channels after the channels of the module. The
+    /// patterns never play in free channels, only `note_on()` does.
+    fn reserve_channels(&mut self, count: u16) -> Result<(), XMError> {
+        self.channels
+            .try_reserve(count as usize)
+            .map_err(|_| XMError::MemoryAllocationFailed)?;
+        let num_channels = self.channels.len() + count as usize;
+        self.channels.resize(num_channels, Channel::new());
+        Ok(())
+    }
+
+    fn play_live(&mut self, channel: u16, cell: module::Cell) {
+        self.channels[channel as usize - 1].live = Some(cell);
+    }
+
+    /// Volumes and pannings change right away, ramped if the `ramping`
+    /// feature is enabled.
+    fn set_mixer(&mut self, mixer: &Mixer) -> Result<(), XMError> {
+        self.mixer.clone_from(mixer);
+        for i in 0..self.channels.len() {
+            self.update_target_volume(i);
+        }
+        Ok(())
+    }
+}
+
+impl Context {
+    /// A copy of the context, sharing the song.
+    fn try_clone_context(&self) -> Result<Context, XMError> {
+        Ok(Context {
+            song: Arc::clone(&self.song),
+            rate: self.rate,
+            interpolation: self.interpolation,
+
+            tempo: self.tempo,
+            bpm: self.bpm,
+            speed: self.speed,
+            pitch_multiplier: self.pitch_multiplier,
+            transpose: self.transpose,
+            pitch: self.pitch,
+            global_volume: self.global_volume,
+            amplification: self.amplification,
+            mixer: self.mixer.try_clone()?,
+            master_volume: self.master_volume,
+
+            #[cfg(feature = "ramping")]
+            volume_ramp: self.volume_ramp,
+
+            current_table_index: self.current_table_index,
+            current_row: self.current_row,
+            current_tick: self.current_tick,
+            remaining_samples_in_tick: self.remaining_samples_in_tick,
+            generated_samples: self.generated_samples,
+
+            position_jump: self.position_jump,
+            pattern_break: self.pattern_break,
+            jump_dest: self.jump_dest,
+            jump_row: self.jump_row,
+            extra_ticks: self.extra_ticks,
+
+            row_loop_count: try_to_vec(&self.row_loop_count)?,
+            loop_count: self.loop_count,
+            max_loop_count: self.max_loop_count,
+
+            next_rand: self.next_rand,
+
+            channels: try_to_vec(&self.channels)?,
+            num_pattern_channels: self.num_pattern_channels,
+
+            instrument_triggers: try_to_vec(&self.instrument_triggers)?,
+            sample_triggers: try_to_vec(&self.sample_triggers)?,
+            muted_instruments: try_to_vec(&self.muted_instruments)?,
+        })
+    }
+
+    /// A context playing the same song, at the start, with the same
+    /// interpolation, free channels and mixer settings.
+    fn fresh_context(&self) -> Result<Context, XMError> {
+        let mut context = Context::from_song(Arc::clone(&self.song), self.rate)?;
+        context.interpolation = self.interpolation;
+        context.reserve_channels((self.channels.len() - self.num_pattern_channels) as u16)?;
+        context.set_mixer(&self.mixer)?;
+        context.master_volume = self.mixer.master_volume;
+        Ok(context)
+    }
+}
+
+/// Creates a vector, or fails if there isn't enough memory.
+fn try_vec<T: Clone>(value: T, len: usize) -> Result<Vec<T>, XMError> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(len)
+        .map_err(|_| XMError::MemoryAllocationFailed)?;
+    vec.resize(len, value);
+    Ok(vec)
+}
+
+fn c_string(mut name: Vec<u8>) -> CString {
+    // Names are NUL-padded
+    if let Some(end) = name.iter().position(|&c| c == 0) {
+        name.truncate(end);
+    }
+    CString::new(name).unwrap()
+}
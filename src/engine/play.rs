@@ -0,0 +1,1615 @@
+//! Playback, ported from libxm's `play.c`. The structure and the comments
+//! follow the original, to make it easy to compare both.
+
+use super::{Channel, Context, Instrument, Sample};
+use crate::backend::delayed_to_tick;
+use crate::math::{cos, floorf, powf, sin, sinf, sqrtf};
+use crate::module::{Cell, Envelope, EnvelopePoint, FrequencyType, SampleBuffer};
+use crate::{Interpolation, LoopType};
+
+const SINE_WAVEFORM: u8 = 0;
+const RAMP_DOWN_WAVEFORM: u8 = 1;
+const SQUARE_WAVEFORM: u8 = 2;
+const RANDOM_WAVEFORM: u8 = 3;
+const RAMP_UP_WAVEFORM: u8 = 4;
+
+const TRIGGER_KEEP_VOLUME: u8 = 1 << 0;
+const TRIGGER_KEEP_PERIOD: u8 = 1 << 1;
+const TRIGGER_KEEP_SAMPLE_POSITION: u8 = 1 << 2;
+const TRIGGER_KEEP_ENVELOPE: u8 = 1 << 3;
+
+const AMIGA_FREQUENCIES: [u16; 13] = [
+    1712, 1616, 1525, 1440, // C-2, C#2, D-2, D#2
+    1357, 1281, 1209, 1141, // E-2, F-2, F#2, G-2
+    1077, 1017, 961, 907, // G#2, A-2, A#2, B-2
+    856, // C-3
+];
+
+const MULTI_RETRIG_ADD: [f32; 16] = [
+    0.0, -1.0, -2.0, -4.0, // 0, 1, 2, 3
+    -8.0, -16.0, 0.0, 0.0, // 4, 5, 6, 7
+    0.0, 1.0, 2.0, 4.0, // 8, 9, A, B
+    8.0, 16.0, 0.0, 0.0, // C, D, E, F
+];
+
+const MULTI_RETRIG_MULTIPLY: [f32; 16] = [
+    1.0, 1.0, 1.0, 1.0, // 0, 1, 2, 3
+    1.0, 1.0, 0.6666667, 0.5, // 4, 5, 6, 7
+    1.0, 1.0, 1.0, 1.0, // 8, 9, A, B
+    1.0, 1.0, 1.5, 2.0, // C, D, E, F
+];
+
+#[inline]
+fn lerp(u: f32, v: f32, t: f32) -> f32 {
+    u + t * (v - u)
+}
+
+#[inline]
+fn inverse_lerp(u: f32, v: f32, lerp: f32) -> f32 {
+    (lerp - u) / (v - u)
+}
+
+#[inline]
+fn clamp_up(value: &mut f32) {
+    if *value > 1.0 {
+        *value = 1.0;
+    }
+}
+
+#[inline]
+fn clamp_down(value: &mut f32) {
+    if *value < 0.0 {
+        *value = 0.0;
+    }
+}
+
+#[inline]
+fn slide_towards(value: &mut f32, goal: f32, increment: f32) {
+    if *value > goal {
+        *value -= increment;
+        if *value < goal {
+            *value = goal;
+        }
+    } else if *value < goal {
+        *value += increment;
+        if *value > goal {
+            *value = goal;
+        }
+    }
+}
+
+#[inline]
+fn note_is_valid(note: u8) -> bool {
+    note > 0 && note < Cell::KEY_OFF
+}
+
+#[inline]
+fn has_tone_portamento(s: &Cell) -> bool {
+    s.effect_type == 3 || s.effect_type == 5 || s.volume_column >> 4 == 0xF
+}
+
+#[inline]
+fn has_arpeggio(s: &Cell) -> bool {
+    s.effect_type == 0 && s.effect_param != 0
+}
+
+#[inline]
+fn has_vibrato(s: &Cell) -> bool {
+    s.effect_type == 4 || s.effect_type == 6 || s.volume_column >> 4 == 0xB
+}
+
+fn waveform(next_rand: &mut u32, waveform: u8, step: u16) -> f32 {
+    let step = (step % 0x40) as u8;
+
+    match waveform {
+        SINE_WAVEFORM => {
+            // Why not use a table? For saving space, and because there's
+            // very very little actual performance gain. The truncated pi is
+            // libxm's.
+            #[allow(clippy::approx_constant)]
+            let pi = 3.141592;
+            -sinf(2.0 * pi * step as f32 / 0x40 as f32)
+        }
+        RAMP_DOWN_WAVEFORM => {
+            // Ramp down: 1.0 when step = 0; -1.0 when step = 0x40
+            (0x20 - step as i32) as f32 / 0x20 as f32
+        }
+        SQUARE_WAVEFORM => {
+            // Square with a 50% duty
+            if step >= 0x20 {
+                1.0
+            } else {
+                -1.0
+            }
+        }
+        RANDOM_WAVEFORM => {
+            // Use the POSIX.1-2001 example, just to be deterministic
+            // across different machines
+            *next_rand = next_rand.wrapping_mul(1103515245).wrapping_add(12345);
+            ((*next_rand >> 16) & 0x7FFF) as f32 / 0x4000 as f32 - 1.0
+        }
+        RAMP_UP_WAVEFORM => {
+            // Ramp up: -1.0 when step = 0; 1.0 when step = 0x40
+            (step as i32 - 0x20) as f32 / 0x20 as f32
+        }
+        _ => 0.0,
+    }
+}
+
+fn linear_period(note: f32) -> f32 {
+    7680.0 - note * 64.0
+}
+
+fn linear_frequency(period: f32) -> f32 {
+    8363.0 * powf(2.0, (4608.0 - period) / 768.0)
+}
+
+fn amiga_period(note: f32) -> f32 {
+    let intnote = note as u32;
+    let a = (intnote % 12) as usize;
+    let octave = (note / 12.0 - 2.0) as i8;
+    let (mut p1, mut p2) = (AMIGA_FREQUENCIES[a] as u32, AMIGA_FREQUENCIES[a + 1] as u32);
+
+    if octave > 0 {
+        p1 >>= octave;
+        p2 >>= octave;
+    } else if octave < 0 {
+        p1 <<= -octave;
+        p2 <<= -octave;
+    }
+
+    lerp(p1 as f32, p2 as f32, note - intnote as f32)
+}
+
+fn amiga_frequency(period: f32) -> f32 {
+    if period == 0.0 {
+        return 0.0;
+    }
+
+    // This is the PAL value. No reason to choose this one over the NTSC
+    // value.
+    7093789.2 / (period * 2.0)
+}
+
+impl Context {
+    fn period(&self, note: f32) -> f32 {
+        match self.song.frequency_type {
+            FrequencyType::Linear => linear_period(note),
+            FrequencyType::Amiga => amiga_period(note),
+        }
+    }
+
+    fn frequency(&self, period: f32, note_offset: f32, period_offset: f32) -> f32 {
+        match self.song.frequency_type {
+            FrequencyType::Linear => {
+                linear_frequency(period - 64.0 * note_offset - 16.0 * period_offset)
+            }
+            FrequencyType::Amiga => {
+                if note_offset == 0.0 {
+                    // A chance to escape from insanity
+                    return amiga_frequency(period + 16.0 * period_offset);
+                }
+
+                // FIXME: this is very crappy at best
+                let mut a = 0;
+                let mut octave: i8 = 0;
+
+                // Find the octave of the current period
+                let shifted = |octave: i8, p: u16| -> f32 {
+                    if octave > 0 {
+                        (p as u32 >> octave) as f32
+                    } else {
+                        ((p as u32) << -octave) as f32
+                    }
+                };
+                if period > AMIGA_FREQUENCIES[0] as f32 {
+                    octave -= 1;
+                    while octave > -16 && period > shifted(octave, AMIGA_FREQUENCIES[0]) {
+                        octave -= 1;
+                    }
+                } else if period < AMIGA_FREQUENCIES[12] as f32 {
+                    octave += 1;
+                    while octave < 16 && period < shifted(octave, AMIGA_FREQUENCIES[12]) {
+                        octave += 1;
+                    }
+                }
+
+                // Find the smallest note closest to the current period
+                let (mut p1, mut p2) = (0.0, 0.0);
+                for i in 0..12 {
+                    p1 = shifted(octave, AMIGA_FREQUENCIES[i]);
+                    p2 = shifted(octave, AMIGA_FREQUENCIES[i + 1]);
+
+                    if p2 <= period && period <= p1 {
+                        a = i;
+                        break;
+                    }
+                }
+
+                let note = 12.0 * (octave as f32 + 2.0) + a as f32 + inverse_lerp(p1, p2, period);
+
+                amiga_frequency(amiga_period(note + note_offset) + 16.0 * period_offset)
+            }
+        }
+    }
+
+    pub(super) fn update_frequency(&mut self, i: usize) {
+        let ch = &self.channels[i];
+        let frequency = self.frequency(
+            ch.period,
+            ch.arp_note_offset as f32,
+            ch.vibrato_note_offset + ch.autovibrato_note_offset,
+        ) * self.pitch;
+
+        let ch = &mut self.channels[i];
+        ch.frequency = frequency;
+        ch.step = frequency / self.rate as f32;
+    }
+
+    fn instrument_of(&self, i: usize) -> Option<&Instrument> {
+        self.channels[i]
+            .instrument
+            .map(|j| &self.song.instruments[j])
+    }
+
+    fn sample_of(&self, i: usize) -> Option<&Sample> {
+        self.channels[i]
+            .sample
+            .map(|(j, k)| &self.song.instruments[j].samples[k])
+    }
+
+    fn autovibrato(&mut self, i: usize) {
+        let (depth, sweep, rate, kind) = match self.instrument_of(i) {
+            Some(instr) if instr.vibrato_depth != 0 => (
+                instr.vibrato_depth,
+                instr.vibrato_sweep,
+                instr.vibrato_rate,
+                instr.vibrato_type,
+            ),
+            _ => {
+                if self.channels[i].autovibrato_note_offset != 0.0 {
+                    self.channels[i].autovibrato_note_offset = 0.0;
+                    self.update_frequency(i);
+                }
+                return;
+            }
+        };
+
+        let ch = &mut self.channels[i];
+        let mut sweep_factor = 1.0;
+        if ch.autovibrato_ticks < sweep as u16 {
+            // No idea if this is correct, but it sounds close enough…
+            sweep_factor = lerp(0.0, 1.0, ch.autovibrato_ticks as f32 / sweep as f32);
+        }
+
+        let step = (ch.autovibrato_ticks as u32 * rate as u32) >> 2;
+        ch.autovibrato_ticks = ch.autovibrato_ticks.wrapping_add(1);
+        ch.autovibrato_note_offset =
+            0.25 * waveform(&mut self.next_rand, kind, step as u16) * depth as f32 / 0xF as f32
+                * sweep_factor;
+        self.update_frequency(i);
+    }
+
+    fn vibrato(&mut self, i: usize) {
+        let ch = &mut self.channels[i];
+        let param = ch.vibrato_param;
+        let pos = ch.vibrato_ticks;
+        ch.vibrato_ticks = ch.vibrato_ticks.wrapping_add(1);
+
+        let step = pos.wrapping_mul((param >> 4) as u16);
+        ch.vibrato_note_offset =
+            2.0 * waveform(&mut self.next_rand, ch.vibrato_waveform, step) * (param & 0x0F) as f32
+                / 0xF as f32;
+        self.update_frequency(i);
+    }
+
+    fn tremolo(&mut self, i: usize) {
+        let ch = &mut self.channels[i];
+        let param = ch.tremolo_param;
+        let pos = ch.tremolo_ticks;
+        ch.tremolo_ticks = ch.tremolo_ticks.wrapping_add(1);
+
+        let step = (pos as u16).wrapping_mul((param >> 4) as u16);
+        // Not so sure about this, it sounds correct by ear compared with
+        // MilkyTracker, but it could come from other bugs
+        ch.tremolo_volume = -waveform(&mut self.next_rand, ch.tremolo_waveform, step)
+            * (param & 0x0F) as f32
+            / 0xF as f32;
+    }
+
+    fn arpeggio(&mut self, i: usize, param: u8, tick: u16) {
+        let ch = &mut self.channels[i];
+        match tick % 3 {
+            0 => {
+                ch.arp_in_progress = false;
+                ch.arp_note_offset = 0;
+            }
+            2 => {
+                ch.arp_in_progress = true;
+                ch.arp_note_offset = param >> 4;
+            }
+            _ => {
+                ch.arp_in_progress = true;
+                ch.arp_note_offset = param & 0x0F;
+            }
+        }
+
+        self.update_frequency(i);
+    }
+
+    fn tone_portamento(&mut self, i: usize) {
+        let factor = match self.song.frequency_type {
+            FrequencyType::Linear => 4.0,
+            FrequencyType::Amiga => 1.0,
+        };
+        let ch = &mut self.channels[i];
+
+        // 3xx called without a note, wait until we get an actual target
+        // note.
+        if ch.tone_portamento_target_period == 0.0 {
+            return;
+        }
+
+        if ch.period != ch.tone_portamento_target_period {
+            slide_towards(
+                &mut ch.period,
+                ch.tone_portamento_target_period,
+                factor * ch.tone_portamento_param as f32,
+            );
+            self.update_frequency(i);
+        }
+    }
+
+    fn pitch_slide(&mut self, i: usize, mut period_offset: f32) {
+        // Don't ask about the 4.0 coefficient. I found mention of it
+        // nowhere. Found by ear™.
+        if self.song.frequency_type == FrequencyType::Linear {
+            period_offset *= 4.0;
+        }
+
+        let ch = &mut self.channels[i];
+        ch.period += period_offset;
+        clamp_down(&mut ch.period);
+        // XXX: upper bound of period ?
+
+        self.update_frequency(i);
+    }
+
+    fn handle_note_and_instrument(&mut self, i: usize, s: Cell) {
+        let num_instruments = self.song.instruments.len();
+
+        if s.instrument > 0 {
+            let ch = &self.channels[i];
+            if has_tone_portamento(&ch.current) && ch.instrument.is_some() && ch.sample.is_some() {
+                // Tone portamento in effect, unclear stuff happens
+                self.trigger_note(i, TRIGGER_KEEP_PERIOD | TRIGGER_KEEP_SAMPLE_POSITION);
+            } else if s.note == 0 && ch.sample.is_some() {
+                // Ghost instrument, trigger note
+                // Sample position is kept, but envelopes are reset
+                self.trigger_note(i, TRIGGER_KEEP_SAMPLE_POSITION);
+            } else if s.instrument as usize > num_instruments {
+                // Invalid instrument, Cut current note
+                let ch = &mut self.channels[i];
+                cut_note(ch);
+                ch.instrument = None;
+                ch.sample = None;
+            } else {
+                self.channels[i].instrument = Some(s.instrument as usize - 1);
+            }
+        }
+
+        if note_is_valid(s.note) {
+            // Yes, the real note number is s.note - 1. Try finding THAT in
+            // any of the specs! :-)
+            let ch = &self.channels[i];
+            let instr = ch.instrument;
+
+            if has_tone_portamento(&ch.current) && instr.is_some() && ch.sample.is_some() {
+                // Tone portamento in effect
+                let sample = self.sample_of(i).unwrap();
+                let note =
+                    s.note as f32 + sample.relative_note as f32 + sample.finetune as f32 / 128.0
+                        - 1.0;
+                let period = self.period(note);
+                let ch = &mut self.channels[i];
+                ch.note = note;
+                ch.tone_portamento_target_period = period;
+            } else if instr.is_none_or(|j| self.song.instruments[j].samples.is_empty()) {
+                // Bad instrument
+                cut_note(&mut self.channels[i]);
+            } else {
+                let j = instr.unwrap();
+                let k = self.song.instruments[j].sample_of_notes[s.note as usize - 1] as usize;
+                if k < self.song.instruments[j].samples.len() {
+                    #[cfg(feature = "ramping")]
+                    {
+                        for z in 0..super::SAMPLE_RAMPING_POINTS {
+                            let value = self.next_of_sample(i);
+                            self.channels[i].end_of_previous_sample[z] = value;
+                        }
+                        self.channels[i].frame_count = 0;
+                    }
+
+                    let sample = &self.song.instruments[j].samples[k];
+                    let note = s.note as f32
+                        + sample.relative_note as f32
+                        + sample.finetune as f32 / 128.0
+                        - 1.0;
+                    let ch = &mut self.channels[i];
+                    ch.sample = Some((j, k));
+                    ch.orig_note = note;
+                    ch.note = note;
+
+                    if s.instrument > 0 {
+                        self.trigger_note(i, 0);
+                    } else {
+                        // Ghost note: keep old volume
+                        self.trigger_note(i, TRIGGER_KEEP_VOLUME);
+                    }
+                } else {
+                    // Bad sample
+                    cut_note(&mut self.channels[i]);
+                }
+            }
+        } else if s.note == Cell::KEY_OFF {
+            self.key_off(i);
+        }
+
+        let ch = &mut self.channels[i];
+        match s.volume_column >> 4 {
+            0x1..=0x5 if s.volume_column <= 0x50 => {
+                // Set volume
+                ch.volume = (s.volume_column - 0x10) as f32 / 0x40 as f32;
+            }
+            0x8 => {
+                // Fine volume slide down
+                volume_slide(ch, s.volume_column & 0x0F);
+            }
+            0x9 => {
+                // Fine volume slide up
+                volume_slide(ch, s.volume_column << 4);
+            }
+            0xA => {
+                // Set vibrato speed
+                ch.vibrato_param = (ch.vibrato_param & 0x0F) | ((s.volume_column & 0x0F) << 4);
+            }
+            0xB if s.volume_column & 0x0F != 0 => {
+                // Vibrato
+                ch.vibrato_param = (ch.vibrato_param & 0xF0) | (s.volume_column & 0x0F);
+            }
+            0xC => {
+                // Set panning
+                ch.panning = (((s.volume_column & 0x0F) << 4) | (s.volume_column & 0x0F)) as f32
+                    / 0xFF as f32;
+            }
+            0xF if s.volume_column & 0x0F != 0 => {
+                // Tone portamento
+                ch.tone_portamento_param =
+                    ((s.volume_column & 0x0F) << 4) | (s.volume_column & 0x0F);
+            }
+            _ => {}
+        }
+
+        match s.effect_type {
+            1 if s.effect_param > 0 => {
+                // 1xx: Portamento up
+                ch.portamento_up_param = s.effect_param;
+            }
+            2 if s.effect_param > 0 => {
+                // 2xx: Portamento down
+                ch.portamento_down_param = s.effect_param;
+            }
+            3 if s.effect_param > 0 => {
+                // 3xx: Tone portamento
+                ch.tone_portamento_param = s.effect_param;
+            }
+            4 => {
+                // 4xy: Vibrato
+                if s.effect_param & 0x0F != 0 {
+                    // Set vibrato depth
+                    ch.vibrato_param = (ch.vibrato_param & 0xF0) | (s.effect_param & 0x0F);
+                }
+                if s.effect_param >> 4 != 0 {
+                    // Set vibrato speed
+                    ch.vibrato_param = (s.effect_param & 0xF0) | (ch.vibrato_param & 0x0F);
+                }
+            }
+            5 | 6 | 0xA if s.effect_param > 0 => {
+                // 5xy: Tone portamento + Volume slide
+                // 6xy: Vibrato + Volume slide
+                // Axy: Volume slide
+                ch.volume_slide_param = s.effect_param;
+            }
+            7 => {
+                // 7xy: Tremolo
+                if s.effect_param & 0x0F != 0 {
+                    // Set tremolo depth
+                    ch.tremolo_param = (ch.tremolo_param & 0xF0) | (s.effect_param & 0x0F);
+                }
+                if s.effect_param >> 4 != 0 {
+                    // Set tremolo speed
+                    ch.tremolo_param = (s.effect_param & 0xF0) | (ch.tremolo_param & 0x0F);
+                }
+            }
+            8 => {
+                // 8xx: Set panning
+                ch.panning = s.effect_param as f32 / 0xFF as f32;
+            }
+            9 => {
+                // 9xx: Sample offset
+                if let (Some((j, k)), true) = (ch.sample, note_is_valid(s.note)) {
+                    let sample = &self.song.instruments[j].samples[k];
+                    let shift = match sample.data {
+                        SampleBuffer::Bits16(_) => 7,
+                        SampleBuffer::Bits8(_) => 8,
+                    };
+                    let final_offset = (s.effect_param as u32) << shift;
+                    if final_offset >= sample.length {
+                        // Pretend the sample dosen't loop and is done playing
+                        ch.sample_position = -1.0;
+                    } else {
+                        ch.sample_position = final_offset as f32;
+                    }
+                }
+            }
+            0xB if (s.effect_param as usize) < self.song.pattern_table.len() => {
+                // Bxx: Position jump
+                self.position_jump = true;
+                self.jump_dest = s.effect_param;
+                self.jump_row = 0;
+            }
+            0xC => {
+                // Cxx: Set volume
+                ch.volume = s.effect_param.min(0x40) as f32 / 0x40 as f32;
+            }
+            0xD => {
+                // Dxx: Pattern break
+                // Jump after playing this line
+                self.pattern_break = true;
+                self.jump_row = (s.effect_param >> 4) * 10 + (s.effect_param & 0x0F);
+            }
+            0xE => self.handle_extended_effect(i, s),
+            0xF if s.effect_param > 0 => {
+                // Fxx: Set tempo/BPM
+                if s.effect_param <= 0x1F {
+                    self.tempo = s.effect_param as u16;
+                } else {
+                    self.bpm = s.effect_param as u16;
+                }
+            }
+            16 => {
+                // Gxx: Set global volume
+                self.global_volume = s.effect_param.min(0x40) as f32 / 0x40 as f32;
+            }
+            17 if s.effect_param > 0 => {
+                // Hxy: Global volume slide
+                ch.global_volume_slide_param = s.effect_param;
+            }
+            21 => {
+                // Lxx: Set envelope position
+                ch.volume_envelope_frame_count = s.effect_param as u16;
+                ch.panning_envelope_frame_count = s.effect_param as u16;
+            }
+            25 if s.effect_param > 0 => {
+                // Pxy: Panning slide
+                ch.panning_slide_param = s.effect_param;
+            }
+            27 if s.effect_param > 0 => {
+                // Rxy: Multi retrig note
+                if s.effect_param >> 4 == 0 {
+                    // Keep previous x value
+                    ch.multi_retrig_param =
+                        (ch.multi_retrig_param & 0xF0) | (s.effect_param & 0x0F);
+                } else {
+                    ch.multi_retrig_param = s.effect_param;
+                }
+            }
+            29 if s.effect_param > 0 => {
+                // Txy: Tremor
+                // Tremor x and y params do not appear to be separately
+                // kept in memory, unlike Rxy
+                ch.tremor_param = s.effect_param;
+            }
+            33 => {
+                // Xxy: Extra stuff
+                match s.effect_param >> 4 {
+                    1 => {
+                        // X1y: Extra fine portamento up
+                        if s.effect_param & 0x0F != 0 {
+                            ch.extra_fine_portamento_up_param = s.effect_param & 0x0F;
+                        }
+                        let offset = -(ch.extra_fine_portamento_up_param as f32);
+                        self.pitch_slide(i, offset);
+                    }
+                    2 => {
+                        // X2y: Extra fine portamento down
+                        if s.effect_param & 0x0F != 0 {
+                            ch.extra_fine_portamento_down_param = s.effect_param & 0x0F;
+                        }
+                        let offset = ch.extra_fine_portamento_down_param as f32;
+                        self.pitch_slide(i, offset);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_extended_effect(&mut self, i: usize, s: Cell) {
+        let ch = &mut self.channels[i];
+
+        match s.effect_param >> 4 {
+            1 => {
+                // E1y: Fine portamento up
+                if s.effect_param & 0x0F != 0 {
+                    ch.fine_portamento_up_param = s.effect_param & 0x0F;
+                }
+                let offset = -(ch.fine_portamento_up_param as f32);
+                self.pitch_slide(i, offset);
+            }
+            2 => {
+                // E2y: Fine portamento down
+                if s.effect_param & 0x0F != 0 {
+                    ch.fine_portamento_down_param = s.effect_param & 0x0F;
+                }
+                let offset = ch.fine_portamento_down_param as f32;
+                self.pitch_slide(i, offset);
+            }
+            4 => {
+                // E4y: Set vibrato control
+                ch.vibrato_waveform = s.effect_param & 3;
+                ch.vibrato_waveform_retrigger = (s.effect_param >> 2) & 1 == 0;
+            }
+            5 if note_is_valid(ch.current.note) => {
+                // E5y: Set finetune
+                if let Some(sample) = self.sample_of(i) {
+                    let note = self.channels[i].current.note as f32
+                        + sample.relative_note as f32
+                        + (((s.effect_param & 0x0F) as i32 - 8) << 4) as f32 / 128.0
+                        - 1.0;
+                    let period = self.period(note);
+                    let ch = &mut self.channels[i];
+                    ch.note = note;
+                    ch.period = period;
+                    self.update_frequency(i);
+                }
+            }
+            6 => {
+                // E6y: Pattern loop
+                if s.effect_param & 0x0F != 0 {
+                    if s.effect_param & 0x0F == ch.pattern_loop_count {
+                        // Loop is over
+                        ch.pattern_loop_count = 0;
+                    } else {
+                        // Jump to the beginning of the loop
+                        ch.pattern_loop_count += 1;
+                        self.position_jump = true;
+                        self.jump_row = ch.pattern_loop_origin;
+                        self.jump_dest = self.current_table_index;
+                    }
+                } else {
+                    // Set loop start point
+                    ch.pattern_loop_origin = self.current_row;
+                    // Replicate FT2 E60 bug
+                    self.jump_row = ch.pattern_loop_origin;
+                }
+            }
+            7 => {
+                // E7y: Set tremolo control
+                ch.tremolo_waveform = s.effect_param & 3;
+                ch.tremolo_waveform_retrigger = (s.effect_param >> 2) & 1 == 0;
+            }
+            0xA => {
+                // EAy: Fine volume slide up
+                if s.effect_param & 0x0F != 0 {
+                    ch.fine_volume_slide_param = s.effect_param & 0x0F;
+                }
+                let param = ch.fine_volume_slide_param << 4;
+                volume_slide(ch, param);
+            }
+            0xB => {
+                // EBy: Fine volume slide down
+                if s.effect_param & 0x0F != 0 {
+                    ch.fine_volume_slide_param = s.effect_param & 0x0F;
+                }
+                let param = ch.fine_volume_slide_param;
+                volume_slide(ch, param);
+            }
+            0xD if s.note == 0 && s.instrument == 0 => {
+                // EDy: Note delay
+                // XXX: figure this out better. EDx triggers the note even
+                // when there no note and no instrument. But ED0 acts like
+                // like a ghost note, EDx (x ≠ 0) does not.
+                let flags = TRIGGER_KEEP_VOLUME;
+
+                if ch.current.effect_param & 0x0F != 0 {
+                    ch.note = ch.orig_note;
+                    self.trigger_note(i, flags);
+                } else {
+                    self.trigger_note(
+                        i,
+                        flags | TRIGGER_KEEP_PERIOD | TRIGGER_KEEP_SAMPLE_POSITION,
+                    );
+                }
+            }
+            0xE => {
+                // EEy: Pattern delay
+                self.extra_ticks =
+                    ((ch.current.effect_param & 0x0F) as u16).wrapping_mul(self.tempo);
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger_note(&mut self, i: usize, flags: u8) {
+        let sample = self
+            .sample_of(i)
+            .map(|sample| (sample.volume, sample.panning));
+        let ch = &mut self.channels[i];
+
+        if flags & TRIGGER_KEEP_SAMPLE_POSITION == 0 {
+            ch.sample_position = 0.0;
+            ch.ping = true;
+        }
+
+        if let Some((volume, panning)) = sample {
+            if flags & TRIGGER_KEEP_VOLUME == 0 {
+                ch.volume = volume;
+            }
+
+            ch.panning = panning;
+        }
+
+        if flags & TRIGGER_KEEP_ENVELOPE == 0 {
+            ch.sustained = true;
+            ch.fadeout_volume = 1.0;
+            ch.volume_envelope_volume = 1.0;
+            ch.panning_envelope_panning = 0.5;
+            ch.volume_envelope_frame_count = 0;
+            ch.panning_envelope_frame_count = 0;
+        }
+        ch.vibrato_note_offset = 0.0;
+        ch.tremolo_volume = 0.0;
+        ch.tremor_on = false;
+
+        ch.autovibrato_ticks = 0;
+
+        if ch.vibrato_waveform_retrigger {
+            // XXX: should the waveform itself also be reset to sine?
+            ch.vibrato_ticks = 0;
+        }
+        if ch.tremolo_waveform_retrigger {
+            ch.tremolo_ticks = 0;
+        }
+
+        if flags & TRIGGER_KEEP_PERIOD == 0 {
+            let note = ch.note;
+            self.channels[i].period = self.period(note);
+            self.update_frequency(i);
+        }
+
+        let ch = &mut self.channels[i];
+        ch.latest_trigger = self.generated_samples;
+        if let Some(j) = ch.instrument {
+            self.instrument_triggers[j] = self.generated_samples;
+        }
+        if let Some((j, k)) = ch.sample {
+            let first = self.song.instruments[j].first_sample;
+            self.sample_triggers[first + k] = self.generated_samples;
+        }
+    }
+
+    fn key_off(&mut self, i: usize) {
+        let envelope = self
+            .instrument_of(i)
+            .is_some_and(|instr| instr.volume_envelope.enabled);
+        let ch = &mut self.channels[i];
+
+        // Key Off
+        ch.sustained = false;
+
+        // If no volume envelope is used, also cut the note
+        if !envelope {
+            cut_note(ch);
+        }
+    }
+
+    fn post_pattern_change(&mut self) {
+        // Loop if necessary
+        if self.current_table_index as usize >= self.song.pattern_table.len() {
+            self.current_table_index = self.song.restart_position as u8;
+            if self.current_table_index as usize >= self.song.pattern_table.len() {
+                self.current_table_index = 0;
+            }
+        }
+    }
+
+    /// Gets a cell of the current pattern, if it exists.
+    fn cell(&self, row: u8, channel: usize) -> Cell {
+        self.song
+            .pattern_table
+            .get(self.current_table_index as usize)
+            .and_then(|&pattern| self.song.patterns.get(pattern as usize))
+            .and_then(|pattern| pattern.rows.get(row as usize))
+            .and_then(|cells| cells.get(channel))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn num_rows(&self, pattern_index: u8) -> usize {
+        self.song
+            .pattern_table
+            .get(pattern_index as usize)
+            .and_then(|&pattern| self.song.patterns.get(pattern as usize))
+            .map_or(0, |pattern| pattern.rows.len())
+    }
+
+    /// Gets the POT index and row number of the row the next row tick
+    /// plays, after jumps and breaks.
+    pub(super) fn next_row(&self) -> (u8, u8) {
+        let (mut pattern_index, mut row) = (self.current_table_index, self.current_row);
+        if self.position_jump || self.pattern_break {
+            pattern_index = if self.position_jump {
+                self.jump_dest
+            } else {
+                pattern_index.wrapping_add(1)
+            };
+            row = self.jump_row;
+
+            // Loop if necessary
+            if pattern_index as usize >= self.song.pattern_table.len() {
+                pattern_index = self.song.restart_position as u8;
+                if pattern_index as usize >= self.song.pattern_table.len() {
+                    pattern_index = 0;
+                }
+            }
+        }
+
+        if row as usize >= self.num_rows(pattern_index) {
+            // A pattern break past the end of the pattern
+            row = 0;
+        }
+        (pattern_index, row)
+    }
+
+    fn row(&mut self) {
+        (self.current_table_index, self.current_row) = self.next_row();
+        if self.position_jump || self.pattern_break {
+            self.position_jump = false;
+            self.pattern_break = false;
+            self.jump_row = 0;
+        }
+
+        let num_rows = self.num_rows(self.current_table_index);
+        let mut in_a_loop = false;
+
+        // Read notes…
+        for i in 0..self.num_pattern_channels {
+            let mut s = self.cell(self.current_row, i);
+            // A live note takes the place of a cell without an effect
+            if s.effect_type == 0 && s.effect_param == 0 {
+                if let Some(live) = self.channels[i].live.take() {
+                    s = delayed_to_tick(live, 0);
+                }
+            }
+            self.channels[i].current = s;
+
+            if s.effect_type != 0xE || s.effect_param >> 4 != 0xD {
+                self.handle_note_and_instrument(i, s);
+            } else {
+                self.channels[i].note_delay_param = s.effect_param & 0x0F;
+            }
+
+            if !in_a_loop && self.channels[i].pattern_loop_count > 0 {
+                in_a_loop = true;
+            }
+        }
+        // Free channels have no pattern data
+        for ch in &mut self.channels[self.num_pattern_channels..] {
+            ch.current = Cell::default();
+        }
+
+        if !in_a_loop {
+            // No E6y loop is in effect (or we are in the first pass)
+            let index = self.current_table_index as usize * 256 + self.current_row as usize;
+            self.loop_count = self.row_loop_count[index];
+            self.row_loop_count[index] = self.loop_count.wrapping_add(1);
+        }
+
+        // Since this is an u8, this line can increment from 255 to 0, in
+        // which case it is still necessary to go the next pattern.
+        self.current_row = self.current_row.wrapping_add(1);
+        if !self.position_jump
+            && !self.pattern_break
+            && (self.current_row as usize >= num_rows || self.current_row == 0)
+        {
+            self.current_table_index = self.current_table_index.wrapping_add(1);
+            // This will be 0 most of the time, except when E60 is used
+            self.current_row = self.jump_row;
+            self.jump_row = 0;
+            self.post_pattern_change();
+        }
+    }
+
+    /// Reads the live note of a channel, if any, like a cell with a note
+    /// delay to the current tick. Past tick 255, which the delay can't
+    /// hold, the note waits.
+    fn play_live_note(&mut self, i: usize) {
+        let tick = match u8::try_from(self.current_tick) {
+            Ok(tick) => tick,
+            Err(_) => return,
+        };
+        if let Some(live) = self.channels[i].live.take() {
+            let ch = &mut self.channels[i];
+            ch.current = delayed_to_tick(live, tick);
+            ch.note_delay_param = tick;
+        }
+    }
+
+    fn envelopes(&mut self, i: usize) {
+        let j = match self.channels[i].instrument {
+            Some(j) => j,
+            None => return,
+        };
+        let instr = &self.song.instruments[j];
+        let ch = &mut self.channels[i];
+
+        if instr.volume_envelope.enabled {
+            if !ch.sustained {
+                ch.fadeout_volume -= instr.volume_fadeout as f32 / 65536.0;
+                clamp_down(&mut ch.fadeout_volume);
+            }
+
+            let sustained = ch.sustained;
+            envelope_tick(
+                sustained,
+                &instr.volume_envelope,
+                &mut ch.volume_envelope_frame_count,
+                &mut ch.volume_envelope_volume,
+            );
+        }
+
+        if instr.panning_envelope.enabled {
+            let sustained = ch.sustained;
+            envelope_tick(
+                sustained,
+                &instr.panning_envelope,
+                &mut ch.panning_envelope_frame_count,
+                &mut ch.panning_envelope_panning,
+            );
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.current_tick == 0 {
+            self.row();
+        }
+
+        for i in 0..self.channels.len() {
+            // On the first tick of a row, the channels of the module only
+            // play live notes in place of a cell (see `row()`)
+            if self.current_tick != 0 || i >= self.num_pattern_channels {
+                self.play_live_note(i);
+            }
+
+            self.envelopes(i);
+            self.autovibrato(i);
+
+            let current = self.channels[i].current;
+
+            if self.channels[i].arp_in_progress && !has_arpeggio(&current) {
+                let ch = &mut self.channels[i];
+                ch.arp_in_progress = false;
+                ch.arp_note_offset = 0;
+                self.update_frequency(i);
+            }
+            if self.channels[i].vibrato_in_progress && !has_vibrato(&current) {
+                let ch = &mut self.channels[i];
+                ch.vibrato_in_progress = false;
+                ch.vibrato_note_offset = 0.0;
+                self.update_frequency(i);
+            }
+
+            if self.current_tick != 0 {
+                self.volume_column_tick(i, current);
+            }
+            self.effect_tick(i, current);
+            self.update_target_volume(i);
+        }
+
+        self.current_tick += 1;
+        if self.current_tick >= self.tempo.wrapping_add(self.extra_ticks) {
+            self.current_tick = 0;
+            self.extra_ticks = 0;
+        }
+
+        // FT2 manual says number of ticks / second = BPM * 0.4
+        self.remaining_samples_in_tick += self.rate as f32 / (self.bpm as f32 * 0.4 * self.speed);
+    }
+
+    /// Computes the volume of each side of a channel, from the volume and
+    /// panning of the channel and the mixer settings.
+    pub(super) fn update_target_volume(&mut self, i: usize) {
+        let mixer = &self.mixer;
+        let ch = &mut self.channels[i];
+
+        let base = mixer.panning(i).unwrap_or(ch.panning);
+        let mut panning =
+            base + (ch.panning_envelope_panning - 0.5) * (0.5 - (base - 0.5).abs()) * 2.0;
+        if mixer.stereo_separation != 1.0 {
+            panning = 0.5 + (panning - 0.5) * mixer.stereo_separation;
+        }
+
+        let volume = if ch.tremor_on {
+            0.0
+        } else {
+            let mut volume = ch.volume + ch.tremolo_volume;
+            clamp_up(&mut volume);
+            clamp_down(&mut volume);
+            volume * ch.fadeout_volume * ch.volume_envelope_volume
+        };
+        let volume = volume * mixer.volume(i);
+
+        let target = [volume * sqrtf(1.0 - panning), volume * sqrtf(panning)];
+        #[cfg(feature = "ramping")]
+        {
+            ch.target_volume = target;
+            ch.target_panning = panning;
+        }
+        #[cfg(not(feature = "ramping"))]
+        {
+            ch.actual_volume = target;
+            ch.actual_panning = panning;
+        }
+    }
+
+    fn volume_column_tick(&mut self, i: usize, current: Cell) {
+        match current.volume_column >> 4 {
+            0x6 => {
+                // Volume slide down
+                volume_slide(&mut self.channels[i], current.volume_column & 0x0F);
+            }
+            0x7 => {
+                // Volume slide up
+                volume_slide(&mut self.channels[i], current.volume_column << 4);
+            }
+            0xB => {
+                // Vibrato
+                self.channels[i].vibrato_in_progress = false;
+                self.vibrato(i);
+            }
+            0xD => {
+                // Panning slide left
+                panning_slide(&mut self.channels[i], current.volume_column & 0x0F);
+            }
+            0xE => {
+                // Panning slide right
+                panning_slide(&mut self.channels[i], current.volume_column << 4);
+            }
+            0xF => {
+                // Tone portamento
+                self.tone_portamento(i);
+            }
+            _ => {}
+        }
+    }
+
+    fn effect_tick(&mut self, i: usize, current: Cell) {
+        let tick = self.current_tick;
+
+        match current.effect_type {
+            0 if current.effect_param > 0 => {
+                // 0xy: Arpeggio
+                let arp_offset = self.tempo % 3;
+                if arp_offset == 2 && tick == 1 {
+                    // 0 -> x -> 0 -> y -> x -> …
+                    let ch = &mut self.channels[i];
+                    ch.arp_in_progress = true;
+                    ch.arp_note_offset = current.effect_param >> 4;
+                    self.update_frequency(i);
+                } else if arp_offset >= 1 && tick == 0 {
+                    // 0 -> 0 -> y -> x -> …
+                    let ch = &mut self.channels[i];
+                    ch.arp_in_progress = false;
+                    ch.arp_note_offset = 0;
+                    self.update_frequency(i);
+                } else {
+                    // 0 -> y -> x -> …
+                    self.arpeggio(i, current.effect_param, tick.wrapping_sub(arp_offset));
+                }
+            }
+            1 if tick != 0 => {
+                // 1xx: Portamento up
+                let offset = -(self.channels[i].portamento_up_param as f32);
+                self.pitch_slide(i, offset);
+            }
+            2 if tick != 0 => {
+                // 2xx: Portamento down
+                let offset = self.channels[i].portamento_down_param as f32;
+                self.pitch_slide(i, offset);
+            }
+            3 if tick != 0 => {
+                // 3xx: Tone portamento
+                self.tone_portamento(i);
+            }
+            4 if tick != 0 => {
+                // 4xy: Vibrato
+                self.channels[i].vibrato_in_progress = true;
+                self.vibrato(i);
+            }
+            5 if tick != 0 => {
+                // 5xy: Tone portamento + Volume slide
+                self.tone_portamento(i);
+                let ch = &mut self.channels[i];
+                let param = ch.volume_slide_param;
+                volume_slide(ch, param);
+            }
+            6 if tick != 0 => {
+                // 6xy: Vibrato + Volume slide
+                self.channels[i].vibrato_in_progress = true;
+                self.vibrato(i);
+                let ch = &mut self.channels[i];
+                let param = ch.volume_slide_param;
+                volume_slide(ch, param);
+            }
+            7 if tick != 0 => {
+                // 7xy: Tremolo
+                self.tremolo(i);
+            }
+            0xA if tick != 0 => {
+                // Axy: Volume slide
+                let ch = &mut self.channels[i];
+                let param = ch.volume_slide_param;
+                volume_slide(ch, param);
+            }
+            0xE => {
+                // EXy: Extended command
+                match current.effect_param >> 4 {
+                    0x9 => {
+                        // E9y: Retrigger note
+                        let y = (current.effect_param & 0x0F) as u16;
+                        if tick != 0 && y != 0 && tick.is_multiple_of(y) {
+                            self.trigger_note(i, TRIGGER_KEEP_VOLUME);
+                            self.envelopes(i);
+                        }
+                    }
+                    0xC if (current.effect_param & 0x0F) as u16 == tick => {
+                        // ECy: Note cut
+                        cut_note(&mut self.channels[i]);
+                    }
+                    0xD if self.channels[i].note_delay_param as u16 == tick => {
+                        // EDy: Note delay
+                        self.handle_note_and_instrument(i, current);
+                        self.envelopes(i);
+                    }
+                    _ => {}
+                }
+            }
+            17 if tick != 0 => {
+                // Hxy: Global volume slide
+                let param = self.channels[i].global_volume_slide_param;
+                if param & 0xF0 != 0 && param & 0x0F != 0 {
+                    // Illegal state
+                } else if param & 0xF0 != 0 {
+                    // Global slide up
+                    self.global_volume += (param >> 4) as f32 / 0x40 as f32;
+                    clamp_up(&mut self.global_volume);
+                } else {
+                    // Global slide down
+                    self.global_volume -= (param & 0x0F) as f32 / 0x40 as f32;
+                    clamp_down(&mut self.global_volume);
+                }
+            }
+            20 if tick == current.effect_param as u16 => {
+                // Kxx: Key off
+                // Most documentations will tell you the parameter has no
+                // use. Don't be fooled.
+                self.key_off(i);
+            }
+            25 if tick != 0 => {
+                // Pxy: Panning slide
+                let ch = &mut self.channels[i];
+                let param = ch.panning_slide_param;
+                panning_slide(ch, param);
+            }
+            27 if tick != 0 => {
+                // Rxy: Multi retrig note
+                let param = self.channels[i].multi_retrig_param;
+                if param & 0x0F != 0 && tick.is_multiple_of((param & 0x0F) as u16) {
+                    self.trigger_note(i, TRIGGER_KEEP_VOLUME | TRIGGER_KEEP_ENVELOPE);
+
+                    // Rxy doesn't affect volume if there's a command in the
+                    // volume column, or if the instrument has a volume
+                    // envelope.
+                    let envelope = self
+                        .instrument_of(i)
+                        .is_some_and(|instr| instr.volume_envelope.enabled);
+                    if current.volume_column == 0 && !envelope {
+                        let ch = &mut self.channels[i];
+                        let x = (param >> 4) as usize;
+                        let mut v = ch.volume * MULTI_RETRIG_MULTIPLY[x]
+                            + MULTI_RETRIG_ADD[x] / 0x40 as f32;
+                        clamp_up(&mut v);
+                        clamp_down(&mut v);
+                        ch.volume = v;
+                    }
+                }
+            }
+            29 if tick != 0 => {
+                // Txy: Tremor
+                let ch = &mut self.channels[i];
+                let param = ch.tremor_param as u16;
+                ch.tremor_on = (tick - 1) % ((param >> 4) + (param & 0x0F) + 2) > (param >> 4);
+            }
+            _ => {}
+        }
+    }
+
+    /// Gets the next point of the sample playing in a channel, and moves
+    /// the channel to the following point.
+    fn next_of_sample(&mut self, i: usize) -> f32 {
+        let interpolation = self.interpolation;
+        let (j, k) = match self.channels[i].sample {
+            Some(sample) if self.channels[i].instrument.is_some() => sample,
+            _ => return self.ramp_from_previous_sample(i, 0.0),
+        };
+        let sample = &self.song.instruments[j].samples[k];
+        let ch = &mut self.channels[i];
+
+        if ch.sample_position < 0.0 {
+            return self.ramp_from_previous_sample(i, 0.0);
+        }
+        if sample.length == 0 {
+            return 0.0;
+        }
+
+        let position = ch.sample_position;
+        let ping = ch.ping;
+
+        // This cast is fine, sample_position will not go above integer
+        // ranges
+        let a = ch.sample_position as u32;
+        let b = a + 1;
+        let t = ch.sample_position - a as f32;
+        let mut u = sample.at(a);
+        let v;
+
+        match sample.loop_type {
+            LoopType::NoLoop => {
+                v = if b < sample.length { sample.at(b) } else { 0.0 };
+                ch.sample_position += ch.step;
+                if ch.sample_position >= sample.length as f32 {
+                    ch.sample_position = -1.0;
+                }
+            }
+            LoopType::Forward => {
+                v = sample.at(if b == sample.loop_end {
+                    sample.loop_start
+                } else {
+                    b.min(sample.length - 1)
+                });
+                ch.sample_position += ch.step;
+                while ch.sample_position >= sample.loop_end as f32 {
+                    ch.sample_position -= sample.loop_length as f32;
+                }
+            }
+            LoopType::PingPong => {
+                if ch.ping {
+                    ch.sample_position += ch.step;
+                } else {
+                    ch.sample_position -= ch.step;
+                }
+                // XXX: this may not work for very tight ping-pong loops
+                // (ie switches direction more than once per sample
+                if ch.ping {
+                    v = sample.at(if b >= sample.loop_end { a } else { b });
+                    if ch.sample_position >= sample.loop_end as f32 {
+                        ch.ping = false;
+                        ch.sample_position = (sample.loop_end << 1) as f32 - ch.sample_position;
+                    }
+                    // sanity checking
+                    if ch.sample_position >= sample.length as f32 {
+                        ch.ping = false;
+                        ch.sample_position -= (sample.length - 1) as f32;
+                    }
+                } else {
+                    v = u;
+                    u = sample.at(if b == 1 || b - 2 <= sample.loop_start {
+                        a
+                    } else {
+                        b - 2
+                    });
+                    if ch.sample_position <= sample.loop_start as f32 {
+                        ch.ping = true;
+                        ch.sample_position = (sample.loop_start << 1) as f32 - ch.sample_position;
+                    }
+                    // sanity checking
+                    if ch.sample_position <= 0.0 {
+                        ch.ping = true;
+                        ch.sample_position = 0.0;
+                    }
+                }
+            }
+        }
+
+        let value = match interpolation {
+            Interpolation::Nearest => {
+                if ping {
+                    sample.at(a)
+                } else {
+                    u
+                }
+            }
+            Interpolation::Linear => lerp(u, v, t),
+            Interpolation::Cubic => cubic(sample, position, ping),
+            Interpolation::Sinc(taps) => sinc(sample, position, ping, taps, ch.step),
+        };
+
+        self.ramp_from_previous_sample(i, value)
+    }
+
+    #[cfg(feature = "ramping")]
+    #[inline]
+    fn ramp_from_previous_sample(&self, i: usize, value: f32) -> f32 {
+        let ch = &self.channels[i];
+        let frame = ch.frame_count as usize;
+        if frame < super::SAMPLE_RAMPING_POINTS {
+            // Smoothly transition between old and new sample.
+            lerp(
+                ch.end_of_previous_sample[frame],
+                value,
+                frame as f32 / super::SAMPLE_RAMPING_POINTS as f32,
+            )
+        } else {
+            value
+        }
+    }
+
+    #[cfg(not(feature = "ramping"))]
+    #[inline]
+    fn ramp_from_previous_sample(&self, _: usize, value: f32) -> f32 {
+        value
+    }
+
+    /// Generates one stereo frame.
+    pub(super) fn sample(&mut self) -> (f32, f32) {
+        self.sample_channels(|_, _, _, _| {})
+    }
+
+    /// Generates a frame, like `sample()`, and gives what each channel
+    /// contributes to it to `channel_output`, with the index and the
+    /// panning of the channel. Muted and silent channels contribute nothing.
+    pub(super) fn sample_channels(
+        &mut self,
+        mut channel_output: impl FnMut(usize, f32, f32, f32),
+    ) -> (f32, f32) {
+        if self.remaining_samples_in_tick <= 0.0 {
+            self.tick();
+        }
+        self.remaining_samples_in_tick -= 1.0;
+
+        let (mut left, mut right) = (0.0, 0.0);
+
+        if self.max_loop_count > 0 && self.loop_count >= self.max_loop_count {
+            return (left, right);
+        }
+
+        #[cfg(feature = "ramping")]
+        slide_towards(
+            &mut self.master_volume,
+            self.mixer.master_volume,
+            self.volume_ramp,
+        );
+        #[cfg(not(feature = "ramping"))]
+        {
+            self.master_volume = self.mixer.master_volume;
+        }
+        let fgvol = self.global_volume * self.amplification * self.master_volume;
+
+        for i in 0..self.channels.len() {
+            let ch = &self.channels[i];
+            let instr_muted = match (ch.instrument, ch.sample) {
+                (Some(j), Some(_)) if ch.sample_position >= 0.0 => self.muted_instruments[j],
+                _ => continue,
+            };
+
+            let fval = self.next_of_sample(i);
+            let ch = &mut self.channels[i];
+
+            if !ch.muted && !instr_muted {
+                let (l, r) = (fval * ch.actual_volume[0], fval * ch.actual_volume[1]);
+                left += l;
+                right += r;
+                channel_output(i, l * fgvol, r * fgvol, ch.actual_panning);
+            }
+
+            #[cfg(feature = "ramping")]
+            {
+                ch.frame_count += 1;
+                slide_towards(
+                    &mut ch.actual_volume[0],
+                    ch.target_volume[0],
+                    self.volume_ramp,
+                );
+                slide_towards(
+                    &mut ch.actual_volume[1],
+                    ch.target_volume[1],
+                    self.volume_ramp,
+                );
+                slide_towards(&mut ch.actual_panning, ch.target_panning, self.volume_ramp);
+            }
+        }
+
+        (left * fgvol, right * fgvol)
+    }
+}
+
+fn cut_note(ch: &mut Channel) {
+    // NB: this is not the same as Key Off
+    ch.volume = 0.0;
+}
+
+fn volume_slide(ch: &mut Channel, rawval: u8) {
+    if rawval & 0xF0 != 0 && rawval & 0x0F != 0 {
+        // Illegal state
+        return;
+    }
+    if rawval & 0xF0 != 0 {
+        // Slide up
+        ch.volume += (rawval >> 4) as f32 / 0x40 as f32;
+        clamp_up(&mut ch.volume);
+    } else {
+        // Slide down
+        ch.volume -= (rawval & 0x0F) as f32 / 0x40 as f32;
+        clamp_down(&mut ch.volume);
+    }
+}
+
+fn panning_slide(ch: &mut Channel, rawval: u8) {
+    if rawval & 0xF0 != 0 && rawval & 0x0F != 0 {
+        // Illegal state
+        return;
+    }
+    if rawval & 0xF0 != 0 {
+        // Slide right
+        ch.panning += (rawval >> 4) as f32 / 0xFF as f32;
+        clamp_up(&mut ch.panning);
+    } else {
+        // Slide left
+        ch.panning -= (rawval & 0x0F) as f32 / 0xFF as f32;
+        clamp_down(&mut ch.panning);
+    }
+}
+
+fn envelope_lerp(a: &EnvelopePoint, b: &EnvelopePoint, pos: u16) -> f32 {
+    // Linear interpolation between two envelope points
+    if pos <= a.frame {
+        a.value as f32
+    } else if pos >= b.frame {
+        b.value as f32
+    } else {
+        let p = (pos - a.frame) as f32 / (b.frame - a.frame) as f32;
+        a.value as f32 * (1.0 - p) + b.value as f32 * p
+    }
+}
+
+fn envelope_tick(sustained: bool, env: &Envelope, counter: &mut u16, outval: &mut f32) {
+    let num_points = env.points.len();
+
+    if num_points < 2 {
+        // Don't really know what to do…
+        if num_points == 1 {
+            // XXX I am pulling this out of my ass
+            *outval = env.points[0].value as f32 / 0x40 as f32;
+            if *outval > 1.0 {
+                *outval = 1.0;
+            }
+        }
+        return;
+    }
+
+    let point = |index: u8| env.points[(index as usize).min(num_points - 1)];
+
+    if env.loop_enabled {
+        let loop_start = point(env.loop_start_point).frame;
+        let loop_end = point(env.loop_end_point).frame;
+        let loop_length = loop_end.wrapping_sub(loop_start);
+
+        if *counter >= loop_end {
+            *counter = counter.wrapping_sub(loop_length);
+        }
+    }
+
+    let mut j = 0;
+    while j < num_points - 2 {
+        if env.points[j].frame <= *counter && env.points[j + 1].frame >= *counter {
+            break;
+        }
+        j += 1;
+    }
+
+    *outval = envelope_lerp(&env.points[j], &env.points[j + 1], *counter) / 0x40 as f32;
+
+    // Make sure it is safe to increment frame count
+    if !sustained || !env.sustain_enabled || *counter != point(env.sustain_point).frame {
+        *counter = counter.wrapping_add(1);
+    }
+}
+
+/// Gets a point of a sample at any index, following its loop, for the
+/// interpolation modes libxm doesn't have.
+fn sample_at(sample: &Sample, index: i64) -> f32 {
+    let length = sample.length as i64;
+    let (loop_start, loop_end) = (sample.loop_start as i64, sample.loop_end as i64);
+    let loop_length = loop_end - loop_start;
+
+    let index = match sample.loop_type {
+        _ if index < 0 => return 0.0,
+        LoopType::NoLoop if index >= length => return 0.0,
+        LoopType::Forward if index >= loop_end => loop_start + (index - loop_end) % loop_length,
+        LoopType::PingPong if index >= loop_end => {
+            // Bounce between the loop ends
+            let offset = (index - loop_end) % (2 * loop_length);
+            if offset < loop_length {
+                loop_end - 1 - offset
+            } else {
+                loop_start + offset - loop_length
+            }
+        }
+        _ => index,
+    };
+
+    sample.at(index.clamp(0, length - 1) as u32)
+}
+
+/// Catmull-Rom interpolation around `position`.
+fn cubic(sample: &Sample, position: f32, forward: bool) -> f32 {
+    let a = floorf(position) as i64;
+    let t = position - a as f32;
+    let d = if forward { 1 } else { -1 };
+
+    let p0 = sample_at(sample, a - d);
+    let p1 = sample_at(sample, a);
+    let p2 = sample_at(sample, a + d);
+    let p3 = sample_at(sample, a + 2 * d);
+
+    p1 + 0.5
+        * t
+        * (p2 - p0 + t * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3 + t * (3.0 * (p1 - p2) + p3 - p0)))
+}
+
+/// Windowed sinc interpolation around `position`, low-pass filtered when
+/// the sample is played faster than the output rate.
+fn sinc(sample: &Sample, position: f32, forward: bool, taps: u8, step: f32) -> f32 {
+    let half = (taps / 2).max(1) as i64;
+    let a = floorf(position) as i64;
+    let t = (position - a as f32) as f64;
+    let d = if forward { 1 } else { -1 };
+    let cutoff = if step > 1.0 { 1.0 / step as f64 } else { 1.0 };
+
+    let mut sum = 0.0;
+    let mut weights = 0.0;
+    for k in (1 - half)..=half {
+        let x = k as f64 - t;
+        let arg = core::f64::consts::PI * x * cutoff;
+        let sinc = if arg == 0.0 { 1.0 } else { sin(arg) / arg };
+        // Blackman window over [-half, half]
+        let w = (x / half as f64 + 1.0) * 0.5;
+        let window = if (0.0..=1.0).contains(&w) {
+            0.42 - 0.5 * cos(2.0 * core::f64::consts::PI * w)
+                + 0.08 * cos(4.0 * core::f64::consts::PI * w)
+        } else {
+            0.0
+        };
+        let weight = sinc * window;
+        sum += weight * sample_at(sample, a + d * k) as f64;
+        weights += weight;
+    }
+
+    if weights == 0.0 {
+        0.0
+    } else {
+        (sum / weights) as f32
+    }
+}
@@ -0,0 +1,291 @@
+//! Timestamped playback events, for syncing visuals to the music.
+//!
+//! # Example
+//! ```no_run
+//! use libxm::events::EventKind;
+//! use libxm::XMContext;
+//!
+//! fn audio_callback(xm: &mut XMContext, buffer: &mut [f32]) {
+//!     let mut events = Vec::new();
+//!     xm.generate_samples_with_events(buffer, &mut events);
+//!
+//!     for event in &events {
+//!         if let EventKind::NoteOn { channel, instrument, note } = event.kind {
+//!             // Schedule a flash `event.offset` samples into the buffer...
+//!         }
+//!     }
+//! }
+//! ```
+
+use crate::{try_to_vec, XMContext, XMError};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// An event that happened while generating samples.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// Offset of the event in the output buffer, in samples (stereo frames).
+    /// The event happened while generating the frame at
+    /// `output[2 * offset..2 * offset + 2]`.
+    pub offset: usize,
+    /// What happened
+    pub kind: EventKind,
+}
+
+/// The possible playback events.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// A new row started playing.
+    RowChanged {
+        /// Pattern index in the POT (pattern order table)
+        pattern_index: u8,
+        /// Pattern number
+        pattern: u8,
+        /// Row number
+        row: u8,
+    },
+    /// A different entry of the POT started playing. Always followed by the
+    /// `RowChanged` event of its first row.
+    PatternChanged {
+        /// Pattern index in the POT (pattern order table)
+        pattern_index: u8,
+        /// Pattern number
+        pattern: u8,
+    },
+    /// A note was triggered. This includes delayed and retriggered notes,
+    /// and the notes of `XMContext::note_on()`.
+    NoteOn {
+        /// Channel number, starting at 1, followed by the free channels
+        channel: u16,
+        /// Instrument number, starting at 1
+        instrument: u16,
+        /// Note, from 1 (C-0) to 96 (B-7)
+        note: u8,
+    },
+    /// A note was released, by a key off or a Kxx effect in the pattern
+    /// data, or by `XMContext::note_off()`.
+    NoteOff {
+        /// Channel number, starting at 1, followed by the free channels
+        channel: u16,
+    },
+    /// An effect command in the pattern data.
+    Effect {
+        /// Channel number, starting at 1
+        channel: u16,
+        /// The effect command
+        effect_type: u8,
+        /// The effect parameter
+        effect_param: u8,
+    },
+    /// The module looped.
+    Loop {
+        /// The new loop count
+        loop_count: u8,
+    },
+}
+
+/// What was observed after the last generated sample, to tell what changed.
+pub(crate) struct EventState {
+    samples: u64,
+    loop_count: u8,
+    // POT index and row number of the row being played, if any
+    current_row: Option<(u8, u8)>,
+    // Per channel, free channels included
+    latest_triggers: Vec<u64>,
+    notes: Vec<u8>,
+    sustained: Vec<bool>,
+}
+
+impl EventState {
+    /// Copies the state, or fails if there isn't enough memory.
+    pub(crate) fn try_clone(&self) -> Result<EventState, XMError> {
+        Ok(EventState {
+            latest_triggers: try_to_vec(&self.latest_triggers)?,
+            notes: try_to_vec(&self.notes)?,
+            sustained: try_to_vec(&self.sustained)?,
+            ..*self
+        })
+    }
+}
+
+impl XMContext {
+    /// Plays the module and puts the sound samples in the specified output
+    /// buffer, like `generate_samples()`. The events that happened while
+    /// generating the samples are appended to `events`, in order.
+    ///
+    /// # Note
+    /// The players have no event callbacks. The samples are generated up to
+    /// each tick, and the playback state is checked after it. This is a
+    /// little slower than `generate_samples()`.
+    ///
+    /// # Return
+    /// The number of samples written to the output buffer
+    pub fn generate_samples_with_events(
+        &mut self,
+        output: &mut [f32],
+        events: &mut Vec<Event>,
+    ) -> usize {
+        assert!(output.len().is_multiple_of(2));
+
+        let num_channels = self.number_of_channels() + self.free_channels;
+        let mut state = match self.event_state.take() {
+            // The state is stale if samples were generated some other way,
+            // or if channels were added since
+            Some(state)
+                if state.samples == self.position().samples
+                    && state.notes.len() == num_channels as usize =>
+            {
+                state
+            }
+            _ => self.observe_event_state(),
+        };
+
+        let frames = output.len() / 2;
+        let mut offset = 0;
+        while offset < frames {
+            // Nothing happens until the next tick
+            let (tick, before_tick) = self.player.next_tick();
+            if before_tick > 0 {
+                let end = frames.min(offset + before_tick);
+                self.player
+                    .generate_samples(&mut output[2 * offset..2 * end]);
+                offset = end;
+                continue;
+            }
+
+            // Rows are processed on their first tick, even when a jump
+            // plays the same row again
+            let new_row = (tick == 0).then(|| self.player.next_row());
+            self.player
+                .generate_samples(&mut output[2 * offset..2 * offset + 2]);
+
+            let loop_count = self.loop_count();
+            if loop_count != state.loop_count {
+                state.loop_count = loop_count;
+                events.push(Event {
+                    offset,
+                    kind: EventKind::Loop { loop_count },
+                });
+            }
+
+            if let Some((pattern_index, row)) = new_row {
+                self.push_row_events(&mut state, pattern_index, row, offset, events);
+            }
+            let row_cells = state.current_row.and_then(|(pattern_index, row)| {
+                let pattern = *self.headers.pattern_table.get(pattern_index as usize)?;
+                let cells = self
+                    .headers
+                    .patterns
+                    .get(pattern as usize)?
+                    .rows
+                    .get(row as usize)?;
+                Some(&cells[..])
+            });
+
+            for channel in 1..=num_channels {
+                let i = channel as usize - 1;
+
+                let sustained = self.player.is_sustained(channel);
+                if state.sustained[i] && !sustained {
+                    events.push(Event {
+                        offset,
+                        kind: EventKind::NoteOff { channel },
+                    });
+                }
+                state.sustained[i] = sustained;
+
+                // Free channels have no cells
+                let cell = row_cells.and_then(|cells| cells.get(i));
+                if let Some(cell) = cell.filter(|_| new_row.is_some()) {
+                    if cell.effect_type != 0 || cell.effect_param != 0 {
+                        events.push(Event {
+                            offset,
+                            kind: EventKind::Effect {
+                                channel,
+                                effect_type: cell.effect_type,
+                                effect_param: cell.effect_param,
+                            },
+                        });
+                    }
+                }
+
+                let latest_trigger = self.player.latest_trigger_of_channel(channel);
+                if latest_trigger != state.latest_triggers[i] {
+                    state.latest_triggers[i] = latest_trigger;
+                    // The cell played, which may be a live note. Without a
+                    // note, the previous note is retriggered.
+                    let cell = self.player.current_cell(channel);
+                    if cell.has_note() {
+                        state.notes[i] = cell.note;
+                    }
+                    events.push(Event {
+                        offset,
+                        kind: EventKind::NoteOn {
+                            channel,
+                            instrument: self.player.channel_state(channel).instrument,
+                            note: state.notes[i],
+                        },
+                    });
+                }
+            }
+            offset += 1;
+        }
+
+        state.samples = self.position().samples;
+        self.event_state = Some(state);
+        output.len()
+    }
+
+    fn observe_event_state(&self) -> EventState {
+        let position = self.position();
+        let num_channels = self.number_of_channels() + self.free_channels;
+
+        EventState {
+            samples: position.samples,
+            loop_count: self.loop_count(),
+            current_row: None,
+            latest_triggers: (1..=num_channels)
+                .map(|channel| self.player.latest_trigger_of_channel(channel))
+                .collect(),
+            notes: vec![0; num_channels as usize],
+            sustained: (1..=num_channels)
+                .map(|channel| self.player.is_sustained(channel))
+                .collect(),
+        }
+    }
+
+    /// Pushes the events of a new row.
+    fn push_row_events(
+        &self,
+        state: &mut EventState,
+        pattern_index: u8,
+        row: u8,
+        offset: usize,
+        events: &mut Vec<Event>,
+    ) {
+        let pattern = match self.headers.pattern_table.get(pattern_index as usize) {
+            Some(&pattern) => pattern,
+            None => return,
+        };
+
+        if state.current_row.map(|(index, _)| index) != Some(pattern_index) {
+            events.push(Event {
+                offset,
+                kind: EventKind::PatternChanged {
+                    pattern_index,
+                    pattern,
+                },
+            });
+        }
+        state.current_row = Some((pattern_index, row));
+
+        events.push(Event {
+            offset,
+            kind: EventKind::RowChanged {
+                pattern_index,
+                pattern,
+                row,
+            },
+        });
+    }
+}
@@ -1,20 +1,28 @@
+//! The C API of libxm.
+//!
+//! libxm isn't built with the `pure-rust` feature, unless `libxm-reference`
+//! is enabled too. These functions can't be linked without it.
+
 #![allow(nonstandard_style)]
 
-pub use std::ffi::{c_char, c_float, c_int};
+pub use core::ffi::{c_char, c_float, c_int, c_void};
 pub type size_t = usize;
 
 pub enum xm_context {}
 pub type xm_context_t = xm_context;
 
-extern "C" {
-    // TODO:
-    // xm_get_sample_waveform
-    // xm_is_channel_active
-    // xm_get_instrument_of_channel
-    // xm_get_frequency_of_channel
-    // xm_get_volume_of_channel
-    // xm_get_panning_of_channel
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct xm_pattern_slot {
+    pub note: u8,
+    pub instrument: u8,
+    pub volume_column: u8,
+    pub effect_type: u8,
+    pub effect_param: u8,
+}
+pub type xm_pattern_slot_t = xm_pattern_slot;
 
+extern "C" {
     pub fn xm_create_context_safe(
         context: *mut *mut xm_context_t,
         moddata: *const c_char,
@@ -38,6 +46,13 @@ extern "C" {
     pub fn xm_get_number_of_rows(context: *mut xm_context_t, pattern: u16) -> u16;
     pub fn xm_get_number_of_instruments(context: *mut xm_context_t) -> u16;
     pub fn xm_get_number_of_samples(context: *mut xm_context_t, instrument: u16) -> u16;
+    pub fn xm_get_sample_waveform(
+        context: *mut xm_context_t,
+        instrument: u16,
+        sample: u16,
+        length: *mut size_t,
+        bits: *mut u8,
+    ) -> *mut c_void;
 
     pub fn xm_get_playing_speed(context: *mut xm_context_t, bpm: *mut u16, tempo: *mut u16);
     pub fn xm_get_position(
@@ -55,7 +70,48 @@ extern "C" {
     ) -> u64;
     pub fn xm_get_latest_trigger_of_channel(context: *mut xm_context_t, channel: u16) -> u64;
 
+    pub fn xm_is_channel_active(context: *mut xm_context_t, channel: u16) -> bool;
+    pub fn xm_get_instrument_of_channel(context: *mut xm_context_t, channel: u16) -> u16;
+    pub fn xm_get_frequency_of_channel(context: *mut xm_context_t, channel: u16) -> c_float;
+    pub fn xm_get_volume_of_channel(context: *mut xm_context_t, channel: u16) -> c_float;
+    pub fn xm_get_panning_of_channel(context: *mut xm_context_t, channel: u16) -> c_float;
+
     pub fn xm_seek(context: *mut xm_context_t, pot: u8, row: u8, tick: u16);
     pub fn xm_mute_channel(context: *mut xm_context_t, channel: u16, mute: bool) -> bool;
     pub fn xm_mute_instrument(context: *mut xm_context_t, instrument: u16, mute: bool) -> bool;
+
+    // Not part of libxm: built with it, from src/backend/xm_rs.c
+    pub fn xm_rs_set_bpm(context: *mut xm_context_t, bpm: u16);
+    pub fn xm_rs_set_tempo(context: *mut xm_context_t, tempo: u16);
+    pub fn xm_rs_get_next_tick(
+        context: *mut xm_context_t,
+        tick: *mut u16,
+        remaining_samples_in_tick: *mut c_float,
+    );
+    pub fn xm_rs_get_next_row(context: *mut xm_context_t, pattern_index: *mut u8, row: *mut u8);
+    pub fn xm_rs_get_slot(
+        context: *mut xm_context_t,
+        pattern_index: u8,
+        row: u8,
+        channel: u16,
+    ) -> *mut xm_pattern_slot_t;
+    pub fn xm_rs_get_current_slot(context: *mut xm_context_t, channel: u16) -> xm_pattern_slot_t;
+    pub fn xm_rs_is_sustained(context: *mut xm_context_t, channel: u16) -> bool;
+    pub fn xm_rs_set_current_slot(
+        context: *mut xm_context_t,
+        channel: u16,
+        slot: *mut xm_pattern_slot_t,
+        note_delay_param: u8,
+    );
+    #[cfg(feature = "ramping")]
+    pub fn xm_rs_get_actual_volumes(context: *mut xm_context_t, volumes: *mut c_float);
+    #[cfg(feature = "ramping")]
+    pub fn xm_rs_mix_channel(
+        context: *mut xm_context_t,
+        channel: u16,
+        before: *const c_float,
+        volume: c_float,
+        panning: c_float,
+        separation: c_float,
+    );
 }
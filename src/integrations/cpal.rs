@@ -0,0 +1,232 @@
+//! Plays an `XMContext` on an audio device with `cpal`.
+//!
+//! Enabled by the `cpal` feature.
+//!
+//! # Example
+//! ```no_run
+//! use libxm::integrations::cpal;
+//! use libxm::XMContext;
+//!
+//! # let data = Vec::new();
+//! let mut xm = XMContext::new(&data, 48000).unwrap();
+//! xm.set_max_loop_count(1);
+//!
+//! let player = cpal::play(xm).unwrap();
+//! player.set_volume(0.5);
+//!
+//! // Block until the song has looped
+//! player.loops().recv().unwrap();
+//! ```
+
+use crate::{Position, XMContext};
+use ::cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::{error, fmt};
+
+/// Possible errors from `play()` and `play_on()`.
+#[derive(Debug)]
+pub enum PlayError {
+    /// There is no default output device
+    NoDevice,
+    /// The output stream couldn't be created. The device may not support
+    /// stereo `f32` output at the rate of the context.
+    BuildStream(::cpal::BuildStreamError),
+    /// The output stream couldn't be started
+    PlayStream(::cpal::PlayStreamError),
+}
+
+impl fmt::Display for PlayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PlayError::NoDevice => write!(f, "no default output device"),
+            PlayError::BuildStream(ref err) => write!(f, "couldn't build the stream: {}", err),
+            PlayError::PlayStream(ref err) => write!(f, "couldn't play the stream: {}", err),
+        }
+    }
+}
+
+impl error::Error for PlayError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            PlayError::NoDevice => None,
+            PlayError::BuildStream(ref err) => Some(err),
+            PlayError::PlayStream(ref err) => Some(err),
+        }
+    }
+}
+
+impl From<::cpal::BuildStreamError> for PlayError {
+    fn from(err: ::cpal::BuildStreamError) -> PlayError {
+        PlayError::BuildStream(err)
+    }
+}
+
+impl From<::cpal::PlayStreamError> for PlayError {
+    fn from(err: ::cpal::PlayStreamError) -> PlayError {
+        PlayError::PlayStream(err)
+    }
+}
+
+/// What the audio callback shares with the `Player`.
+struct Shared {
+    xm: Mutex<XMContext>,
+    paused: AtomicBool,
+    // f32 bits
+    volume: AtomicU32,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, XMContext> {
+        // The context stays usable if a callback panicked
+        self.xm.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A handle to a module playing on an audio device. Playback stops when the
+/// player is dropped.
+///
+/// # Note
+/// On some platforms, the `cpal` stream (and so the player) can't be sent
+/// to another thread.
+pub struct Player {
+    shared: Arc<Shared>,
+    loops: Receiver<u8>,
+    errors: Receiver<::cpal::StreamError>,
+    // Dropping the stream stops playback
+    _stream: ::cpal::Stream,
+}
+
+/// Plays a module on the default output device, at the rate of the context.
+pub fn play(xm: XMContext) -> Result<Player, PlayError> {
+    let device = ::cpal::default_host()
+        .default_output_device()
+        .ok_or(PlayError::NoDevice)?;
+
+    play_on(&device, xm)
+}
+
+/// Plays a module on an output device, at the rate of the context.
+pub fn play_on(device: &::cpal::Device, xm: XMContext) -> Result<Player, PlayError> {
+    let config = ::cpal::StreamConfig {
+        channels: 2,
+        sample_rate: ::cpal::SampleRate(xm.rate()),
+        buffer_size: ::cpal::BufferSize::Default,
+    };
+
+    let shared = Arc::new(Shared {
+        xm: Mutex::new(xm),
+        paused: AtomicBool::new(false),
+        volume: AtomicU32::new(1.0f32.to_bits()),
+    });
+    let (loop_tx, loops) = channel();
+    let (error_tx, errors) = channel();
+
+    let callback_shared = Arc::clone(&shared);
+    let mut last_loop_count = callback_shared.lock().loop_count();
+    let callback = move |output: &mut [f32], _: &::cpal::OutputCallbackInfo| {
+        if callback_shared.paused.load(Ordering::Relaxed) {
+            output.fill(0.0);
+            return;
+        }
+
+        let loop_count = {
+            let mut xm = callback_shared.lock();
+            xm.generate_samples(output);
+            xm.loop_count()
+        };
+
+        let volume = f32::from_bits(callback_shared.volume.load(Ordering::Relaxed));
+        if volume != 1.0 {
+            for sample in output.iter_mut() {
+                *sample *= volume;
+            }
+        }
+
+        if loop_count != last_loop_count {
+            last_loop_count = loop_count;
+            // Nobody may be listening
+            let _ = loop_tx.send(loop_count);
+        }
+    };
+    let error_callback = move |err| {
+        let _ = error_tx.send(err);
+    };
+
+    let stream = device.build_output_stream(&config, callback, error_callback, None)?;
+    stream.play()?;
+
+    Ok(Player {
+        shared,
+        loops,
+        errors,
+        _stream: stream,
+    })
+}
+
+impl Player {
+    /// Pauses playback. The device keeps playing silence.
+    pub fn pause(&self) {
+        self.shared.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Resumes playback after `pause()`.
+    pub fn resume(&self) {
+        self.shared.paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Whether playback is paused.
+    pub fn is_paused(&self) -> bool {
+        self.shared.paused.load(Ordering::Relaxed)
+    }
+
+    /// Sets the output volume, where 1.0 is the volume of the module.
+    pub fn set_volume(&self, volume: f32) {
+        self.shared
+            .volume
+            .store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Gets the output volume.
+    pub fn volume(&self) -> f32 {
+        f32::from_bits(self.shared.volume.load(Ordering::Relaxed))
+    }
+
+    /// Gets the current position in the module being played.
+    ///
+    /// This is the position of the samples last handed to the device, which
+    /// are heard after the latency of the device.
+    pub fn position(&self) -> Position {
+        self.shared.lock().position()
+    }
+
+    /// Gets the loop count of the module being played.
+    pub fn loop_count(&self) -> u8 {
+        self.shared.lock().loop_count()
+    }
+
+    /// Whether the module has looped as many times as set by
+    /// `XMContext::set_max_loop_count()`, and is now playing silence.
+    pub fn is_finished(&self) -> bool {
+        self.shared.lock().finished()
+    }
+
+    /// Receives the new loop count every time the module loops.
+    pub fn loops(&self) -> &Receiver<u8> {
+        &self.loops
+    }
+
+    /// Receives the errors reported by the device while playing.
+    pub fn errors(&self) -> &Receiver<::cpal::StreamError> {
+        &self.errors
+    }
+
+    /// Calls `f` with the context being played, for instance to mute
+    /// channels or to seek.
+    ///
+    /// The audio callback waits until `f` returns, so `f` should be quick.
+    pub fn with_context<R, F: FnOnce(&mut XMContext) -> R>(&self, f: F) -> R {
+        f(&mut self.shared.lock())
+    }
+}
@@ -0,0 +1,10 @@
+//! Glue between `XMContext` and audio output libraries, or JavaScript.
+//!
+//! Each integration is behind the Cargo feature of the same name.
+
+#[cfg(feature = "cpal")]
+pub mod cpal;
+#[cfg(feature = "rodio")]
+pub mod rodio;
+#[cfg(feature = "wasm")]
+pub mod wasm;
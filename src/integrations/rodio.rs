@@ -0,0 +1,129 @@
+//! A `rodio::Source` that plays an `XMContext`.
+//!
+//! Enabled by the `rodio` feature.
+//!
+//! # Example
+//! ```no_run
+//! use libxm::integrations::rodio::XmSource;
+//! use libxm::XMContext;
+//!
+//! # let data = Vec::new();
+//! let mut xm = XMContext::new(&data, 48000).unwrap();
+//! xm.set_max_loop_count(1);
+//!
+//! let (_stream, handle) = rodio::OutputStream::try_default().unwrap();
+//! let sink = rodio::Sink::try_new(&handle).unwrap();
+//! sink.append(XmSource::new(xm));
+//! sink.sleep_until_end();
+//! ```
+
+use crate::XMContext;
+use ::rodio::source::SeekError;
+use ::rodio::Source;
+use std::time::Duration;
+
+/// Number of stereo frames generated at a time.
+const CHUNK_FRAMES: usize = 1024;
+
+/// Plays an `XMContext` as an interleaved stereo `f32` source.
+///
+/// The source ends once the module has looped as many times as set by
+/// `XMContext::set_max_loop_count()`. If the maximum loop count is 0, it
+/// never ends.
+pub struct XmSource {
+    xm: XMContext,
+    buffer: Box<[f32]>,
+    // Index of the next sample of `buffer` to return
+    position: usize,
+    // Number of samples of `buffer` that were generated
+    length: usize,
+}
+
+impl XmSource {
+    /// Creates a source playing `xm` from its current position.
+    pub fn new(xm: XMContext) -> XmSource {
+        XmSource {
+            xm,
+            buffer: vec![0.0; CHUNK_FRAMES * 2].into_boxed_slice(),
+            position: 0,
+            length: 0,
+        }
+    }
+
+    /// Gets the context being played.
+    #[inline]
+    pub fn context(&self) -> &XMContext {
+        &self.xm
+    }
+
+    /// Gets the context being played, to change its playback state.
+    ///
+    /// Samples are generated ahead, in chunks of a few milliseconds. Changes
+    /// to the context are heard once the samples already generated have
+    /// been played.
+    #[inline]
+    pub fn context_mut(&mut self) -> &mut XMContext {
+        &mut self.xm
+    }
+
+    /// Gets back the context.
+    #[inline]
+    pub fn into_inner(self) -> XMContext {
+        self.xm
+    }
+}
+
+impl Iterator for XmSource {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if self.position == self.length {
+            if self.xm.finished() {
+                return None;
+            }
+            self.length = self.xm.generate_samples(&mut self.buffer[..]);
+            self.position = 0;
+        }
+
+        let sample = self.buffer[self.position];
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+impl Source for XmSource {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        // The format never changes
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.xm.rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Seeks with `XMContext::seek_to_sample()`.
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let samples = (pos.as_secs_f64() * self.xm.rate() as f64) as u64;
+        self.xm
+            .seek_to_sample(samples)
+            .map_err(|err| SeekError::Other(Box::new(err)))?;
+
+        // Drop the samples generated before the seek
+        self.position = 0;
+        self.length = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+//! A `wasm-bindgen` wrapper, to play modules from JavaScript.
+//!
+//! Enabled by the `wasm` feature, which also enables `pure-rust`: libxm
+//! itself doesn't build for `wasm32-unknown-unknown`.
+//!
+//! Build a `cdylib` crate that depends on `libxm` with the `wasm` feature and
+//! re-exports the wrapper, then run `wasm-bindgen` on it:
+//!
+//! ```ignore
+//! pub use libxm::integrations::wasm::{XmPlayer, XmPosition};
+//! ```
+//!
+//! # Example
+//! In an `AudioWorkletProcessor`, which receives the compiled WebAssembly
+//! module and the module data from the main thread:
+//!
+//! ```js
+//! import { initSync, XmPlayer } from "./player.js";
+//!
+//! class XmProcessor extends AudioWorkletProcessor {
+//!     constructor(options) {
+//!         super();
+//!         const { wasm, data } = options.processorOptions;
+//!         initSync({ module: wasm });
+//!         this.player = new XmPlayer(new Uint8Array(data), sampleRate);
+//!     }
+//!
+//!     process(inputs, outputs) {
+//!         const [left, right] = outputs[0];
+//!         this.player.generatePlanar(left, right);
+//!         return true;
+//!     }
+//! }
+//!
+//! registerProcessor("xm-processor", XmProcessor);
+//! ```
+//!
+//! # Note
+//! `AudioWorkletGlobalScope` has no `TextDecoder`, which the glue generated
+//! by `wasm-bindgen` uses to pass strings, including error messages. Either
+//! polyfill it in the worklet, or make sure loading the module can't fail
+//! there (for instance by loading it once on the main thread first).
+
+use crate::XMContext;
+use wasm_bindgen::prelude::*;
+
+/// A module being played, for JavaScript.
+#[wasm_bindgen]
+pub struct XmPlayer {
+    xm: XMContext,
+}
+
+/// The position of an `XmPlayer`, as returned by `XmPlayer.position()`.
+#[wasm_bindgen]
+#[derive(Copy, Clone)]
+pub struct XmPosition {
+    /// Pattern index in the POT (pattern order table)
+    #[wasm_bindgen(js_name = patternIndex)]
+    pub pattern_index: u8,
+    /// Pattern number
+    pub pattern: u8,
+    /// Row number
+    pub row: u8,
+    /// Total number of generated samples, as a number rather than a
+    /// `BigInt`
+    pub samples: f64,
+}
+
+#[wasm_bindgen]
+impl XmPlayer {
+    /// Loads a module, to play at `rate` Hz (use the `sampleRate` of the
+    /// audio context).
+    #[wasm_bindgen(constructor)]
+    pub fn new(data: &[u8], rate: u32) -> Result<XmPlayer, JsError> {
+        Ok(XmPlayer {
+            xm: XMContext::new(data, rate)?,
+        })
+    }
+
+    /// Plays the module into `output`, as interleaved stereo samples.
+    ///
+    /// Returns the number of samples written.
+    pub fn generate(&mut self, output: &mut [f32]) -> usize {
+        self.xm.generate_samples(output)
+    }
+
+    /// Plays the module into two buffers of the same length, one per
+    /// channel, as an `AudioWorkletProcessor` gets them.
+    ///
+    /// Returns the number of samples written to each buffer.
+    #[wasm_bindgen(js_name = generatePlanar)]
+    pub fn generate_planar(&mut self, left: &mut [f32], right: &mut [f32]) -> usize {
+        self.xm.generate_samples_planar(left, right)
+    }
+
+    /// Sets the maximum number of times the module can loop. After that,
+    /// it plays silence. 0 (the default) loops forever.
+    #[wasm_bindgen(js_name = setMaxLoopCount)]
+    pub fn set_max_loop_count(&mut self, count: u8) {
+        self.xm.set_max_loop_count(count);
+    }
+
+    /// The number of times the module has looped.
+    #[wasm_bindgen(getter, js_name = loopCount)]
+    pub fn loop_count(&self) -> u8 {
+        self.xm.loop_count()
+    }
+
+    /// The play rate in Hz.
+    #[wasm_bindgen(getter)]
+    pub fn rate(&self) -> u32 {
+        self.xm.rate()
+    }
+
+    /// Gets the current position.
+    pub fn position(&self) -> XmPosition {
+        let position = self.xm.position();
+        XmPosition {
+            pattern_index: position.pattern_index,
+            pattern: position.pattern,
+            row: position.row,
+            samples: position.samples as f64,
+        }
+    }
+
+    /// Jumps to a row of the pattern order table. Returns false if the
+    /// position is never reached when playing the module.
+    #[wasm_bindgen(js_name = seekToPosition)]
+    pub fn seek_to_position(&mut self, pattern_index: u8, row: u8) -> Result<bool, JsError> {
+        Ok(self.xm.seek_to_position(pattern_index, row)?)
+    }
+
+    /// Jumps to a time, in seconds from the start of the module.
+    #[wasm_bindgen(js_name = seekToTime)]
+    pub fn seek_to_time(&mut self, seconds: f64) -> Result<(), JsError> {
+        let samples = (seconds.max(0.0) * self.xm.rate() as f64) as u64;
+        Ok(self.xm.seek_to_sample(samples)?)
+    }
+
+    /// The number of channels of the module.
+    #[wasm_bindgen(getter, js_name = numberOfChannels)]
+    pub fn number_of_channels(&self) -> u16 {
+        self.xm.number_of_channels()
+    }
+
+    /// The number of instruments of the module.
+    #[wasm_bindgen(getter, js_name = numberOfInstruments)]
+    pub fn number_of_instruments(&self) -> u16 {
+        self.xm.number_of_instruments()
+    }
+
+    /// Mutes or unmutes a channel, from 1 to `numberOfChannels`. Returns
+    /// whether it was muted.
+    #[wasm_bindgen(js_name = muteChannel)]
+    pub fn mute_channel(&mut self, channel: u16, mute: bool) -> Result<bool, JsError> {
+        if channel == 0 || channel > self.xm.number_of_channels() {
+            return Err(JsError::new("no such channel"));
+        }
+        Ok(self.xm.mute_channel(channel, mute))
+    }
+
+    /// Mutes or unmutes an instrument, from 1 to `numberOfInstruments`.
+    /// Returns whether it was muted.
+    #[wasm_bindgen(js_name = muteInstrument)]
+    pub fn mute_instrument(&mut self, instrument: u16, mute: bool) -> Result<bool, JsError> {
+        if instrument == 0 || instrument > self.xm.number_of_instruments() {
+            return Err(JsError::new("no such instrument"));
+        }
+        Ok(self.xm.mute_instrument(instrument, mute))
+    }
+}
@@ -0,0 +1,120 @@
+#[cfg(feature = "pure-rust")]
+use crate::math::sqrtf;
+use crate::XMContext;
+#[cfg(feature = "pure-rust")]
+use crate::XMError;
+
+/// Number of stereo frames generated at a time.
+const CHUNK_FRAMES: usize = 256;
+
+impl XMContext {
+    /// Plays the module and puts the sound samples in separate left and right
+    /// output buffers (planar stereo).
+    ///
+    /// # Return
+    /// The number of samples written to each output buffer
+    pub fn generate_samples_planar(&mut self, left: &mut [f32], right: &mut [f32]) -> usize {
+        assert!(left.len() == right.len());
+
+        let mut buffer = [0.0f32; CHUNK_FRAMES * 2];
+        for (left, right) in left
+            .chunks_mut(CHUNK_FRAMES)
+            .zip(right.chunks_mut(CHUNK_FRAMES))
+        {
+            let frames = &mut buffer[..left.len() * 2];
+            self.generate_samples(frames);
+
+            for ((frame, l), r) in frames.chunks(2).zip(left.iter_mut()).zip(right.iter_mut()) {
+                *l = frame[0];
+                *r = frame[1];
+            }
+        }
+
+        left.len()
+    }
+
+    /// Plays the module and puts the sound samples, downmixed to mono, in the
+    /// specified output buffer.
+    ///
+    /// # Return
+    /// The number of samples written to the output buffer
+    pub fn generate_samples_mono(&mut self, output: &mut [f32]) -> usize {
+        let mut buffer = [0.0f32; CHUNK_FRAMES * 2];
+        for chunk in output.chunks_mut(CHUNK_FRAMES) {
+            let frames = &mut buffer[..chunk.len() * 2];
+            self.generate_samples(frames);
+
+            for (frame, out) in frames.chunks(2).zip(chunk.iter_mut()) {
+                *out = (frame[0] + frame[1]) * 0.5;
+            }
+        }
+
+        output.len()
+    }
+
+    /// Plays the module, spreading its channels across any number of
+    /// speakers. Each output buffer is a mono speaker feed; the speakers are
+    /// assumed to be laid out evenly from left (`outputs[0]`) to right (the
+    /// last output).
+    ///
+    /// Each channel is placed according to the panning the engine mixes it
+    /// with (after the mixer settings), and split between the two nearest
+    /// speakers with the same constant-power law libxm uses for stereo. With
+    /// two outputs, this is the same as `generate_samples_planar()` (up to
+    /// rounding), except while the volume of a channel ramps with the
+    /// `ramping` feature: stereo ramps each side on its own, which moves
+    /// the sound a little for a few samples. With one output, every channel
+    /// is played at full power, without the attenuation of a stereo
+    /// downmix.
+    ///
+    /// Only built with the pure-Rust engine (the `pure-rust` feature).
+    ///
+    /// # Return
+    /// The number of samples written to each output buffer
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` with `Backend::Libxm` (`libxm-reference`),
+    /// which can't render channels separately.
+    #[cfg(feature = "pure-rust")]
+    pub fn generate_samples_spread(
+        &mut self,
+        outputs: &mut [&mut [f32]],
+    ) -> Result<usize, XMError> {
+        assert!(!outputs.is_empty());
+
+        let length = outputs[0].len();
+        assert!(outputs.iter().all(|output| output.len() == length));
+
+        let mut buffer = [0.0f32; CHUNK_FRAMES * 2];
+        let last_speaker = (outputs.len() - 1) as f32;
+
+        let mut start = 0;
+        while start < length {
+            let frames = (length - start).min(CHUNK_FRAMES);
+            for output in outputs.iter_mut() {
+                output[start..start + frames].fill(0.0);
+            }
+
+            self.player.generate_samples_per_channel(
+                &mut buffer[..frames * 2],
+                &mut |i, _, l, r, panning| {
+                    // l = v * sqrt(1 - panning), r = v * sqrt(panning)
+                    let value = sqrtf(l * l + r * r).copysign(l + r);
+
+                    let position = panning * last_speaker;
+                    let speaker = (position as usize).min(outputs.len() - 1);
+                    let fraction = position - speaker as f32;
+
+                    outputs[speaker][start + i] += value * sqrtf(1.0 - fraction);
+                    if fraction > 0.0 {
+                        outputs[speaker + 1][start + i] += value * sqrtf(fraction);
+                    }
+                },
+            )?;
+
+            start += frames;
+        }
+
+        Ok(length)
+    }
+}
@@ -5,6 +5,8 @@
 //! Designed for easy integration in demos and such, and provides timing
 //! functions for easy sync against specific instruments, samples or channels.
 //!
+//! MOD, S3M and IT modules are played too, converted to XM (see `module`).
+//!
 //! # Example
 //! ```no_run
 //! use libxm::XMContext;
@@ -35,11 +37,43 @@
 //! }
 //! ```
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(all(
+    feature = "arena",
+    any(not(feature = "pure-rust"), feature = "libxm-reference")
+))]
+mod arena;
+mod backend;
+#[cfg(feature = "pure-rust")]
+mod engine;
+pub mod events;
 pub mod ffi;
-use std::mem;
+pub mod integrations;
+mod layout;
+mod math;
+mod mixer;
+pub mod module;
+pub mod pcm;
+#[cfg(feature = "std")]
+pub mod render;
+pub mod snapshot;
+#[cfg(feature = "pure-rust")]
+mod stems;
+#[cfg(feature = "std")]
+pub mod stream;
+pub mod timeline;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::{error, fmt};
 
-/// Possible errors from the `XMContext::new` method.
+/// Possible errors from `XMContext` methods.
 #[derive(Copy, Clone, Debug)]
+#[non_exhaustive]
 pub enum XMError {
     /// An unknown error reported by libxm.
     /// This enum exists in order to gracefully handle future errors from
@@ -49,6 +83,227 @@ pub enum XMError {
     ModuleDataNotSane,
     /// There was an issue allocating additional memory
     MemoryAllocationFailed,
+    /// The module data was rejected, for the given reason
+    InvalidModule(ModuleError),
+    /// The operation isn't supported by the backend, or by this build
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for XMError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            XMError::Unknown(code) => write!(f, "unknown libxm error (code {})", code),
+            XMError::ModuleDataNotSane => write!(f, "the module data is corrupted or invalid"),
+            XMError::MemoryAllocationFailed => write!(f, "memory allocation failed"),
+            XMError::InvalidModule(ref err) => write!(f, "invalid module: {}", err),
+            XMError::Unsupported(what) => write!(f, "unsupported: {}", what),
+        }
+    }
+}
+
+impl error::Error for XMError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            XMError::InvalidModule(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Why a module was rejected, and where in the data the problem was found.
+///
+/// Returned by `XMContext::new` and `Module::parse` inside
+/// `XMError::InvalidModule`, and by `module::validate`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ModuleError {
+    /// What is wrong with the module
+    pub kind: ModuleErrorKind,
+    /// Byte offset in the module data
+    pub offset: usize,
+}
+
+/// The possible reasons for a module to be rejected.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ModuleErrorKind {
+    /// The data doesn't start with the XM signature
+    BadMagic,
+    /// The data ends in the middle of a header
+    TruncatedHeader,
+    /// The XM version is not 1.04
+    UnsupportedVersion(u16),
+    /// The pattern order table references a pattern that doesn't exist
+    NonexistentPattern(u8),
+    /// The packed data of a pattern doesn't match its number of rows and
+    /// channels, or runs past the end of the data
+    PatternSizeMismatch {
+        /// The pattern number
+        pattern: u16,
+    },
+    /// The data of a sample runs past the end of the data
+    SampleLengthPastEof {
+        /// The instrument number, starting at 1
+        instrument: u16,
+        /// The sample number, starting at 0
+        sample: u16,
+    },
+    /// A MOD, S3M or IT module can't be converted to XM, for the given
+    /// reason
+    Unconvertible(&'static str),
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at byte offset {})", self.kind, self.offset)
+    }
+}
+
+impl error::Error for ModuleError {}
+
+impl fmt::Display for ModuleErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ModuleErrorKind::BadMagic => {
+                write!(f, "bad magic, this is not an XM, MOD, S3M or IT module")
+            }
+            ModuleErrorKind::TruncatedHeader => write!(f, "truncated header"),
+            ModuleErrorKind::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported XM version {}.{:02x}, only 1.04 is supported",
+                version >> 8,
+                version & 0xFF
+            ),
+            ModuleErrorKind::NonexistentPattern(pattern) => write!(
+                f,
+                "the pattern order table references nonexistent pattern {}",
+                pattern
+            ),
+            ModuleErrorKind::PatternSizeMismatch { pattern } => {
+                write!(f, "the data of pattern {} doesn't match its size", pattern)
+            }
+            ModuleErrorKind::SampleLengthPastEof { instrument, sample } => write!(
+                f,
+                "sample {} of instrument {} extends past the end of the data",
+                sample, instrument
+            ),
+            ModuleErrorKind::Unconvertible(reason) => {
+                write!(f, "the module can't be converted to XM: {}", reason)
+            }
+        }
+    }
+}
+
+/// The libxm build settings, as returned by `build_config()`.
+///
+/// Each setting corresponds to the Cargo feature of the same name.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BuildConfig {
+    /// Module data and function arguments are checked for errors
+    pub defensive: bool,
+    /// Module, instrument and sample names are kept in memory
+    pub strings: bool,
+    /// Samples are delta-coded in the libxm format
+    pub libxmize_delta_samples: bool,
+    /// Samples are resampled with linear interpolation, rather than
+    /// nearest-neighbour
+    pub linear_interpolation: bool,
+    /// Volume changes are ramped to avoid clicks
+    pub ramping: bool,
+    /// libxm prints debug messages to stderr
+    pub debug: bool,
+    /// libxm is built for a big-endian target
+    pub big_endian: bool,
+}
+
+/// Gets the settings libxm was compiled with.
+///
+/// # Note
+/// If the build of `xm` is overridden to link a prebuilt libxm, this
+/// reflects the Cargo features, not the settings of the linked library.
+pub fn build_config() -> BuildConfig {
+    BuildConfig {
+        defensive: cfg!(feature = "defensive"),
+        strings: cfg!(feature = "strings"),
+        libxmize_delta_samples: cfg!(feature = "libxmize-delta-samples"),
+        linear_interpolation: cfg!(feature = "linear-interpolation"),
+        ramping: cfg!(feature = "ramping"),
+        debug: cfg!(feature = "debug"),
+        big_endian: cfg!(feature = "big-endian"),
+    }
+}
+
+/// How samples are resampled to the play rate.
+///
+/// The pure-Rust engine can switch between every mode at runtime
+/// (`XMContext::set_interpolation()`). libxm chooses its interpolation when
+/// it is compiled, and can only play with `Interpolation::compiled()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Nearest-neighbour, the crunchy sound of old trackers
+    Nearest,
+    /// Linear interpolation between two points
+    Linear,
+    /// Cubic (Catmull-Rom) interpolation over four points
+    Cubic,
+    /// Windowed sinc interpolation, with the given number of taps
+    Sinc(u8),
+}
+
+impl Interpolation {
+    /// Gets the interpolation libxm was compiled with, as selected by the
+    /// `linear-interpolation` feature.
+    pub fn compiled() -> Interpolation {
+        if cfg!(feature = "linear-interpolation") {
+            Interpolation::Linear
+        } else {
+            Interpolation::Nearest
+        }
+    }
+}
+
+/// The players a module can be played with.
+///
+/// The pure-Rust engine is built by default, with the `pure-rust` feature.
+/// Without it, libxm is built instead; with the `libxm-reference` feature,
+/// both are.
+///
+/// The engine is a port of libxm; `tests/compare.rs` checks that both play
+/// the same output, within a small tolerance. Rendering each channel
+/// separately (`XMContext::generate_samples_per_channel()`,
+/// `generate_samples_spread()`) and switching interpolations
+/// (`XMContext::set_interpolation()`) are only built with the engine. Only
+/// the engine has the continuous controls too: changing the speed, pitch
+/// and transposition, and free channels (`XMContext::reserve_channels()`).
+/// With libxm, these return `XMError::Unsupported`, and so do the mixer
+/// settings of the channels (`XMContext::set_channel_volume()`…) without
+/// the `ramping` feature.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Backend {
+    /// libxm, the C library
+    Libxm,
+    /// The pure-Rust engine
+    PureRust,
+}
+
+impl Backend {
+    /// Whether the backend is built, as selected by the `pure-rust` and
+    /// `libxm-reference` features.
+    pub fn is_available(self) -> bool {
+        match self {
+            Backend::Libxm => !cfg!(feature = "pure-rust") || cfg!(feature = "libxm-reference"),
+            Backend::PureRust => cfg!(feature = "pure-rust"),
+        }
+    }
+}
+
+/// The pure-Rust engine if it is built, libxm otherwise.
+impl Default for Backend {
+    fn default() -> Backend {
+        if cfg!(feature = "pure-rust") {
+            Backend::PureRust
+        } else {
+            Backend::Libxm
+        }
+    }
 }
 
 /// The return values from `XMContext::get_playing_speed()`.
@@ -73,49 +328,399 @@ pub struct Position {
     pub samples: u64,
 }
 
+/// The return values from `XMContext::channel_state()`.
+///
+/// When a channel is not active, libxm leaves the frequency, volume and
+/// panning undefined. In that case they are reported as `0.0`, `0.0` and
+/// `0.5` (center).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ChannelState {
+    /// Whether the channel is playing something
+    pub active: bool,
+    /// Instrument number currently playing in the channel, or 0 if the
+    /// channel is not active
+    pub instrument: u16,
+    /// Frequency of the sample currently playing, in Hz
+    pub frequency: f32,
+    /// Volume of the sample currently playing, between 0 and 1.
+    /// This takes envelopes, fadeout, tremolo, etc. into account.
+    pub volume: f32,
+    /// Panning of the sample currently playing, between 0 (left) and 1 (right)
+    pub panning: f32,
+}
+
+/// Which channels and instruments are muted, as returned by
+/// `XMContext::mute_mask()`.
+///
+/// A mask can be restored with `XMContext::set_mute_mask()`, on the context
+/// it was taken from or on any other.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MuteMask {
+    channels: Vec<bool>,
+    instruments: Vec<bool>,
+}
+
+impl MuteMask {
+    /// Gets whether a channel is muted. Channel numbers start at 1;
+    /// channels the mask doesn't know of aren't muted.
+    #[inline]
+    pub fn is_channel_muted(&self, channel: u16) -> bool {
+        channel >= 1 && self.channels.get(channel as usize - 1) == Some(&true)
+    }
+
+    /// Gets whether an instrument is muted. Instrument numbers start at 1;
+    /// instruments the mask doesn't know of aren't muted.
+    #[inline]
+    pub fn is_instrument_muted(&self, instrument: u16) -> bool {
+        instrument >= 1 && self.instruments.get(instrument as usize - 1) == Some(&true)
+    }
+}
+
+/// The loop type of a sample.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LoopType {
+    /// The sample is played once
+    NoLoop,
+    /// The loop is played from start to end, over and over
+    Forward,
+    /// The loop is played forwards, then backwards, over and over
+    PingPong,
+}
+
+/// The waveform of a sample, in the bit depth it was stored in the module.
+#[derive(Copy, Clone, Debug)]
+pub enum SampleData<'a> {
+    /// 8-bit signed PCM
+    Bits8(&'a [i8]),
+    /// 16-bit signed PCM
+    Bits16(&'a [i16]),
+}
+
+impl<'a> SampleData<'a> {
+    /// Gets the length of the waveform (in samples).
+    #[inline]
+    pub fn len(&self) -> usize {
+        match *self {
+            SampleData::Bits8(data) => data.len(),
+            SampleData::Bits16(data) => data.len(),
+        }
+    }
+
+    /// Returns true if the waveform has no samples.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets the bit depth of the waveform (8 or 16).
+    #[inline]
+    pub fn bits(&self) -> u8 {
+        match *self {
+            SampleData::Bits8(_) => 8,
+            SampleData::Bits16(_) => 16,
+        }
+    }
+
+    /// Gets a single sample point, scaled to the `-1.0..1.0` range.
+    #[inline]
+    pub fn get_f32(&self, index: usize) -> Option<f32> {
+        match *self {
+            SampleData::Bits8(data) => data.get(index).map(|&v| v as f32 / 128.0),
+            SampleData::Bits16(data) => data.get(index).map(|&v| v as f32 / 32768.0),
+        }
+    }
+}
+
+/// The return values from `XMContext::sample_waveform()`.
+#[derive(Copy, Clone, Debug)]
+pub struct SampleWaveform<'a> {
+    /// The sample points
+    pub data: SampleData<'a>,
+    /// Start of the loop (in samples)
+    pub loop_start: u32,
+    /// Length of the loop (in samples)
+    pub loop_length: u32,
+    /// Loop type
+    pub loop_type: LoopType,
+}
+
+/// A call that changed the playback state, other than generating samples.
+#[derive(Copy, Clone, PartialEq)]
+enum Command {
+    Seek {
+        pot: u8,
+        row: u8,
+        tick: u16,
+    },
+    SetMaxLoopCount(u8),
+    NoteOn {
+        channel: u16,
+        instrument: u16,
+        note: u8,
+        volume: u8,
+    },
+    NoteOff(u16),
+    SetBpm(u16),
+    SetTempo(u16),
+    SetSpeedMultiplier(f32),
+    SetPitchMultiplier(f32),
+    SetTranspose(i8),
+}
+
+impl Command {
+    /// Applies the command to a player.
+    fn apply(self, player: &mut dyn backend::Player) -> Result<(), XMError> {
+        match self {
+            Command::Seek { pot, row, tick } => player.seek(pot, row, tick),
+            Command::SetMaxLoopCount(loopcnt) => player.set_max_loop_count(loopcnt),
+            Command::NoteOn {
+                channel,
+                instrument,
+                note,
+                volume,
+            } => player.note_on(channel, instrument, note, volume)?,
+            Command::NoteOff(channel) => player.note_off(channel),
+            Command::SetBpm(bpm) => player.set_bpm(bpm),
+            Command::SetTempo(tempo) => player.set_tempo(tempo),
+            Command::SetSpeedMultiplier(multiplier) => player.set_speed_multiplier(multiplier)?,
+            Command::SetPitchMultiplier(multiplier) => player.set_pitch_multiplier(multiplier)?,
+            Command::SetTranspose(semitones) => player.set_transpose(semitones)?,
+        }
+        Ok(())
+    }
+}
+
+/// The settings `seek_to_sample()` plays the module again with.
+#[derive(Copy, Clone, PartialEq)]
+struct Settings {
+    max_loop_count: u8,
+    speed_multiplier: f32,
+    pitch_multiplier: f32,
+    transpose: i8,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            max_loop_count: 0,
+            speed_multiplier: 1.0,
+            pitch_multiplier: 1.0,
+            transpose: 0,
+        }
+    }
+}
+
+impl Settings {
+    /// Gets the commands setting what isn't the default.
+    fn commands(self) -> impl Iterator<Item = Command> {
+        let default = Settings::default();
+        [
+            (self.max_loop_count != default.max_loop_count)
+                .then_some(Command::SetMaxLoopCount(self.max_loop_count)),
+            (self.speed_multiplier != default.speed_multiplier)
+                .then_some(Command::SetSpeedMultiplier(self.speed_multiplier)),
+            (self.pitch_multiplier != default.pitch_multiplier)
+                .then_some(Command::SetPitchMultiplier(self.pitch_multiplier)),
+            (self.transpose != default.transpose).then_some(Command::SetTranspose(self.transpose)),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// The most commands kept in the history of an `XMContext`.
+const MAX_HISTORY: usize = 1 << 16;
+
 /// The XM context.
 pub struct XMContext {
-    raw: *mut ffi::xm_context_t,
+    player: Box<dyn backend::Player>,
+    rate: u32,
+    mod_data: Arc<[u8]>,
+    // The module without its sample data, for what libxm doesn't expose
+    // (sample loops, pattern data)
+    headers: Arc<module::Module>,
+    muted_channels: Vec<bool>,
+    muted_instruments: Vec<bool>,
+    settings: Settings,
+    // For players that can't copy themselves, the commands since the module
+    // last played from the start, along with the number of generated
+    // samples at the time each was issued. Replaying these on a new player
+    // brings it to the same state as this one. None for the other players,
+    // and once there were too many to keep.
+    history: Option<Vec<(u64, Command)>>,
+    // Whether the playback so far is that of the module played from the
+    // start with the current settings, which `seek_to_sample()` can go on
+    // with
+    playthrough: bool,
+    event_state: Option<events::EventState>,
+    dither: pcm::DitherState,
+    interpolation: Interpolation,
+    // The format of the module. `mod_data` is always XM.
+    format: module::Format,
+    // Channels added by `reserve_channels()`, after those of the module
+    free_channels: u16,
+    // Volumes and pannings applied by the engine
+    mixer: mixer::Mixer,
 }
 
-unsafe impl Send for XMContext {}
-unsafe impl Sync for XMContext {}
-
 impl XMContext {
-    /// Creates an XM context.
+    /// Creates an XM context, playing with the default backend (see
+    /// `Backend`).
+    ///
+    /// XM, MOD, S3M and IT modules are accepted. Other formats than XM are
+    /// converted to XM first; see `module` for what that entails.
     ///
     /// # Parameters
     /// * `mod_data` - The contents of the module.
     /// * `rate` - The play rate in Hz. Recommended value is 48000.
+    ///
+    /// # Errors
+    /// `XMError::InvalidModule` if libxm's sanity checks reject the module,
+    /// with the reason. Modules that are cut short or whose sizes don't
+    /// match still load, as they do in libxm: check them with
+    /// `module::validate` first to reject them.
     pub fn new(mod_data: &[u8], rate: u32) -> Result<XMContext, XMError> {
-        unsafe {
-            let mut raw: *mut ffi::xm_context = std::ptr::null_mut();
-
-            let mod_data_ptr = mem::transmute(mod_data.as_ptr());
-            let mod_data_len = mod_data.len() as ffi::size_t;
-
-            let result = ffi::xm_create_context_safe(&mut raw, mod_data_ptr, mod_data_len, rate);
-            match result {
-                0 => Ok(XMContext { raw: raw }),
-                1 => Err(XMError::ModuleDataNotSane),
-                2 => Err(XMError::MemoryAllocationFailed),
-                _ => Err(XMError::Unknown(result)),
-            }
-        }
+        XMContext::create(
+            mod_data,
+            rate,
+            Backend::default(),
+            #[cfg(feature = "arena")]
+            None,
+        )
+    }
+
+    /// Creates an XM context, playing with the given backend.
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` if the backend isn't built (see
+    /// `Backend::is_available()`).
+    pub fn with_backend(
+        mod_data: &[u8],
+        rate: u32,
+        backend: Backend,
+    ) -> Result<XMContext, XMError> {
+        XMContext::create(
+            mod_data,
+            rate,
+            backend,
+            #[cfg(feature = "arena")]
+            None,
+        )
+    }
+
+    /// Creates an XM context playing with libxm, with the memory of libxm in
+    /// `arena` rather than on the heap. Needs the `arena` feature.
+    ///
+    /// libxm makes a single allocation per context, of a size depending on
+    /// the module; `memory_needed()` tells how much. The context keeps the
+    /// arena for its whole life, including when it is restarted by
+    /// `seek_to_sample()`. Everything else (the copy of the module data, its
+    /// headers and the history of commands) uses the global allocator.
+    ///
+    /// # Errors
+    /// `XMError::MemoryAllocationFailed` if the arena is too small.
+    /// `XMError::Unsupported` if libxm isn't built.
+    #[cfg(feature = "arena")]
+    pub fn new_in(
+        mod_data: &[u8],
+        rate: u32,
+        arena: &'static mut [u8],
+    ) -> Result<XMContext, XMError> {
+        XMContext::create(mod_data, rate, Backend::Libxm, Some(arena))
+    }
+
+    /// Gets the size of the arena `new_in()` needs to play a module.
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` if libxm isn't built.
+    #[cfg(feature = "arena")]
+    pub fn memory_needed(mod_data: &[u8], rate: u32) -> Result<usize, XMError> {
+        backend::memory_needed(&module::to_xm(mod_data)?, rate)
+    }
+
+    fn create(
+        mod_data: &[u8],
+        rate: u32,
+        backend: Backend,
+        #[cfg(feature = "arena")] arena: Option<&'static mut [u8]>,
+    ) -> Result<XMContext, XMError> {
+        let format = module::Format::detect(mod_data).unwrap_or(module::Format::Xm);
+        let mod_data: Arc<[u8]> = Arc::from(&*module::to_xm(mod_data)?);
+        // Parsing runs the checks of libxm, and tells why it rejects a module
+        let headers = Arc::new(module::parse_headers(&mod_data)?);
+        let player = backend::create(
+            backend,
+            &mod_data,
+            rate,
+            #[cfg(feature = "arena")]
+            arena,
+        )?;
+
+        let mut xm = XMContext {
+            player,
+            rate,
+            headers,
+            mod_data,
+            muted_channels: Vec::new(),
+            muted_instruments: Vec::new(),
+            settings: Settings::default(),
+            history: None,
+            playthrough: true,
+            event_state: None,
+            dither: pcm::DitherState::new(),
+            interpolation: Interpolation::compiled(),
+            format,
+            free_channels: 0,
+            mixer: mixer::Mixer::default(),
+        };
+        xm.muted_channels = vec![false; xm.number_of_channels() as usize];
+        xm.muted_instruments = vec![false; xm.number_of_instruments() as usize];
+        xm.start_history();
+
+        Ok(xm)
+    }
+
+    /// Gets the backend playing the module.
+    #[inline]
+    pub fn backend(&self) -> Backend {
+        self.player.backend()
+    }
+
+    /// Gets the play rate in Hz, as given to `XMContext::new()`.
+    #[inline]
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Gets the format of the module, as given to `XMContext::new()`.
+    #[inline]
+    pub fn format(&self) -> module::Format {
+        self.format
     }
 
     /// Plays the module and puts the sound samples in the specified output buffer.
     /// The output is in stereo.
     #[inline]
     pub fn generate_samples(&mut self, output: &mut [f32]) -> usize {
-        unsafe {
-            // Output buffer must have a multiple-of-two length.
-            assert!(output.len() % 2 == 0);
+        // Output buffer must have a multiple-of-two length.
+        assert!(output.len().is_multiple_of(2));
 
-            let output_len = (output.len() / 2) as ffi::size_t;
-            let samples = ffi::xm_generate_samples(self.raw, output.as_mut_ptr(), output_len);
+        self.player.generate_samples(output);
+        output.len()
+    }
 
-            (samples * 2) as usize
+    /// Plays the module like `generate_samples()`, into a buffer of any
+    /// sample type: `f32`, `i16`, `i32` or `u8` (see `pcm::Sample`).
+    /// Integer samples are rounded without dithering; see
+    /// `generate_samples_i16()` and `generate_samples_i32()` for dithering.
+    ///
+    /// # Return
+    /// The number of samples written to the output buffer
+    #[inline]
+    pub fn generate_samples_as<T: pcm::Sample>(&mut self, output: &mut [T]) -> usize {
+        match T::as_f32_slice(output) {
+            Some(output) => self.generate_samples(output),
+            None => self.generate_samples_dithered(output, pcm::Dither::None),
         }
     }
 
@@ -125,9 +730,24 @@ impl XMContext {
     /// generate silence.
     #[inline]
     pub fn set_max_loop_count(&mut self, loopcnt: u8) {
-        unsafe {
-            ffi::xm_set_max_loop_count(self.raw, loopcnt);
-        }
+        self.player.set_max_loop_count(loopcnt);
+        self.record(Command::SetMaxLoopCount(loopcnt));
+        self.settings.max_loop_count = loopcnt;
+        self.settings_changed();
+    }
+
+    /// Gets the maximum number of times the module can loop, as set by
+    /// `set_max_loop_count()`. 0 means the module loops forever.
+    pub fn max_loop_count(&self) -> u8 {
+        self.settings.max_loop_count
+    }
+
+    /// Whether the module has looped as many times as set by
+    /// `set_max_loop_count()`, and only generates silence from now on.
+    #[cfg(feature = "std")]
+    pub(crate) fn finished(&self) -> bool {
+        let max_loop_count = self.max_loop_count();
+        max_loop_count > 0 && self.loop_count() >= max_loop_count
     }
 
     /// Gets the loop count of the currently playing module.
@@ -136,57 +756,45 @@ impl XMContext {
     /// looped once, etc.
     #[inline]
     pub fn loop_count(&self) -> u8 {
-        unsafe { ffi::xm_get_loop_count(self.raw) }
+        self.player.loop_count()
     }
 
     /// Gets the module name as a byte slice. The string encoding is unknown.
     ///
-    /// Returns None if the XM_STRINGS build setting is false.
+    /// Returns None if the crate was compiled without the `strings` feature,
+    /// which can be told apart from an empty name with `build_config()`.
     #[inline]
     pub fn module_name(&self) -> Option<&[u8]> {
         // Is name always UTF-8? Another encoding?
-        unsafe {
-            let name = ffi::xm_get_module_name(self.raw);
-            if name.is_null() {
-                None
-            } else {
-                Some(std::ffi::CStr::from_ptr(name).to_bytes())
-            }
-        }
+        self.player.module_name()
     }
 
     /// Gets the tracker name as a byte slice. The string encoding is unknown.
     ///
-    /// Returns None if the XM_STRINGS build setting is false.
+    /// Returns None if the crate was compiled without the `strings` feature,
+    /// which can be told apart from an empty name with `build_config()`.
     #[inline]
     pub fn tracker_name(&self) -> Option<&[u8]> {
         // Is name always UTF-8? Another encoding?
-        unsafe {
-            let name = ffi::xm_get_tracker_name(self.raw);
-            if name.is_null() {
-                None
-            } else {
-                Some(std::ffi::CStr::from_ptr(name).to_bytes())
-            }
-        }
+        self.player.tracker_name()
     }
 
     /// Gets the number of channels.
     #[inline]
     pub fn number_of_channels(&self) -> u16 {
-        unsafe { ffi::xm_get_number_of_channels(self.raw) }
+        self.player.number_of_channels()
     }
 
     /// Gets the module length (in patterns).
     #[inline]
     pub fn module_length(&self) -> u16 {
-        unsafe { ffi::xm_get_module_length(self.raw) }
+        self.player.module_length()
     }
 
     /// Gets the number of patterns.
     #[inline]
     pub fn number_of_patterns(&self) -> u16 {
-        unsafe { ffi::xm_get_number_of_patterns(self.raw) }
+        self.player.number_of_patterns()
     }
 
     /// Gets the number of rows in a pattern.
@@ -197,13 +805,13 @@ impl XMContext {
     pub fn number_of_rows(&self, pattern: u16) -> u16 {
         assert!(pattern < self.number_of_patterns());
 
-        unsafe { ffi::xm_get_number_of_rows(self.raw, pattern) }
+        self.player.number_of_rows(pattern)
     }
 
     /// Gets the number of instruments.
     #[inline]
     pub fn number_of_instruments(&self) -> u16 {
-        unsafe { ffi::xm_get_number_of_instruments(self.raw) }
+        self.player.number_of_instruments()
     }
 
     /// Gets the number of samples of an instrument.
@@ -215,42 +823,167 @@ impl XMContext {
         assert!(instrument >= 1);
         assert!(instrument <= self.number_of_instruments());
 
-        unsafe { ffi::xm_get_number_of_samples(self.raw, instrument) }
+        self.player.number_of_samples(instrument)
+    }
+
+    /// Gets the waveform of a sample, along with its loop points.
+    ///
+    /// # Note
+    /// Instrument numbers go from `1` to `get_number_of_instruments()`
+    ///
+    /// Sample numbers go from `0` to `get_number_of_samples(instrument) - 1`
+    pub fn sample_waveform(&self, instrument: u16, sample: u16) -> SampleWaveform<'_> {
+        assert!(instrument >= 1);
+        assert!(instrument <= self.number_of_instruments());
+        assert!(sample < self.number_of_samples(instrument));
+
+        let data = self.player.sample_data(instrument, sample);
+        let header = &self.headers.instruments[instrument as usize - 1].samples[sample as usize];
+
+        if header.loop_length == 0 {
+            SampleWaveform {
+                data,
+                loop_start: header.loop_start,
+                loop_length: 0,
+                loop_type: LoopType::NoLoop,
+            }
+        } else {
+            SampleWaveform {
+                data,
+                loop_start: header.loop_start,
+                loop_length: header.loop_length,
+                loop_type: header.loop_type,
+            }
+        }
+    }
+
+    /// Sets how samples are resampled to the play rate, for this context
+    /// only. Only built with the pure-Rust engine (the `pure-rust`
+    /// feature).
+    ///
+    /// # Note
+    /// The pure-Rust engine supports every mode. Sinc interpolation needs
+    /// at least 2 taps.
+    ///
+    /// With `Backend::Libxm` (`libxm-reference`), only the interpolation
+    /// libxm was compiled with is supported (see
+    /// `Interpolation::compiled()`). Other modes return
+    /// `XMError::Unsupported` and leave the context unchanged.
+    #[cfg(feature = "pure-rust")]
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) -> Result<(), XMError> {
+        self.player.set_interpolation(interpolation)?;
+
+        self.interpolation = interpolation;
+        self.settings_changed();
+        Ok(())
+    }
+
+    /// Gets how samples are resampled to the play rate.
+    #[inline]
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
     }
 
     /// Gets the current module speed.
     #[inline]
     pub fn playing_speed(&self) -> PlayingSpeed {
-        let (mut bpm, mut tempo) = (0, 0);
-        unsafe { ffi::xm_get_playing_speed(self.raw, &mut bpm, &mut tempo) };
+        self.player.playing_speed()
+    }
 
-        PlayingSpeed {
-            bpm: bpm,
-            tempo: tempo,
-        }
+    /// Sets the BPM, until the module changes it.
+    ///
+    /// Like an Fxx effect, this lasts until the next Fxx effect that sets
+    /// the BPM. `playing_speed()` reports the new value.
+    pub fn set_bpm(&mut self, bpm: u16) {
+        assert!(bpm >= 1);
+
+        self.player.set_bpm(bpm);
+        self.record(Command::SetBpm(bpm));
+        self.playthrough = false;
+    }
+
+    /// Sets the tempo (ticks per row), until the module changes it.
+    ///
+    /// Like an Fxx effect, this lasts until the next Fxx effect that sets
+    /// the tempo. `playing_speed()` reports the new value.
+    pub fn set_tempo(&mut self, tempo: u16) {
+        assert!(tempo >= 1);
+
+        self.player.set_tempo(tempo);
+        self.record(Command::SetTempo(tempo));
+        self.playthrough = false;
+    }
+
+    /// Plays faster or slower, without changing the pitch.
+    ///
+    /// The length of every tick is divided by `multiplier`: 2.0 plays twice
+    /// as fast, 0.5 half as fast. Unlike `set_bpm()`, this lasts whatever
+    /// the module does. `playing_speed()` still reports the BPM of the
+    /// module.
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` with libxm.
+    pub fn set_speed_multiplier(&mut self, multiplier: f32) -> Result<(), XMError> {
+        assert!(multiplier.is_finite() && multiplier > 0.0);
+
+        self.player.set_speed_multiplier(multiplier)?;
+        self.record(Command::SetSpeedMultiplier(multiplier));
+        self.settings.speed_multiplier = multiplier;
+        self.settings_changed();
+        Ok(())
+    }
+
+    /// Gets the speed multiplier set by `set_speed_multiplier()`.
+    pub fn speed_multiplier(&self) -> f32 {
+        self.settings.speed_multiplier
+    }
+
+    /// Multiplies the frequency of every note, without changing the speed.
+    ///
+    /// Playing notes change pitch right away. 2.0 plays an octave higher.
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` with libxm.
+    pub fn set_pitch_multiplier(&mut self, multiplier: f32) -> Result<(), XMError> {
+        assert!(multiplier.is_finite() && multiplier > 0.0);
+
+        self.player.set_pitch_multiplier(multiplier)?;
+        self.record(Command::SetPitchMultiplier(multiplier));
+        self.settings.pitch_multiplier = multiplier;
+        self.settings_changed();
+        Ok(())
+    }
+
+    /// Gets the pitch multiplier set by `set_pitch_multiplier()`.
+    pub fn pitch_multiplier(&self) -> f32 {
+        self.settings.pitch_multiplier
+    }
+
+    /// Shifts every note by a number of semitones, on top of the pitch
+    /// multiplier.
+    ///
+    /// Playing notes change pitch right away. Instruments keep playing the
+    /// samples of the original notes.
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` with libxm.
+    pub fn set_transpose(&mut self, semitones: i8) -> Result<(), XMError> {
+        self.player.set_transpose(semitones)?;
+        self.record(Command::SetTranspose(semitones));
+        self.settings.transpose = semitones;
+        self.settings_changed();
+        Ok(())
+    }
+
+    /// Gets the transposition set by `set_transpose()`, in semitones.
+    pub fn transpose(&self) -> i8 {
+        self.settings.transpose
     }
 
     /// Gets the current position in the module being played.
     #[inline]
     pub fn position(&self) -> Position {
-        let (mut pattern_index, mut pattern, mut row) = (0, 0, 0);
-        let mut samples = 0;
-        unsafe {
-            ffi::xm_get_position(
-                self.raw,
-                &mut pattern_index,
-                &mut pattern,
-                &mut row,
-                &mut samples,
-            )
-        };
-
-        Position {
-            pattern_index: pattern_index,
-            pattern: pattern,
-            row: row,
-            samples: samples,
-        }
+        self.player.position()
     }
 
     /// Gets the latest time (in number of generated samples) when a
@@ -263,7 +996,7 @@ impl XMContext {
         assert!(instrument >= 1);
         assert!(instrument <= self.number_of_instruments());
 
-        unsafe { ffi::xm_get_latest_trigger_of_instrument(self.raw, instrument) }
+        self.player.latest_trigger_of_instrument(instrument)
     }
 
     /// Get the latest time (in number of generated samples) when a
@@ -279,45 +1012,123 @@ impl XMContext {
         assert!(instrument <= self.number_of_instruments());
         assert!(sample < self.number_of_samples(instrument));
 
-        unsafe { ffi::xm_get_latest_trigger_of_sample(self.raw, instrument, sample) }
+        self.player.latest_trigger_of_sample(instrument, sample)
     }
 
     /// Get the latest time (in number of generated samples) when any
     /// instrument was triggered in a given channel.
     ///
     /// # Note
-    /// Channel numbers go from `1` to `get_number_of_channels()`
+    /// Channel numbers go from `1` to `get_number_of_channels()`, followed
+    /// by the free channels added by `reserve_channels()`
     #[inline]
     pub fn latest_trigger_of_channel(&self, channel: u16) -> u64 {
         assert!(channel >= 1);
-        assert!(channel <= self.number_of_channels());
+        assert!(channel <= self.number_of_channels() + self.free_channels);
+
+        self.player.latest_trigger_of_channel(channel)
+    }
+
+    /// Gets a snapshot of what is currently playing in a given channel.
+    ///
+    /// # Note
+    /// Channel numbers go from `1` to `get_number_of_channels()`, followed
+    /// by the free channels added by `reserve_channels()`
+    pub fn channel_state(&self, channel: u16) -> ChannelState {
+        assert!(channel >= 1);
+        assert!(channel <= self.number_of_channels() + self.free_channels);
+
+        self.player.channel_state(channel)
+    }
 
-        unsafe { ffi::xm_get_latest_trigger_of_channel(self.raw, channel) }
+    /// Gets a snapshot of every channel, in channel order, free channels
+    /// included.
+    ///
+    /// The state of channel `n` is at index `n - 1`.
+    pub fn channel_states(&self) -> Vec<ChannelState> {
+        (1..=self.number_of_channels() + self.free_channels)
+            .map(|channel| self.channel_state(channel))
+            .collect()
     }
 
     /// Seek to a specific position in a module
     ///
     /// WARNING: WITH BIG LETTERS: seeking modules is broken by design,
     /// don't expect miracles
+    ///
+    /// This jumps without updating the tempo, effect memory or notes of the
+    /// rows in between. Use `seek_to_sample()` or `seek_to_position()` for
+    /// accurate seeking.
     #[inline]
     pub fn seek(&mut self, pot: u8, row: u8, tick: u16) {
-        unsafe {
-            ffi::xm_seek(self.raw, pot, row, tick);
+        self.player.seek(pot, row, tick);
+        self.record(Command::Seek { pot, row, tick });
+        self.playthrough = false;
+    }
+
+    /// Seeks to the point where `samples` samples have been generated.
+    ///
+    /// Playback continues exactly as if the module had been played from
+    /// the start up to that point, with the current settings (maximum loop
+    /// count, speed, pitch, interpolation, mixer): the output is the same,
+    /// bit for bit, as that of a context that only generated samples.
+    /// Tempo, effect memory, envelopes and playing notes are all restored.
+    /// What changed the playback on the way is forgotten: `seek()`,
+    /// `set_bpm()`, `set_tempo()`, `note_on()` and `note_off()`.
+    ///
+    /// With libxm, the random vibrato and tremolo waveforms are the
+    /// exception: libxm keeps a single random generator for every context.
+    ///
+    /// # Note
+    /// Players can't skip ahead without running the playback, so the module
+    /// is played (with every channel muted) up to the target. Seeking
+    /// forward continues from the current position, unless something
+    /// changed the playback so far; otherwise, and when seeking backward,
+    /// the module is played again from the start. Either way, this takes
+    /// about as long as generating the skipped samples.
+    ///
+    /// # Errors
+    /// `XMError::MemoryAllocationFailed` if playing again from the start
+    /// needs memory that can't be allocated. The context then keeps playing
+    /// from where it was, except libxm in an arena, which frees its context
+    /// first and then has none to play.
+    pub fn seek_to_sample(&mut self, samples: u64) -> Result<(), XMError> {
+        if self.playthrough && samples >= self.position().samples {
+            self.fast_forward(samples)
+        } else {
+            self.play_again(samples)
+        }
+    }
+
+    /// Seeks to the first time a row is played, like `seek_to_sample()`.
+    ///
+    /// # Return
+    /// Whether the row is played before the module loops. If it isn't, the
+    /// playback state is left unchanged.
+    pub fn seek_to_position(&mut self, pattern_index: u8, row: u8) -> Result<bool, XMError> {
+        match self
+            .analyze_timeline()
+            .samples_of_position(pattern_index, row)
+        {
+            Some(samples) => self.seek_to_sample(samples).map(|_| true),
+            None => Ok(false),
         }
     }
 
     /// Mute or unmute a channel
     ///
     /// # Note
-    /// Channel numbers go from `1` to `get_number_of_channels()`
+    /// Channel numbers go from `1` to `get_number_of_channels()`, followed
+    /// by the free channels added by `reserve_channels()`
     ///
     /// # Return
     /// Whether the channel was muted
     pub fn mute_channel(&mut self, channel: u16, mute: bool) -> bool {
         assert!(channel >= 1);
-        assert!(channel <= self.number_of_channels());
+        assert!(channel <= self.number_of_channels() + self.free_channels);
 
-        unsafe { ffi::xm_mute_channel(self.raw, channel, mute) }
+        self.muted_channels[channel as usize - 1] = mute;
+        self.player.mute_channel(channel, mute)
     }
 
     /// Mute or unmute a instrument
@@ -331,14 +1142,306 @@ impl XMContext {
         assert!(instrument >= 1);
         assert!(instrument <= self.number_of_instruments());
 
-        unsafe { ffi::xm_mute_instrument(self.raw, instrument, mute) }
+        self.muted_instruments[instrument as usize - 1] = mute;
+        self.player.mute_instrument(instrument, mute)
+    }
+
+    /// Gets whether a channel is muted.
+    ///
+    /// # Note
+    /// Channel numbers go from `1` to `get_number_of_channels()`, followed
+    /// by the free channels added by `reserve_channels()`
+    #[inline]
+    pub fn is_channel_muted(&self, channel: u16) -> bool {
+        assert!(channel >= 1);
+        assert!(channel <= self.number_of_channels() + self.free_channels);
+
+        self.muted_channels[channel as usize - 1]
+    }
+
+    /// Gets whether an instrument is muted.
+    ///
+    /// # Note
+    /// Instrument numbers go from `1` to `get_number_of_instruments()`
+    #[inline]
+    pub fn is_instrument_muted(&self, instrument: u16) -> bool {
+        assert!(instrument >= 1);
+        assert!(instrument <= self.number_of_instruments());
+
+        self.muted_instruments[instrument as usize - 1]
+    }
+
+    /// Unmutes a channel and mutes all the others.
+    ///
+    /// This forgets which channels were muted before; save them with
+    /// `mute_mask()` to bring them back after `unsolo_all()`.
+    ///
+    /// # Note
+    /// Channel numbers go from `1` to `get_number_of_channels()`, followed
+    /// by the free channels added by `reserve_channels()`
+    pub fn solo_channel(&mut self, channel: u16) {
+        assert!(channel >= 1);
+        assert!(channel <= self.number_of_channels() + self.free_channels);
+
+        for other in 1..=self.number_of_channels() + self.free_channels {
+            self.mute_channel(other, other != channel);
+        }
+    }
+
+    /// Unmutes every channel. Instruments stay muted.
+    pub fn unsolo_all(&mut self) {
+        for channel in 1..=self.number_of_channels() + self.free_channels {
+            self.mute_channel(channel, false);
+        }
+    }
+
+    /// Gets which channels and instruments are muted.
+    pub fn mute_mask(&self) -> MuteMask {
+        MuteMask {
+            channels: self.muted_channels.clone(),
+            instruments: self.muted_instruments.clone(),
+        }
+    }
+
+    /// Mutes the channels and instruments muted in a mask, and unmutes the
+    /// others.
+    pub fn set_mute_mask(&mut self, mask: &MuteMask) {
+        for channel in 1..=self.number_of_channels() + self.free_channels {
+            self.mute_channel(channel, mask.is_channel_muted(channel));
+        }
+        for instrument in 1..=self.number_of_instruments() {
+            self.mute_instrument(instrument, mask.is_instrument_muted(instrument));
+        }
+    }
+
+    /// Adds `count` free channels, numbered after the channels of the
+    /// module. The patterns never play in free channels, so they can play
+    /// the instruments of the module with `note_on()` (sound effects, a
+    /// keyboard…) while the music goes on.
+    ///
+    /// Free channels are mixed with the others, and muted like them. They
+    /// aren't rendered by `generate_samples_per_channel()`.
+    ///
+    /// # Return
+    /// The number of the first added channel
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` with libxm, which only plays the channels of
+    /// the module. `XMError::MemoryAllocationFailed` if the channels can't
+    /// be allocated. No channel is added then.
+    pub fn reserve_channels(&mut self, count: u16) -> Result<u16, XMError> {
+        let first = self.number_of_channels() + self.free_channels + 1;
+        assert!(first as usize + count as usize <= u16::MAX as usize + 1);
+
+        self.muted_channels
+            .try_reserve(count as usize)
+            .map_err(|_| XMError::MemoryAllocationFailed)?;
+        self.player.reserve_channels(count)?;
+
+        self.free_channels += count;
+        self.muted_channels
+            .resize(self.muted_channels.len() + count as usize, false);
+        Ok(first)
+    }
+
+    /// Gets the number of free channels added by `reserve_channels()`.
+    /// Always `0` with libxm.
+    #[inline]
+    pub fn number_of_free_channels(&self) -> u16 {
+        self.free_channels
+    }
+
+    /// Plays a note of an instrument in a channel, outside of the pattern
+    /// data.
+    ///
+    /// The note is played on the next tick, as if it was read in the
+    /// pattern with a volume in the volume column, and a note delay (EDx)
+    /// to that tick: envelopes, fadeout, autovibrato and muting apply as
+    /// usual. In a channel of the module, it takes the place of the cell of
+    /// the pattern until the next row, and plays until the next note there.
+    /// On the first tick of a row, it only takes the place of a cell
+    /// without an effect; otherwise, it waits for the next tick. With the
+    /// engine, use a free channel (see `reserve_channels()`) to play over
+    /// the music.
+    ///
+    /// # Parameters
+    /// * `channel` - From `1` to `get_number_of_channels()`, followed by the
+    ///   free channels
+    /// * `instrument` - From `1` to `get_number_of_instruments()`
+    /// * `note` - From `1` (C-0) to `96` (B-7); `49` is C-4
+    /// * `volume` - From `0.0` to `1.0`
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` for instruments above 255, which the pattern
+    /// data can't hold. Nothing is played then.
+    pub fn note_on(
+        &mut self,
+        channel: u16,
+        instrument: u16,
+        note: u8,
+        volume: f32,
+    ) -> Result<(), XMError> {
+        assert!(channel >= 1);
+        assert!(channel <= self.number_of_channels() + self.free_channels);
+        assert!(instrument >= 1);
+        assert!(instrument <= self.number_of_instruments());
+        assert!((1..=module::NUM_NOTES as u8).contains(&note));
+
+        let volume = (volume.clamp(0.0, 1.0) * 64.0 + 0.5) as u8;
+        self.player.note_on(channel, instrument, note, volume)?;
+        self.record(Command::NoteOn {
+            channel,
+            instrument,
+            note,
+            volume,
+        });
+        self.playthrough = false;
+        Ok(())
+    }
+
+    /// Releases the note playing in a channel, on the next tick, like a key
+    /// off in the pattern: the note fades out if its instrument has a
+    /// volume envelope, and is cut otherwise. The key off is read like the
+    /// notes of `note_on()`.
+    ///
+    /// # Note
+    /// Channel numbers go from `1` to `get_number_of_channels()`, followed
+    /// by the free channels added by `reserve_channels()`
+    pub fn note_off(&mut self, channel: u16) {
+        assert!(channel >= 1);
+        assert!(channel <= self.number_of_channels() + self.free_channels);
+
+        self.player.note_off(channel);
+        self.record(Command::NoteOff(channel));
+        self.playthrough = false;
+    }
+
+    /// Plays again from the start up to `samples` generated samples, with
+    /// the current settings and nothing else.
+    fn play_again(&mut self, samples: u64) -> Result<(), XMError> {
+        self.player.restart()?;
+        self.start_history();
+        for command in self.settings.commands() {
+            command.apply(&mut *self.player)?;
+            self.record(command);
+        }
+
+        self.playthrough = true;
+        self.fast_forward(samples)
+    }
+
+    /// Notes that a setting changed. Unless nothing was played yet, the
+    /// playback so far was played with other settings.
+    pub(crate) fn settings_changed(&mut self) {
+        if self.position().samples > 0 {
+            self.playthrough = false;
+        }
+    }
+
+    /// Brings the player forward to `samples` generated samples.
+    fn fast_forward(&mut self, samples: u64) -> Result<(), XMError> {
+        let result = replay(
+            &mut *self.player,
+            &[],
+            samples,
+            &self.muted_channels,
+            &self.muted_instruments,
+        );
+
+        self.event_state = None;
+        result
+    }
+
+    /// Starts the history over, once the player is at the start of the
+    /// module, if it can't copy itself.
+    fn start_history(&mut self) {
+        self.history = if self.player.can_copy() {
+            None
+        } else {
+            Some(Vec::new())
+        };
+    }
+
+    /// Adds a command to the history, once the player has applied it.
+    /// Every setter applies then records, so a command that fails isn't
+    /// recorded. Past `MAX_HISTORY` commands, or without the memory for
+    /// one more, the history is dropped until the module plays from the
+    /// start again.
+    fn record(&mut self, command: Command) {
+        let samples = self.position().samples;
+        if let Some(history) = &mut self.history {
+            if history.len() < MAX_HISTORY && history.try_reserve(1).is_ok() {
+                history.push((samples, command));
+            } else {
+                self.history = None;
+            }
+        }
     }
 }
 
-impl Drop for XMContext {
-    fn drop(&mut self) {
-        unsafe {
-            ffi::xm_free_context(self.raw);
+/// Copies a slice, or fails if there isn't enough memory.
+pub(crate) fn try_to_vec<T: Clone>(slice: &[T]) -> Result<Vec<T>, XMError> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(slice.len())
+        .map_err(|_| XMError::MemoryAllocationFailed)?;
+    vec.extend_from_slice(slice);
+    Ok(vec)
+}
+
+/// Brings a player forward to `samples` generated samples, applying the
+/// commands of `history` on the way. The channels and instruments are muted
+/// as given once done.
+fn replay(
+    player: &mut dyn backend::Player,
+    history: &[(u64, Command)],
+    samples: u64,
+    muted_channels: &[bool],
+    muted_instruments: &[bool],
+) -> Result<(), XMError> {
+    // Muting doesn't change the playback state, it only skips mixing
+    for channel in 1..=muted_channels.len() {
+        player.mute_channel(channel as u16, true);
+    }
+    let result = replay_muted(player, history, samples);
+    for (i, &muted) in muted_channels.iter().enumerate() {
+        player.mute_channel(i as u16 + 1, muted);
+    }
+    for (i, &muted) in muted_instruments.iter().enumerate() {
+        player.mute_instrument(i as u16 + 1, muted);
+    }
+    result
+}
+
+fn replay_muted(
+    player: &mut dyn backend::Player,
+    history: &[(u64, Command)],
+    samples: u64,
+) -> Result<(), XMError> {
+    let mut scratch = [0.0; 2048];
+    let mut next = 0;
+
+    loop {
+        let target = match history.get(next) {
+            Some(&(at, _)) if at <= samples => at,
+            _ => samples,
+        };
+
+        // Generate (and discard) samples up to the target
+        loop {
+            let generated = player.position().samples;
+            if generated >= target {
+                break;
+            }
+            let frames = (target - generated).min(scratch.len() as u64 / 2) as usize;
+            player.generate_samples(&mut scratch[..frames * 2]);
+        }
+
+        match history.get(next) {
+            Some(&(at, command)) if at <= samples => {
+                command.apply(player)?;
+                next += 1;
+            }
+            _ => return Ok(()),
         }
     }
 }
@@ -0,0 +1,75 @@
+//! Floating-point functions that `core` doesn't have. They come from `std`
+//! when it is available, and from `libm` otherwise. Some are only needed by
+//! the pure-Rust engine.
+
+#[cfg(feature = "std")]
+mod imp {
+    #[cfg(feature = "pure-rust")]
+    #[inline]
+    pub fn sqrtf(x: f32) -> f32 {
+        x.sqrt()
+    }
+
+    #[cfg(feature = "pure-rust")]
+    #[inline]
+    pub fn sinf(x: f32) -> f32 {
+        x.sin()
+    }
+
+    #[cfg(feature = "pure-rust")]
+    #[inline]
+    pub fn powf(x: f32, y: f32) -> f32 {
+        x.powf(y)
+    }
+
+    #[inline]
+    pub fn log2f(x: f32) -> f32 {
+        x.log2()
+    }
+
+    #[cfg(feature = "pure-rust")]
+    #[inline]
+    pub fn floorf(x: f32) -> f32 {
+        x.floor()
+    }
+
+    #[inline]
+    pub fn ceilf(x: f32) -> f32 {
+        x.ceil()
+    }
+
+    #[inline]
+    pub fn roundf(x: f32) -> f32 {
+        x.round()
+    }
+
+    #[inline]
+    pub fn round(x: f64) -> f64 {
+        x.round()
+    }
+
+    #[cfg(feature = "pure-rust")]
+    #[inline]
+    pub fn sin(x: f64) -> f64 {
+        x.sin()
+    }
+
+    #[cfg(feature = "pure-rust")]
+    #[inline]
+    pub fn cos(x: f64) -> f64 {
+        x.cos()
+    }
+}
+
+#[cfg(all(not(feature = "std"), not(feature = "libm")))]
+compile_error!("without the `std` feature, enable the `libm` feature for math functions");
+
+#[cfg(all(not(feature = "std"), feature = "libm"))]
+mod imp {
+    pub use libm::{ceilf, log2f, round, roundf};
+    // Only the engine needs these
+    #[cfg(feature = "pure-rust")]
+    pub use libm::{cos, floorf, powf, sin, sinf, sqrtf};
+}
+
+pub(crate) use imp::*;
@@ -0,0 +1,241 @@
+//! Continuous control over the mix: master volume, channel volumes and
+//! pannings, stereo separation.
+//!
+//! These apply after everything the module does, like muting. With the
+//! `ramping` feature, volumes and pannings move to their new values over a
+//! few samples, without clicks, so they can be used for fades.
+//!
+//! With `Backend::Libxm`, the settings of the channels (volumes, pannings,
+//! stereo separation, Amiga panning) need the `ramping` feature: libxm mixes
+//! the first frame of each tick before they can apply, and only its ramping
+//! can follow them from the next frame. Without it, their setters return
+//! `XMError::Unsupported`.
+
+use crate::{try_to_vec, XMContext, XMError};
+use alloc::vec::Vec;
+
+/// How the channels are mixed, on top of what the module does.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Mixer {
+    /// From 0.0 (silent) to 1.0
+    pub(crate) master_volume: f32,
+    /// Multiplies the volume of each channel, from 0.0 to 1.0. Channel `n`
+    /// is at index `n - 1`; missing channels are at 1.0.
+    pub(crate) channel_volumes: Vec<f32>,
+    /// Replaces the panning of each channel, from 0.0 (left) to 1.0
+    /// (right). Channel `n` is at index `n - 1`.
+    pub(crate) channel_pannings: Vec<Option<f32>>,
+    /// From 0.0 (mono) to 1.0 (the panning of the module)
+    pub(crate) stereo_separation: f32,
+    /// Pans channels hard left or right, like the Amiga did
+    pub(crate) amiga_panning: bool,
+}
+
+impl Default for Mixer {
+    fn default() -> Mixer {
+        Mixer {
+            master_volume: 1.0,
+            channel_volumes: Vec::new(),
+            channel_pannings: Vec::new(),
+            stereo_separation: 1.0,
+            amiga_panning: false,
+        }
+    }
+}
+
+impl Mixer {
+    /// Copies the settings, or fails if there isn't enough memory.
+    pub(crate) fn try_clone(&self) -> Result<Mixer, XMError> {
+        Ok(Mixer {
+            channel_volumes: try_to_vec(&self.channel_volumes)?,
+            channel_pannings: try_to_vec(&self.channel_pannings)?,
+            ..*self
+        })
+    }
+
+    /// Whether the settings change the volumes of the channels.
+    #[cfg(any(not(feature = "pure-rust"), feature = "libxm-reference"))]
+    pub(crate) fn mixes_channels(&self) -> bool {
+        self.channel_volumes.iter().any(|&volume| volume != 1.0)
+            || self.channel_pannings.iter().any(Option::is_some)
+            || self.stereo_separation != 1.0
+            || self.amiga_panning
+    }
+
+    /// Gets the volume multiplier of channel index `i`.
+    #[cfg(any(feature = "pure-rust", feature = "ramping"))]
+    pub(crate) fn volume(&self, i: usize) -> f32 {
+        self.channel_volumes.get(i).copied().unwrap_or(1.0)
+    }
+
+    /// Gets the panning channel index `i` has instead of its own, if any.
+    #[cfg(any(feature = "pure-rust", feature = "ramping"))]
+    pub(crate) fn panning(&self, i: usize) -> Option<f32> {
+        match self.channel_pannings.get(i) {
+            Some(&Some(panning)) => Some(panning),
+            // Channels go left, right, right, left, like on the Amiga
+            _ if self.amiga_panning => Some((i % 4 == 1 || i % 4 == 2) as u8 as f32),
+            _ => None,
+        }
+    }
+}
+
+impl XMContext {
+    /// Sets the volume of the whole mix.
+    ///
+    /// # Parameters
+    /// * `volume` - From `0.0` (silent) upwards; `1.0` leaves the mix as it
+    ///   is. Above `1.0`, the output may clip.
+    pub fn set_master_volume(&mut self, volume: f32) -> Result<(), XMError> {
+        assert!(volume.is_finite() && volume >= 0.0);
+
+        self.update_mixer(|mixer| mixer.master_volume = volume)
+    }
+
+    /// Gets the volume set by `set_master_volume()`.
+    #[inline]
+    pub fn master_volume(&self) -> f32 {
+        self.mixer.master_volume
+    }
+
+    /// Sets the volume of a channel, on top of the volume set by the module.
+    ///
+    /// # Parameters
+    /// * `channel` - From `1` to `get_number_of_channels()`, followed by the
+    ///   free channels added by `reserve_channels()`
+    /// * `volume` - From `0.0` (silent) upwards; `1.0` leaves the channel as
+    ///   it is
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` with libxm without the `ramping` feature.
+    pub fn set_channel_volume(&mut self, channel: u16, volume: f32) -> Result<(), XMError> {
+        assert!(channel >= 1);
+        assert!(channel <= self.number_of_channels() + self.free_channels);
+        assert!(volume.is_finite() && volume >= 0.0);
+
+        self.update_mixer(|mixer| {
+            let volumes = &mut mixer.channel_volumes;
+            if volumes.len() < channel as usize {
+                volumes.resize(channel as usize, 1.0);
+            }
+            volumes[channel as usize - 1] = volume;
+        })
+    }
+
+    /// Gets the volume set by `set_channel_volume()`.
+    ///
+    /// # Note
+    /// Channel numbers go from `1` to `get_number_of_channels()`, followed
+    /// by the free channels added by `reserve_channels()`
+    pub fn channel_volume(&self, channel: u16) -> f32 {
+        assert!(channel >= 1);
+        assert!(channel <= self.number_of_channels() + self.free_channels);
+
+        let volumes = &self.mixer.channel_volumes;
+        volumes.get(channel as usize - 1).copied().unwrap_or(1.0)
+    }
+
+    /// Pans a channel, overriding the panning set by the module (samples,
+    /// effects and panning envelopes) until `reset_channel_panning()`.
+    ///
+    /// # Parameters
+    /// * `channel` - From `1` to `get_number_of_channels()`, followed by the
+    ///   free channels added by `reserve_channels()`
+    /// * `panning` - From `0.0` (left) to `1.0` (right); `0.5` is centered
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` with libxm without the `ramping` feature.
+    pub fn set_channel_panning(&mut self, channel: u16, panning: f32) -> Result<(), XMError> {
+        assert!((0.0..=1.0).contains(&panning));
+        self.override_panning(channel, Some(panning))
+    }
+
+    /// Gives the panning of a channel back to the module.
+    ///
+    /// # Note
+    /// Channel numbers go from `1` to `get_number_of_channels()`, followed
+    /// by the free channels added by `reserve_channels()`
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` with libxm without the `ramping` feature.
+    pub fn reset_channel_panning(&mut self, channel: u16) -> Result<(), XMError> {
+        self.override_panning(channel, None)
+    }
+
+    /// Gets the panning set by `set_channel_panning()`, if any.
+    ///
+    /// # Note
+    /// Channel numbers go from `1` to `get_number_of_channels()`, followed
+    /// by the free channels added by `reserve_channels()`
+    pub fn channel_panning(&self, channel: u16) -> Option<f32> {
+        assert!(channel >= 1);
+        assert!(channel <= self.number_of_channels() + self.free_channels);
+
+        let pannings = &self.mixer.channel_pannings;
+        pannings.get(channel as usize - 1).copied().flatten()
+    }
+
+    /// Narrows the stereo image, towards mono.
+    ///
+    /// # Parameters
+    /// * `separation` - From `0.0` (mono) to `1.0` (full stereo, the
+    ///   default)
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` with libxm without the `ramping` feature.
+    pub fn set_stereo_separation(&mut self, separation: f32) -> Result<(), XMError> {
+        assert!((0.0..=1.0).contains(&separation));
+
+        self.update_mixer(|mixer| mixer.stereo_separation = separation)
+    }
+
+    /// Gets the separation set by `set_stereo_separation()`.
+    #[inline]
+    pub fn stereo_separation(&self) -> f32 {
+        self.mixer.stereo_separation
+    }
+
+    /// Pans channels hard left or right, in the left, right, right, left
+    /// order of the Amiga, ignoring the panning set by the module.
+    ///
+    /// Channels panned with `set_channel_panning()` keep their panning. The
+    /// stereo separation still applies: a separation around `0.7` softens
+    /// the hard panning, as many MOD players do.
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` with libxm without the `ramping` feature.
+    pub fn set_amiga_panning(&mut self, enabled: bool) -> Result<(), XMError> {
+        self.update_mixer(|mixer| mixer.amiga_panning = enabled)
+    }
+
+    /// Gets whether channels are panned like on the Amiga, as set by
+    /// `set_amiga_panning()`.
+    #[inline]
+    pub fn amiga_panning(&self) -> bool {
+        self.mixer.amiga_panning
+    }
+
+    fn override_panning(&mut self, channel: u16, panning: Option<f32>) -> Result<(), XMError> {
+        assert!(channel >= 1);
+        assert!(channel <= self.number_of_channels() + self.free_channels);
+
+        self.update_mixer(|mixer| {
+            let pannings = &mut mixer.channel_pannings;
+            if pannings.len() < channel as usize {
+                pannings.resize(channel as usize, None);
+            }
+            pannings[channel as usize - 1] = panning;
+        })
+    }
+
+    /// Changes the mixer settings, and gives them to the player.
+    fn update_mixer(&mut self, change: impl FnOnce(&mut Mixer)) -> Result<(), XMError> {
+        let mut mixer = self.mixer.clone();
+        change(&mut mixer);
+
+        self.player.set_mixer(&mixer)?;
+        self.mixer = mixer;
+        self.settings_changed();
+        Ok(())
+    }
+}
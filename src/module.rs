@@ -0,0 +1,1142 @@
+//! A pure-Rust parser for XM modules.
+//!
+//! Unlike `XMContext`, which only gives access to playback, this module
+//! exposes the song data itself: the order table, every pattern cell,
+//! instruments with their envelopes and keymaps, and the sample waveforms.
+//!
+//! The parser follows the same rules as libxm's loader, so any data accepted
+//! by `XMContext::new` is accepted by `Module::parse` (and vice versa).
+//! In particular, reads past the end of the data yield zeros, as they do in
+//! libxm, except that sample data is cut where the data ends. Use `validate`
+//! to find out about such problems.
+//!
+//! # Other formats
+//! ProTracker MOD, Scream Tracker 3 S3M and Impulse Tracker IT modules are
+//! loaded too, converted to the XM model: `Module::parse` detects the format
+//! from the magic bytes (see `Format::detect`), and `XMContext::new` plays
+//! the converted module. Channel, instrument and sample numbers are kept, so
+//! timing functions work the same whatever the format.
+//!
+//! The conversion is as close as XM allows, but some features have no XM
+//! equivalent and are dropped or approximated:
+//! * Channel panning and volume, and the global volume of the song. Samples
+//!   start centered, unless S3M or IT give them a panning of their own.
+//! * Effects without an XM counterpart (like S3M/IT channel volume and
+//!   panbrello), and effect memory shared between effects.
+//! * IT new note actions, pitch envelopes, sustain loops (played as normal
+//!   loops when there is no other loop), and envelopes beyond 12 points.
+//! * Notes outside of the XM range (C-0 to B-7).
+//! * Skip markers in the order table, which are removed. Position jumps are
+//!   adjusted accordingly.
+//!
+//! 15-sample Soundtracker modules, which have no magic bytes, aren't
+//! detected.
+//!
+//! # Example
+//! ```no_run
+//! use libxm::module::Module;
+//! use std::fs::File;
+//! use std::io::Read;
+//!
+//! let mut data = Vec::new();
+//! File::open("song.xm").unwrap().read_to_end(&mut data).unwrap();
+//!
+//! let module = Module::parse(&data).unwrap();
+//! for &pattern in &module.pattern_table {
+//!     let pattern = &module.patterns[pattern as usize];
+//!     for (row, cells) in pattern.rows.iter().enumerate() {
+//!         for (channel, cell) in cells.iter().enumerate() {
+//!             if cell.has_note() {
+//!                 println!("row {} channel {}: note {}", row, channel + 1, cell.note);
+//!             }
+//!         }
+//!     }
+//! }
+//! ```
+
+use crate::{try_to_vec, LoopType, ModuleError, ModuleErrorKind, SampleData, XMError};
+use alloc::borrow::Cow;
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+
+mod it;
+mod protracker;
+mod s3m;
+
+/// The number of notes in an instrument keymap.
+pub const NUM_NOTES: usize = 96;
+
+/// The maximum number of points in an envelope.
+pub const MAX_ENVELOPE_POINTS: usize = 12;
+
+/// The maximum length of the pattern order table.
+pub const MAX_PATTERN_TABLE_LENGTH: usize = 256;
+
+/// The maximum number of rows in a pattern.
+pub const MAX_ROWS: usize = 256;
+
+/// The module formats that can be loaded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    /// FastTracker II Extended Module
+    Xm,
+    /// ProTracker (or compatible) module, with 4 to 32 channels
+    Mod,
+    /// Scream Tracker 3 module
+    S3m,
+    /// Impulse Tracker module
+    It,
+}
+
+impl Format {
+    /// Detects the format of a module from its magic bytes.
+    ///
+    /// Returns `None` if the data doesn't look like any supported format.
+    pub fn detect(data: &[u8]) -> Option<Format> {
+        if data.starts_with(b"Extended Module: ") {
+            Some(Format::Xm)
+        } else if data.starts_with(b"IMPM") {
+            Some(Format::It)
+        } else if data.get(44..48) == Some(b"SCRM") {
+            Some(Format::S3m)
+        } else if protracker::num_channels(data).is_some() {
+            Some(Format::Mod)
+        } else {
+            None
+        }
+    }
+}
+
+/// A parsed XM module.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Module {
+    /// The module header
+    pub header: Header,
+    /// The pattern order table (POT). Each entry is a pattern number.
+    pub pattern_table: Vec<u8>,
+    /// The patterns, indexed by pattern number
+    pub patterns: Vec<Pattern>,
+    /// The instruments. Instrument `n` is at index `n - 1`.
+    pub instruments: Vec<Instrument>,
+}
+
+/// How note frequencies are computed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrequencyType {
+    /// Linear frequency table
+    Linear,
+    /// Amiga frequency table
+    Amiga,
+}
+
+/// The XM module header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    /// The module name. The string encoding is unknown.
+    pub name: Vec<u8>,
+    /// The tracker name. The string encoding is unknown.
+    pub tracker_name: Vec<u8>,
+    /// The format version. libxm only accepts `0x0104`.
+    pub version: u16,
+    /// Index in the POT to jump to when the song loops
+    pub restart_position: u16,
+    /// Number of channels
+    pub num_channels: u16,
+    /// Frequency table
+    pub frequency_type: FrequencyType,
+    /// Initial ticks per line
+    pub tempo: u16,
+    /// Initial beats per minute
+    pub bpm: u16,
+}
+
+/// A pattern: a grid of cells, one row per line and one column per channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pattern {
+    /// The rows of the pattern. Each row has one cell per channel.
+    pub rows: Vec<Vec<Cell>>,
+}
+
+/// A single cell (or slot) in a pattern.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cell {
+    /// 0 for no note, 1 to 96 for C-0 to B-7, or `Cell::KEY_OFF`
+    pub note: u8,
+    /// 0 for no instrument, otherwise the instrument number
+    pub instrument: u8,
+    /// The raw volume column
+    pub volume_column: u8,
+    /// The effect command
+    pub effect_type: u8,
+    /// The effect parameter
+    pub effect_param: u8,
+}
+
+impl Cell {
+    /// The note value of a key off.
+    pub const KEY_OFF: u8 = 97;
+
+    /// Returns true if the cell contains a note (not a key off).
+    #[inline]
+    pub fn has_note(&self) -> bool {
+        self.note >= 1 && self.note < Cell::KEY_OFF
+    }
+
+    /// Returns true if the cell is completely empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        *self == Cell::default()
+    }
+}
+
+/// A point in an envelope.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EnvelopePoint {
+    /// Position of the point (in ticks)
+    pub frame: u16,
+    /// Value of the point, from 0 to 64
+    pub value: u16,
+}
+
+/// A volume or panning envelope.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Envelope {
+    /// The points of the envelope (at most `MAX_ENVELOPE_POINTS`)
+    pub points: Vec<EnvelopePoint>,
+    /// Index of the sustain point
+    pub sustain_point: u8,
+    /// Index of the loop start point
+    pub loop_start_point: u8,
+    /// Index of the loop end point
+    pub loop_end_point: u8,
+    /// Whether the envelope is used
+    pub enabled: bool,
+    /// Whether the sustain point is used
+    pub sustain_enabled: bool,
+    /// Whether the loop is used
+    pub loop_enabled: bool,
+}
+
+/// An instrument.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instrument {
+    /// The instrument name. The string encoding is unknown.
+    pub name: Vec<u8>,
+    /// The instrument type (usually 0)
+    pub instrument_type: u8,
+    /// The sample played for each of the 96 notes
+    pub sample_of_notes: [u8; NUM_NOTES],
+    /// Volume envelope
+    pub volume_envelope: Envelope,
+    /// Panning envelope
+    pub panning_envelope: Envelope,
+    /// Autovibrato waveform: 0 = sine, 1 = square, 2 = ramp down, 3 = ramp up
+    pub vibrato_type: u8,
+    /// Autovibrato sweep
+    pub vibrato_sweep: u8,
+    /// Autovibrato depth
+    pub vibrato_depth: u8,
+    /// Autovibrato rate
+    pub vibrato_rate: u8,
+    /// Volume fadeout after a key off
+    pub volume_fadeout: u16,
+    /// The samples of the instrument
+    pub samples: Vec<Sample>,
+}
+
+/// The waveform of a sample, as owned data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SampleBuffer {
+    /// 8-bit signed PCM
+    Bits8(Vec<i8>),
+    /// 16-bit signed PCM
+    Bits16(Vec<i16>),
+}
+
+impl SampleBuffer {
+    /// Borrows the waveform.
+    #[inline]
+    pub fn as_data(&self) -> SampleData<'_> {
+        match *self {
+            SampleBuffer::Bits8(ref data) => SampleData::Bits8(data),
+            SampleBuffer::Bits16(ref data) => SampleData::Bits16(data),
+        }
+    }
+
+    /// Gets the length of the waveform (in samples).
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.as_data().len()
+    }
+
+    /// Returns true if the waveform has no samples.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A sample.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    /// The sample name. The string encoding is unknown.
+    pub name: Vec<u8>,
+    /// Start of the loop (in samples)
+    pub loop_start: u32,
+    /// Length of the loop (in samples)
+    pub loop_length: u32,
+    /// Loop type
+    pub loop_type: LoopType,
+    /// Default volume, from 0 to 64
+    pub volume: u8,
+    /// Finetune, in 1/128ths of a semitone
+    pub finetune: i8,
+    /// Default panning, from 0 (left) to 255 (right)
+    pub panning: u8,
+    /// Relative note number, in semitones
+    pub relative_note: i8,
+    /// The sample points
+    pub data: SampleBuffer,
+}
+
+impl Module {
+    /// Parses a module. XM, MOD, S3M and IT modules are accepted, and
+    /// converted to XM (see the module documentation).
+    ///
+    /// # Parameters
+    /// * `data` - The contents of the module.
+    pub fn parse(data: &[u8]) -> Result<Module, XMError> {
+        parse(data, true)
+    }
+
+    /// Gets the number of channels.
+    #[inline]
+    pub fn num_channels(&self) -> u16 {
+        self.header.num_channels
+    }
+
+    /// Writes the module as a FastTracker II XM v1.04 file.
+    ///
+    /// Patterns are written with packed encoding, and sample data is
+    /// delta-encoded. Names longer than their field are truncated.
+    ///
+    /// # Errors
+    /// Returns an `InvalidInput` error if the module can't be represented
+    /// in the XM format: a row without exactly `num_channels` cells, more
+    /// than 256 entries in the pattern table or one that refers to a
+    /// nonexistent pattern, a pattern of more than 256 rows or too large to
+    /// pack, a sample loop that ends past the end of its sample, or more
+    /// patterns, instruments or sample data than the header can count.
+    ///
+    /// Requires the `std` feature.
+    #[cfg(feature = "std")]
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes()?)
+    }
+
+    /// Writes the module to a new buffer. See `write_to()`.
+    #[cfg(feature = "std")]
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        self.encode()
+            .map_err(|message| io::Error::new(io::ErrorKind::InvalidInput, message))
+    }
+
+    /// Encodes the module as an XM file, or tells why it can't be.
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, &'static str> {
+        self.validate()?;
+
+        let mut buffer = Vec::new();
+        self.write_header(&mut buffer);
+        for pattern in &self.patterns {
+            write_pattern(&mut buffer, pattern)?;
+        }
+        for instrument in &self.instruments {
+            write_instrument(&mut buffer, instrument);
+        }
+
+        Ok(buffer)
+    }
+
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.pattern_table.len() > MAX_PATTERN_TABLE_LENGTH {
+            return Err("pattern table has more than 256 entries");
+        }
+        if self
+            .pattern_table
+            .iter()
+            .any(|&pattern| pattern as usize >= self.patterns.len())
+        {
+            return Err("pattern table refers to a nonexistent pattern");
+        }
+        if self.patterns.len() > u16::MAX as usize {
+            return Err("too many patterns");
+        }
+        if self.instruments.len() > u16::MAX as usize {
+            return Err("too many instruments");
+        }
+        for pattern in &self.patterns {
+            if pattern.rows.len() > MAX_ROWS {
+                return Err("pattern has more than 256 rows");
+            }
+            if pattern
+                .rows
+                .iter()
+                .any(|row| row.len() != self.header.num_channels as usize)
+            {
+                return Err("row length doesn't match the number of channels");
+            }
+        }
+        for instrument in &self.instruments {
+            if instrument.samples.len() > u16::MAX as usize {
+                return Err("instrument has too many samples");
+            }
+            for sample in &instrument.samples {
+                let bytes_per_sample = if let SampleBuffer::Bits16(_) = sample.data {
+                    2
+                } else {
+                    1
+                };
+                // Lengths are stored in bytes, loops are within the sample
+                match sample.data.len().checked_mul(bytes_per_sample) {
+                    Some(bytes) if bytes <= u32::MAX as usize => (),
+                    _ => return Err("sample is too long"),
+                }
+                match sample.loop_start.checked_add(sample.loop_length) {
+                    Some(end) if end as usize <= sample.data.len() => (),
+                    _ => return Err("sample loop ends past the end of the sample"),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_header(&self, w: &mut Vec<u8>) {
+        let header = &self.header;
+
+        w.extend_from_slice(b"Extended Module: ");
+        write_string(w, &header.name, 20);
+        w.push(0x1A);
+        write_string(w, &header.tracker_name, 20);
+        w.extend_from_slice(&0x0104u16.to_le_bytes());
+
+        // Header size, counted from this field
+        w.extend_from_slice(&(20 + MAX_PATTERN_TABLE_LENGTH as u32).to_le_bytes());
+        w.extend_from_slice(&(self.pattern_table.len() as u16).to_le_bytes());
+        w.extend_from_slice(&header.restart_position.to_le_bytes());
+        w.extend_from_slice(&header.num_channels.to_le_bytes());
+        w.extend_from_slice(&(self.patterns.len() as u16).to_le_bytes());
+        w.extend_from_slice(&(self.instruments.len() as u16).to_le_bytes());
+        let flags: u16 = match header.frequency_type {
+            FrequencyType::Linear => 1,
+            FrequencyType::Amiga => 0,
+        };
+        w.extend_from_slice(&flags.to_le_bytes());
+        w.extend_from_slice(&header.tempo.to_le_bytes());
+        w.extend_from_slice(&header.bpm.to_le_bytes());
+
+        let mut pattern_table = [0; MAX_PATTERN_TABLE_LENGTH];
+        pattern_table[..self.pattern_table.len()].copy_from_slice(&self.pattern_table);
+        w.extend_from_slice(&pattern_table);
+    }
+}
+
+/// Writes a fixed-length string, truncating or padding it with NULs.
+fn write_string(w: &mut Vec<u8>, s: &[u8], length: usize) {
+    let s = &s[..s.len().min(length)];
+    w.extend_from_slice(s);
+    w.resize(w.len() + length - s.len(), 0);
+}
+
+fn write_pattern(w: &mut Vec<u8>, pattern: &Pattern) -> Result<(), &'static str> {
+    let mut packed = Vec::new();
+
+    // An empty pattern is stored without any pattern data
+    if pattern.rows.iter().flatten().any(|cell| !cell.is_empty()) {
+        for cell in pattern.rows.iter().flatten() {
+            let fields = [
+                cell.note,
+                cell.instrument,
+                cell.volume_column,
+                cell.effect_type,
+                cell.effect_param,
+            ];
+            let mask = fields
+                .iter()
+                .enumerate()
+                .filter(|&(_, &field)| field != 0)
+                .fold(0u8, |mask, (bit, _)| mask | (1 << bit));
+
+            if mask == 0x1F && cell.note & 0x80 == 0 {
+                // Packing every field would take more space than not packing
+                packed.extend_from_slice(&fields);
+            } else {
+                packed.push(0x80 | mask);
+                packed.extend(fields.iter().filter(|&&field| field != 0));
+            }
+        }
+    }
+
+    if packed.len() > u16::MAX as usize {
+        return Err("pattern is too large to pack");
+    }
+
+    // Header length, packing type, rows, packed data size
+    w.extend_from_slice(&9u32.to_le_bytes());
+    w.push(0);
+    w.extend_from_slice(&(pattern.rows.len() as u16).to_le_bytes());
+    w.extend_from_slice(&(packed.len() as u16).to_le_bytes());
+    w.extend_from_slice(&packed);
+    Ok(())
+}
+
+fn write_envelope_points(w: &mut Vec<u8>, envelope: &Envelope) {
+    for j in 0..MAX_ENVELOPE_POINTS {
+        let point = envelope.points.get(j).copied().unwrap_or_default();
+        w.extend_from_slice(&point.frame.to_le_bytes());
+        w.extend_from_slice(&point.value.to_le_bytes());
+    }
+}
+
+fn envelope_flags(envelope: &Envelope) -> u8 {
+    (envelope.enabled as u8)
+        | (envelope.sustain_enabled as u8) << 1
+        | (envelope.loop_enabled as u8) << 2
+}
+
+fn write_instrument(w: &mut Vec<u8>, instrument: &Instrument) {
+    const INSTRUMENT_HEADER_SIZE: u32 = 263;
+    const SAMPLE_HEADER_SIZE: u32 = 40;
+
+    let volume_envelope = &instrument.volume_envelope;
+    let panning_envelope = &instrument.panning_envelope;
+
+    w.extend_from_slice(&INSTRUMENT_HEADER_SIZE.to_le_bytes());
+    write_string(w, &instrument.name, 22);
+    w.push(instrument.instrument_type);
+    w.extend_from_slice(&(instrument.samples.len() as u16).to_le_bytes());
+    w.extend_from_slice(&SAMPLE_HEADER_SIZE.to_le_bytes());
+    w.extend_from_slice(&instrument.sample_of_notes);
+    write_envelope_points(w, volume_envelope);
+    write_envelope_points(w, panning_envelope);
+    w.extend_from_slice(&[
+        volume_envelope.points.len().min(MAX_ENVELOPE_POINTS) as u8,
+        panning_envelope.points.len().min(MAX_ENVELOPE_POINTS) as u8,
+        volume_envelope.sustain_point,
+        volume_envelope.loop_start_point,
+        volume_envelope.loop_end_point,
+        panning_envelope.sustain_point,
+        panning_envelope.loop_start_point,
+        panning_envelope.loop_end_point,
+        envelope_flags(volume_envelope),
+        envelope_flags(panning_envelope),
+        instrument.vibrato_type,
+        instrument.vibrato_sweep,
+        instrument.vibrato_depth,
+        instrument.vibrato_rate,
+    ]);
+    w.extend_from_slice(&instrument.volume_fadeout.to_le_bytes());
+    w.extend_from_slice(&[0; 22]);
+
+    for sample in &instrument.samples {
+        let (shift, bits_flag) = match sample.data {
+            SampleBuffer::Bits8(_) => (0, 0),
+            SampleBuffer::Bits16(_) => (1, 1 << 4),
+        };
+        let loop_flag = match sample.loop_type {
+            LoopType::NoLoop => 0,
+            LoopType::Forward => 1,
+            LoopType::PingPong => 2,
+        };
+
+        // Lengths are stored in bytes
+        w.extend_from_slice(&((sample.data.len() as u32) << shift).to_le_bytes());
+        w.extend_from_slice(&(sample.loop_start << shift).to_le_bytes());
+        w.extend_from_slice(&(sample.loop_length << shift).to_le_bytes());
+        w.extend_from_slice(&[
+            sample.volume,
+            sample.finetune as u8,
+            loop_flag | bits_flag,
+            sample.panning,
+            sample.relative_note as u8,
+            0,
+        ]);
+        write_string(w, &sample.name, 22);
+    }
+
+    for sample in &instrument.samples {
+        // Sample data is delta-encoded
+        match sample.data {
+            SampleBuffer::Bits8(ref data) => {
+                let mut previous = 0i8;
+                w.extend(data.iter().map(|&v| {
+                    let delta = v.wrapping_sub(previous);
+                    previous = v;
+                    delta as u8
+                }));
+            }
+            SampleBuffer::Bits16(ref data) => {
+                let mut previous = 0i16;
+                w.extend(data.iter().flat_map(|&v| {
+                    let delta = v.wrapping_sub(previous);
+                    previous = v;
+                    delta.to_le_bytes()
+                }));
+            }
+        }
+    }
+}
+
+/// Parses everything but the sample points, which are left empty.
+pub(crate) fn parse_headers(data: &[u8]) -> Result<Module, XMError> {
+    parse(data, false)
+}
+
+/// Converts a module of any supported format to an XM file, for libxm.
+/// XM data (or data in no known format, for libxm to reject) is returned
+/// as is.
+pub(crate) fn to_xm(data: &[u8]) -> Result<Cow<'_, [u8]>, XMError> {
+    match Format::detect(data) {
+        None | Some(Format::Xm) => Ok(Cow::Borrowed(data)),
+        Some(_) => {
+            let module = parse(data, true)?;
+            let xm = module.encode().map_err(|reason| {
+                XMError::InvalidModule(module_error(ModuleErrorKind::Unconvertible(reason), 0))
+            })?;
+            Ok(Cow::Owned(xm))
+        }
+    }
+}
+
+/// Bounds-checked reads. Like libxm's `READ_U8` and friends, reading past the
+/// end of the data yields zeros.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&self, offset: usize) -> u8 {
+        self.data.get(offset).copied().unwrap_or(0)
+    }
+
+    /// Moves `offset` forward by a length read from the data. Past the end
+    /// of the data, where reads yield zeros, the offset stops at the end.
+    fn skip(&self, offset: usize, length: usize) -> usize {
+        offset
+            .checked_add(length)
+            .map_or(self.data.len(), |offset| offset.min(self.data.len()))
+    }
+
+    /// Gets how many bytes are left after `offset`.
+    fn remaining(&self, offset: usize) -> usize {
+        self.data.len().saturating_sub(offset)
+    }
+
+    fn u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.u8(offset), self.u8(offset + 1)])
+    }
+
+    fn u16_be(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.u8(offset), self.u8(offset + 1)])
+    }
+
+    fn u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes([
+            self.u8(offset),
+            self.u8(offset + 1),
+            self.u8(offset + 2),
+            self.u8(offset + 3),
+        ])
+    }
+
+    fn bytes(&self, offset: usize, buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.u8(offset + i);
+        }
+    }
+
+    /// Reads a fixed-length string, stopping at the first NUL.
+    fn string(&self, offset: usize, length: usize) -> Vec<u8> {
+        (0..length)
+            .map(|i| self.u8(offset + i))
+            .take_while(|&b| b != 0)
+            .collect()
+    }
+
+    /// Gets up to `length` bytes, less if the data ends before.
+    fn slice(&self, offset: usize, length: usize) -> &'a [u8] {
+        let start = offset.min(self.data.len());
+        &self.data[start..offset.saturating_add(length).min(self.data.len())]
+    }
+}
+
+fn parse(data: &[u8], decode_samples: bool) -> Result<Module, XMError> {
+    let module = match Format::detect(data) {
+        Some(Format::Mod) => protracker::load(data, decode_samples),
+        Some(Format::S3m) => s3m::load(data, decode_samples),
+        Some(Format::It) => it::load(data, decode_samples),
+        None | Some(Format::Xm) => return parse_xm(data, decode_samples),
+    };
+    module.map_err(XMError::InvalidModule)
+}
+
+fn parse_xm(data: &[u8], decode_samples: bool) -> Result<Module, XMError> {
+    check_sanity_preload(data).map_err(XMError::InvalidModule)?;
+
+    let r = Reader { data };
+
+    let song_length = r.u16(64) as usize;
+    let num_channels = r.u16(68);
+    let num_patterns = r.u16(70);
+    let num_instruments = r.u16(72);
+    let flags = r.u16(74);
+
+    let header = Header {
+        name: r.string(17, 20),
+        tracker_name: r.string(38, 20),
+        version: r.u16(58),
+        restart_position: r.u16(66),
+        num_channels,
+        frequency_type: if flags & 1 != 0 {
+            FrequencyType::Linear
+        } else {
+            FrequencyType::Amiga
+        },
+        tempo: r.u16(76),
+        bpm: r.u16(78),
+    };
+
+    let mut pattern_table = vec![0; song_length.min(MAX_PATTERN_TABLE_LENGTH)];
+    r.bytes(80, &mut pattern_table);
+
+    let mut offset = r.skip(60, r.u32(60) as usize);
+
+    let mut patterns = try_vec_with_capacity(num_patterns as usize)?;
+    for _ in 0..num_patterns {
+        let (pattern, next) = parse_pattern(&r, offset, num_channels as usize)?;
+        patterns.push(pattern);
+        offset = next;
+    }
+
+    let mut instruments = try_vec_with_capacity(num_instruments as usize)?;
+    for _ in 0..num_instruments {
+        let (instrument, next) = parse_instrument(&r, offset, decode_samples)?;
+        instruments.push(instrument);
+        offset = next;
+    }
+
+    check_pattern_table(&mut pattern_table, patterns.len()).map_err(XMError::InvalidModule)?;
+
+    Ok(Module {
+        header,
+        pattern_table,
+        patterns,
+        instruments,
+    })
+}
+
+/// Checks the structure of a module more strictly than libxm does, without
+/// loading it.
+///
+/// libxm (and `Module::parse`) read missing data as zeros, so a module that
+/// was cut short or has inconsistent sizes may still load, and sound wrong.
+/// This function reports the first such problem instead, which is useful to
+/// tell users what is wrong with a file before accepting it.
+///
+/// Only XM modules are checked in depth. Modules in other formats are only
+/// checked to load.
+///
+/// # Parameters
+/// * `data` - The contents of the module.
+pub fn validate(data: &[u8]) -> Result<(), ModuleError> {
+    match Format::detect(data) {
+        Some(Format::Mod) => protracker::load(data, false).map(|_| ()),
+        Some(Format::S3m) => s3m::load(data, false).map(|_| ()),
+        Some(Format::It) => it::load(data, false).map(|_| ()),
+        None | Some(Format::Xm) => validate_xm(data),
+    }
+}
+
+fn validate_xm(data: &[u8]) -> Result<(), ModuleError> {
+    check_sanity(data)?;
+
+    let r = Reader { data };
+    let num_channels = r.u16(68) as usize;
+    let num_patterns = r.u16(70);
+    let num_instruments = r.u16(72);
+
+    // Offsets and sizes are checked against the data before they are
+    // added, so the sums can't overflow
+    let past_end = |offset: usize, length: usize| length > data.len().saturating_sub(offset);
+
+    // The header fields end at 80, the header size counts from 60
+    if past_end(0, 80) || past_end(60, r.u32(60) as usize) {
+        return Err(module_error(ModuleErrorKind::TruncatedHeader, 0));
+    }
+    let mut offset = 60 + r.u32(60) as usize;
+
+    for pattern in 0..num_patterns {
+        let mismatch = module_error(ModuleErrorKind::PatternSizeMismatch { pattern }, offset);
+
+        if past_end(offset, 9) || past_end(offset, r.u32(offset) as usize) {
+            return Err(module_error(ModuleErrorKind::TruncatedHeader, offset));
+        }
+        let num_rows = r.u16(offset + 5) as usize;
+        let packed_size = r.u16(offset + 7) as usize;
+        let data_offset = offset + r.u32(offset) as usize;
+        if past_end(data_offset, packed_size) {
+            return Err(mismatch);
+        }
+
+        if packed_size > 0 {
+            let mut j = 0;
+            let mut num_cells = 0;
+            while j < packed_size {
+                let note = r.u8(data_offset + j);
+                j += if note & (1 << 7) != 0 {
+                    1 + (note & 0x1F).count_ones() as usize
+                } else {
+                    5
+                };
+                num_cells += 1;
+            }
+            if j != packed_size || num_cells != num_rows * num_channels {
+                return Err(mismatch);
+            }
+        }
+
+        offset = data_offset + packed_size;
+    }
+
+    for instrument in 1..=num_instruments {
+        if past_end(offset, 29) {
+            return Err(module_error(ModuleErrorKind::TruncatedHeader, offset));
+        }
+        let header_size = r.u32(offset) as usize;
+        let num_samples = r.u16(offset + 27);
+        let sample_header_size = if num_samples > 0 {
+            if past_end(offset, 33) {
+                return Err(module_error(ModuleErrorKind::TruncatedHeader, offset));
+            }
+            r.u32(offset + 29) as usize
+        } else {
+            0
+        };
+        if past_end(offset, header_size) {
+            return Err(module_error(ModuleErrorKind::TruncatedHeader, offset));
+        }
+        offset += header_size;
+
+        let mut byte_lengths = Vec::with_capacity(num_samples as usize);
+        for _ in 0..num_samples {
+            if past_end(offset, sample_header_size) || past_end(offset, 4) {
+                return Err(module_error(ModuleErrorKind::TruncatedHeader, offset));
+            }
+            byte_lengths.push(r.u32(offset) as usize);
+            offset += sample_header_size;
+        }
+
+        for (sample, byte_length) in byte_lengths.into_iter().enumerate() {
+            if past_end(offset, byte_length) {
+                let kind = ModuleErrorKind::SampleLengthPastEof {
+                    instrument,
+                    sample: sample as u16,
+                };
+                return Err(module_error(kind, offset));
+            }
+            offset += byte_length;
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the same sanity checks as libxm, to find out why it rejected a
+/// module.
+pub(crate) fn check_sanity(data: &[u8]) -> Result<(), ModuleError> {
+    check_sanity_preload(data)?;
+
+    let r = Reader { data };
+    let song_length = (r.u16(64) as usize).min(MAX_PATTERN_TABLE_LENGTH);
+    let mut pattern_table = vec![0; song_length];
+    r.bytes(80, &mut pattern_table);
+    check_pattern_table(&mut pattern_table, r.u16(70) as usize)
+}
+
+fn module_error(kind: ModuleErrorKind, offset: usize) -> ModuleError {
+    ModuleError { kind, offset }
+}
+
+/// Makes an instrument that plays the same sample on every note, as MOD and
+/// S3M instruments (and IT samples) do.
+fn single_sample_instrument(sample: Sample) -> Instrument {
+    Instrument {
+        name: sample.name.clone(),
+        instrument_type: 0,
+        sample_of_notes: [0; NUM_NOTES],
+        volume_envelope: Envelope::default(),
+        panning_envelope: Envelope::default(),
+        vibrato_type: 0,
+        vibrato_sweep: 0,
+        vibrato_depth: 0,
+        vibrato_rate: 0,
+        volume_fadeout: 0,
+        samples: vec![sample],
+    }
+}
+
+fn check_sanity_preload(data: &[u8]) -> Result<(), ModuleError> {
+    if data.len() < 60 {
+        return Err(module_error(ModuleErrorKind::TruncatedHeader, 0));
+    }
+    if &data[0..17] != b"Extended Module: " {
+        return Err(module_error(ModuleErrorKind::BadMagic, 0));
+    }
+    if data[37] != 0x1A {
+        return Err(module_error(ModuleErrorKind::BadMagic, 37));
+    }
+    if data[58] != 0x04 || data[59] != 0x01 {
+        let version = u16::from_le_bytes([data[58], data[59]]);
+        return Err(module_error(
+            ModuleErrorKind::UnsupportedVersion(version),
+            58,
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_pattern_table(
+    pattern_table: &mut Vec<u8>,
+    num_patterns: usize,
+) -> Result<(), ModuleError> {
+    let length = pattern_table.len();
+
+    for i in 0..length {
+        if pattern_table[i] as usize >= num_patterns {
+            if i + 1 == length && length > 1 {
+                // Same cheap fix as libxm: drop a trailing invalid entry
+                pattern_table.pop();
+            } else {
+                return Err(module_error(
+                    ModuleErrorKind::NonexistentPattern(pattern_table[i]),
+                    80 + i,
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_pattern(
+    r: &Reader,
+    offset: usize,
+    num_channels: usize,
+) -> Result<(Pattern, usize), XMError> {
+    let num_rows = r.u16(offset + 5) as usize;
+    let packed_size = r.u16(offset + 7) as usize;
+    let offset = r.skip(offset, r.u32(offset) as usize);
+
+    // Cells missing from the packed data are empty, so the size of the
+    // pattern doesn't depend on the data. It may not fit in memory.
+    let num_cells = num_rows
+        .checked_mul(num_channels)
+        .ok_or(XMError::MemoryAllocationFailed)?;
+    let mut cells = try_vec_with_capacity(num_cells)?;
+    cells.resize(num_cells, Cell::default());
+
+    let mut j = 0;
+    let mut k = 0;
+    while j < packed_size && k < cells.len() {
+        let note = r.u8(offset + j);
+        let cell = &mut cells[k];
+
+        if note & (1 << 7) != 0 {
+            // Packed cell: the first byte says which fields follow
+            j += 1;
+            for (bit, field) in [
+                &mut cell.note,
+                &mut cell.instrument,
+                &mut cell.volume_column,
+                &mut cell.effect_type,
+                &mut cell.effect_param,
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                if note & (1 << bit) != 0 {
+                    *field = r.u8(offset + j);
+                    j += 1;
+                }
+            }
+        } else {
+            cell.note = note;
+            cell.instrument = r.u8(offset + j + 1);
+            cell.volume_column = r.u8(offset + j + 2);
+            cell.effect_type = r.u8(offset + j + 3);
+            cell.effect_param = r.u8(offset + j + 4);
+            j += 5;
+        }
+
+        k += 1;
+    }
+
+    let mut rows = try_vec_with_capacity(num_rows)?;
+    if num_channels == 0 {
+        rows.resize(num_rows, Vec::new());
+    } else {
+        for row in cells.chunks(num_channels) {
+            rows.push(try_to_vec(row)?);
+        }
+    }
+
+    Ok((Pattern { rows }, r.skip(offset, packed_size)))
+}
+
+fn parse_envelope(r: &Reader, points_offset: usize, offset: usize, which: usize) -> Envelope {
+    let num_points = (r.u8(offset + 225 + which) as usize).min(MAX_ENVELOPE_POINTS);
+    let flags = r.u8(offset + 233 + which);
+
+    Envelope {
+        points: (0..num_points)
+            .map(|j| EnvelopePoint {
+                frame: r.u16(points_offset + 4 * j),
+                value: r.u16(points_offset + 4 * j + 2),
+            })
+            .collect(),
+        sustain_point: r.u8(offset + 227 + 3 * which),
+        loop_start_point: r.u8(offset + 228 + 3 * which),
+        loop_end_point: r.u8(offset + 229 + 3 * which),
+        enabled: flags & 1 != 0,
+        sustain_enabled: flags & 2 != 0,
+        loop_enabled: flags & 4 != 0,
+    }
+}
+
+fn parse_instrument(
+    r: &Reader,
+    offset: usize,
+    decode_samples: bool,
+) -> Result<(Instrument, usize), XMError> {
+    let num_samples = r.u16(offset + 27);
+
+    let mut instrument = Instrument {
+        name: r.string(offset + 4, 22),
+        instrument_type: r.u8(offset + 26),
+        sample_of_notes: [0; NUM_NOTES],
+        volume_envelope: Envelope::default(),
+        panning_envelope: Envelope::default(),
+        vibrato_type: 0,
+        vibrato_sweep: 0,
+        vibrato_depth: 0,
+        vibrato_rate: 0,
+        volume_fadeout: 0,
+        samples: try_vec_with_capacity(num_samples as usize)?,
+    };
+
+    // The extra header properties are only present if there are samples
+    let mut sample_header_size = 0;
+    if num_samples > 0 {
+        sample_header_size = r.u32(offset + 29) as usize;
+        r.bytes(offset + 33, &mut instrument.sample_of_notes);
+        instrument.volume_envelope = parse_envelope(r, offset + 129, offset, 0);
+        instrument.panning_envelope = parse_envelope(r, offset + 177, offset, 1);
+        instrument.vibrato_type = r.u8(offset + 235);
+        instrument.vibrato_sweep = r.u8(offset + 236);
+        instrument.vibrato_depth = r.u8(offset + 237);
+        instrument.vibrato_rate = r.u8(offset + 238);
+        instrument.volume_fadeout = r.u16(offset + 239);
+    }
+
+    let mut offset = r.skip(offset, r.u32(offset) as usize);
+
+    // All sample headers come first, then all the sample data
+    let mut byte_lengths = try_vec_with_capacity(num_samples as usize)?;
+    for _ in 0..num_samples {
+        let byte_length = r.u32(offset);
+        let flags = r.u8(offset + 14);
+        let sixteen_bit = flags & (1 << 4) != 0;
+        let shift = if sixteen_bit { 1 } else { 0 };
+
+        // Lengths are stored in bytes; everything here is in samples
+        let length = byte_length >> shift;
+        let loop_start = (r.u32(offset + 4) >> shift).min(length);
+        let loop_length = (r.u32(offset + 8) >> shift).min(length - loop_start);
+
+        instrument.samples.push(Sample {
+            name: r.string(offset + 18, 22),
+            loop_start,
+            loop_length,
+            loop_type: match flags & 3 {
+                0 => LoopType::NoLoop,
+                1 => LoopType::Forward,
+                _ => LoopType::PingPong,
+            },
+            volume: r.u8(offset + 12),
+            finetune: r.u8(offset + 13) as i8,
+            panning: r.u8(offset + 15),
+            relative_note: r.u8(offset + 16) as i8,
+            data: if sixteen_bit {
+                SampleBuffer::Bits16(Vec::new())
+            } else {
+                SampleBuffer::Bits8(Vec::new())
+            },
+        });
+        byte_lengths.push(byte_length as usize);
+
+        offset = r.skip(offset, sample_header_size);
+    }
+
+    for (sample, byte_length) in instrument.samples.iter_mut().zip(byte_lengths) {
+        // Sample data past the end of the data is left out, rather than
+        // read as zeros, so that the length given by the header can't
+        // make the sample bigger than the data.
+        let present = byte_length.min(r.remaining(offset));
+        if decode_samples {
+            // Sample data is delta-encoded
+            match sample.data {
+                SampleBuffer::Bits8(ref mut data) => {
+                    data.try_reserve_exact(present)
+                        .map_err(|_| XMError::MemoryAllocationFailed)?;
+                    let mut v = 0i8;
+                    data.extend((0..present).map(|k| {
+                        v = v.wrapping_add(r.u8(offset + k) as i8);
+                        v
+                    }));
+                }
+                SampleBuffer::Bits16(ref mut data) => {
+                    data.try_reserve_exact(present / 2)
+                        .map_err(|_| XMError::MemoryAllocationFailed)?;
+                    let mut v = 0i16;
+                    data.extend((0..present / 2).map(|k| {
+                        v = v.wrapping_add(r.u16(offset + 2 * k) as i16);
+                        v
+                    }));
+                }
+            }
+        }
+        if present < byte_length {
+            let length = match sample.data {
+                SampleBuffer::Bits8(_) => present as u32,
+                SampleBuffer::Bits16(_) => (present / 2) as u32,
+            };
+            sample.loop_start = sample.loop_start.min(length);
+            sample.loop_length = sample.loop_length.min(length - sample.loop_start);
+        }
+        offset = r.skip(offset, byte_length);
+    }
+
+    Ok((instrument, offset))
+}
+
+/// Makes an empty vector, or fails if `capacity` elements don't fit in
+/// memory.
+fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, XMError> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(capacity)
+        .map_err(|_| XMError::MemoryAllocationFailed)?;
+    Ok(vec)
+}
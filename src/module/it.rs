@@ -0,0 +1,647 @@
+//! Impulse Tracker IT loader.
+
+use super::s3m::{
+    add_missing_patterns, convert_effect, convert_note, convert_orders, decode, note_cut,
+    pitch_of_speed, remap_jumps,
+};
+use super::{
+    module_error, single_sample_instrument, Cell, Envelope, EnvelopePoint, FrequencyType, Header,
+    Instrument, Module, Pattern, Reader, Sample, SampleBuffer, MAX_ENVELOPE_POINTS,
+    MAX_PATTERN_TABLE_LENGTH, MAX_ROWS, NUM_NOTES,
+};
+use crate::{LoopType, ModuleError, ModuleErrorKind};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const MAX_CHANNELS: usize = 64;
+const MAX_ENVELOPE_NODES: usize = 25;
+
+/// IT plays middle C as C-5, XM as C-4.
+const NOTE_OFFSET: u8 = 11;
+
+/// A sample, with what XM has on instruments rather than samples.
+struct ItSample {
+    sample: Sample,
+    /// From 0 to 64
+    global_volume: u8,
+    /// Autovibrato type, sweep, depth and rate, in XM terms
+    vibrato: [u8; 4],
+}
+
+/// The parts of a pattern cell that can be repeated from the previous cell
+/// of the channel.
+#[derive(Copy, Clone, Default)]
+struct ItCell {
+    note: u8,
+    instrument: u8,
+    volume: u8,
+    command: u8,
+    param: u8,
+}
+
+pub(super) fn load(data: &[u8], decode_samples: bool) -> Result<Module, ModuleError> {
+    if data.len() < 192 {
+        return Err(module_error(ModuleErrorKind::TruncatedHeader, 0));
+    }
+    let r = Reader { data };
+
+    let num_orders = r.u16(32) as usize;
+    let num_instruments = r.u16(34) as usize;
+    let num_samples = r.u16(36) as usize;
+    let num_patterns = (r.u16(38) as usize).min(MAX_PATTERN_TABLE_LENGTH);
+    let compatible_version = r.u16(42);
+    let flags = r.u16(44);
+
+    let mut orders = vec![0; num_orders];
+    r.bytes(192, &mut orders);
+    let (pattern_table, positions) = convert_orders(&orders);
+
+    let pointers = 192 + num_orders;
+    let instrument_pointer = |i: usize| r.u32(pointers + 4 * i) as usize;
+    let sample_pointer = |i: usize| r.u32(pointers + 4 * (num_instruments + i)) as usize;
+    let pattern_pointer =
+        |i: usize| r.u32(pointers + 4 * (num_instruments + num_samples + i)) as usize;
+
+    // Muted channels don't play. Channel numbers are kept, so they are left
+    // empty.
+    let enabled: Vec<bool> = (0..MAX_CHANNELS).map(|c| r.u8(64 + c) & 128 == 0).collect();
+
+    let mut patterns: Vec<Pattern> = (0..num_patterns)
+        .map(|p| parse_pattern(&r, pattern_pointer(p), &enabled))
+        .collect();
+
+    // Only keep the channels up to the last one used
+    let num_channels = patterns
+        .iter()
+        .flat_map(|p| p.rows.iter())
+        .filter_map(|row| row.iter().rposition(|cell| !cell.is_empty()))
+        .max()
+        .map_or(1, |c| c + 1);
+    for row in patterns.iter_mut().flat_map(|p| p.rows.iter_mut()) {
+        row.truncate(num_channels);
+    }
+    add_missing_patterns(&mut patterns, &pattern_table, num_channels, 64);
+    remap_jumps(&mut patterns, &positions, pattern_table.len());
+
+    let samples: Vec<ItSample> = (0..num_samples)
+        .map(|i| parse_sample(&r, sample_pointer(i), decode_samples))
+        .collect();
+
+    let instruments = if flags & 4 != 0 {
+        (0..num_instruments)
+            .map(|i| {
+                let old_format = compatible_version < 0x200;
+                parse_instrument(&r, instrument_pointer(i), old_format, &samples)
+            })
+            .collect()
+    } else {
+        // Without instruments, cells refer to samples
+        samples
+            .iter()
+            .map(|it_sample| {
+                let [vibrato_type, vibrato_sweep, vibrato_depth, vibrato_rate] = it_sample.vibrato;
+                let mut instrument = single_sample_instrument(scaled(
+                    &it_sample.sample,
+                    it_sample.global_volume,
+                    64,
+                ));
+                instrument.vibrato_type = vibrato_type;
+                instrument.vibrato_sweep = vibrato_sweep;
+                instrument.vibrato_depth = vibrato_depth;
+                instrument.vibrato_rate = vibrato_rate;
+                instrument
+            })
+            .collect()
+    };
+
+    let speed = r.u8(50);
+    let tempo = r.u8(51);
+    let header = Header {
+        name: r.string(4, 26),
+        tracker_name: b"Impulse Tracker".to_vec(),
+        version: 0x0104,
+        restart_position: 0,
+        num_channels: num_channels as u16,
+        frequency_type: if flags & 8 != 0 {
+            FrequencyType::Linear
+        } else {
+            FrequencyType::Amiga
+        },
+        tempo: if speed == 0 { 6 } else { speed as u16 },
+        bpm: if tempo < 32 { 125 } else { tempo as u16 },
+    };
+
+    Ok(Module {
+        header,
+        pattern_table,
+        patterns,
+        instruments,
+    })
+}
+
+fn parse_pattern(r: &Reader, offset: usize, enabled: &[bool]) -> Pattern {
+    // A null pointer means an empty pattern of 64 rows
+    let num_rows = if offset == 0 {
+        64
+    } else {
+        (r.u16(offset + 2) as usize).clamp(1, MAX_ROWS)
+    };
+    let mut rows = vec![vec![Cell::default(); MAX_CHANNELS]; num_rows];
+    if offset == 0 {
+        return Pattern { rows };
+    }
+
+    let mut masks = [0u8; MAX_CHANNELS];
+    let mut previous = [ItCell::default(); MAX_CHANNELS];
+
+    let mut j = offset + 8;
+    let end = j + r.u16(offset) as usize;
+    let mut row = 0;
+    while row < num_rows && j < end {
+        let channel_variable = r.u8(j);
+        j += 1;
+        if channel_variable == 0 {
+            row += 1;
+            continue;
+        }
+
+        let channel = ((channel_variable - 1) & 63) as usize;
+        if channel_variable & 128 != 0 {
+            masks[channel] = r.u8(j);
+            j += 1;
+        }
+        let mask = masks[channel];
+        let previous = &mut previous[channel];
+
+        let (mut note, mut instrument, mut volume, mut effect) = (None, 0, None, None);
+        if mask & 1 != 0 {
+            previous.note = r.u8(j);
+            j += 1;
+        }
+        if mask & 2 != 0 {
+            previous.instrument = r.u8(j);
+            j += 1;
+        }
+        if mask & 4 != 0 {
+            previous.volume = r.u8(j);
+            j += 1;
+        }
+        if mask & 8 != 0 {
+            previous.command = r.u8(j);
+            previous.param = r.u8(j + 1);
+            j += 2;
+        }
+        // The low bits read new values, the high bits repeat the previous
+        // ones
+        if mask & (1 | 16) != 0 {
+            note = Some(previous.note);
+        }
+        if mask & (2 | 32) != 0 {
+            instrument = previous.instrument;
+        }
+        if mask & (4 | 64) != 0 {
+            volume = Some(previous.volume);
+        }
+        if mask & (8 | 128) != 0 {
+            effect = Some((previous.command, previous.param));
+        }
+
+        if !enabled[channel] {
+            continue;
+        }
+        rows[row][channel] = convert_cell(note, instrument, volume, effect);
+    }
+
+    Pattern { rows }
+}
+
+fn convert_cell(
+    note: Option<u8>,
+    instrument: u8,
+    volume: Option<u8>,
+    effect: Option<(u8, u8)>,
+) -> Cell {
+    let (effect_type, effect_param) = effect.map_or((0, 0), |(command, param)| {
+        convert_effect(command, param, true)
+    });
+    let mut cell = Cell {
+        note: 0,
+        instrument,
+        volume_column: 0,
+        effect_type,
+        effect_param,
+    };
+
+    if let Some(volume) = volume {
+        cell.volume_column = match volume {
+            // Set volume, fine volume slides up and down, volume slides up
+            // and down
+            0..=64 => 0x10 + volume,
+            65..=74 => 0x90 + (volume - 65),
+            75..=84 => 0x80 + (volume - 75),
+            85..=94 => 0x70 + (volume - 85),
+            95..=104 => 0x60 + (volume - 95),
+            // Set panning, from 0 to 64
+            128..=192 => 0xC0 + ((volume - 128) / 4).min(15),
+            // Tone portamento, with speeds from a table
+            193..=202 => {
+                const SPEEDS: [u8; 10] = [0, 1, 4, 8, 16, 32, 64, 96, 128, 255];
+                let speed = SPEEDS[(volume - 193) as usize];
+                0xF0 + if speed == 0 {
+                    0
+                } else {
+                    (speed / 16).clamp(1, 15)
+                }
+            }
+            // Vibrato depth
+            203..=212 => 0xB0 + (volume - 203),
+            _ => 0,
+        };
+
+        // Portamentos down and up have no place in the XM volume column,
+        // but may have one in the effect column
+        if (105..=124).contains(&volume) && cell.effect_type == 0 && cell.effect_param == 0 {
+            let (effect, amount) = if volume < 115 {
+                (2, volume - 105)
+            } else {
+                (1, volume - 115)
+            };
+            cell.effect_type = effect;
+            cell.effect_param = amount * 4;
+        }
+    }
+
+    match note {
+        Some(note @ 0..=119) => cell.note = convert_note(note.wrapping_sub(NOTE_OFFSET)),
+        Some(254) => note_cut(&mut cell),
+        // Note off and note fade
+        Some(_) => cell.note = Cell::KEY_OFF,
+        None => (),
+    }
+
+    cell
+}
+
+fn parse_sample(r: &Reader, offset: usize, decode_samples: bool) -> ItSample {
+    let flags = r.u8(offset + 18);
+    let convert = r.u8(offset + 46);
+    let sixteen_bit = flags & 2 != 0;
+    let compressed = flags & 8 != 0;
+
+    // Without this flag, there is no sample data
+    let length = if flags & 1 != 0 {
+        r.u32(offset + 48) as usize
+    } else {
+        0
+    };
+
+    // Stereo samples have the left channel first, which is the one played
+    let data_offset = r.u32(offset + 72) as usize;
+    let data = if compressed && decode_samples {
+        let it215 = convert & 4 != 0;
+        decompress(r.slice(data_offset, usize::MAX), length, sixteen_bit, it215)
+    } else {
+        let bytes_per_sample = if sixteen_bit { 2 } else { 1 };
+        let bytes = r.slice(data_offset, length.saturating_mul(bytes_per_sample));
+        decode(bytes, sixteen_bit, convert & 1 != 0, decode_samples)
+    };
+    let length = if decode_samples {
+        data.len()
+    } else if compressed {
+        length
+    } else {
+        let bytes_per_sample = if sixteen_bit { 2 } else { 1 };
+        r.slice(data_offset, length.saturating_mul(bytes_per_sample))
+            .len()
+            / bytes_per_sample
+    } as u32;
+
+    // The sustain loop is played as a normal loop, when there is no other
+    let (loop_offset, ping_pong) = if flags & 16 != 0 {
+        (52, flags & 64 != 0)
+    } else if flags & 32 != 0 {
+        (64, flags & 128 != 0)
+    } else {
+        (0, false)
+    };
+    let (mut loop_start, mut loop_length, mut loop_type) = (0, 0, LoopType::NoLoop);
+    if loop_offset != 0 {
+        let start = r.u32(offset + loop_offset).min(length);
+        let end = r.u32(offset + loop_offset + 4).clamp(start, length);
+        if end > start {
+            loop_start = start;
+            loop_length = end - start;
+            loop_type = if ping_pong {
+                LoopType::PingPong
+            } else {
+                LoopType::Forward
+            };
+        }
+    }
+
+    let default_panning = r.u8(offset + 47);
+    let (relative_note, finetune) = pitch_of_speed(r.u32(offset + 60));
+
+    // IT speed, depth (twice as fine as XM), rate (how fast the depth is
+    // reached) and waveform (sine, ramp down, square, random)
+    let (speed, depth, rate) = (r.u8(offset + 76), r.u8(offset + 77), r.u8(offset + 78));
+    let vibrato_type = [0, 2, 1, 0][(r.u8(offset + 79) & 3) as usize];
+    let vibrato_sweep = if rate == 0 {
+        0
+    } else {
+        (depth as u32 * 256 / rate as u32).min(255) as u8
+    };
+
+    ItSample {
+        sample: Sample {
+            name: r.string(offset + 20, 26),
+            loop_start,
+            loop_length,
+            loop_type,
+            volume: r.u8(offset + 19).min(64),
+            finetune,
+            panning: if default_panning & 128 != 0 {
+                panning(default_panning & 127)
+            } else {
+                128
+            },
+            relative_note,
+            data,
+        },
+        global_volume: r.u8(offset + 17).min(64),
+        vibrato: [
+            vibrato_type,
+            vibrato_sweep,
+            (depth / 2).min(15),
+            speed.min(63),
+        ],
+    }
+}
+
+/// Converts an IT panning, from 0 to 64, to XM.
+fn panning(value: u8) -> u8 {
+    (value.min(64) as u16 * 255 / 64) as u8
+}
+
+/// Scales the waveform of a sample by a volume, as XM has no sample or
+/// instrument global volumes.
+fn scaled(sample: &Sample, volume: u8, max: u8) -> Sample {
+    let mut sample = sample.clone();
+    if volume >= max {
+        return sample;
+    }
+
+    let scale = |v: i32| v * volume as i32 / max as i32;
+    match sample.data {
+        SampleBuffer::Bits8(ref mut data) => {
+            data.iter_mut().for_each(|v| *v = scale(*v as i32) as i8);
+        }
+        SampleBuffer::Bits16(ref mut data) => {
+            data.iter_mut().for_each(|v| *v = scale(*v as i32) as i16);
+        }
+    }
+    sample
+}
+
+fn parse_instrument(
+    r: &Reader,
+    offset: usize,
+    old_format: bool,
+    samples: &[ItSample],
+) -> Instrument {
+    // The global samples this instrument plays, in order of first use
+    let mut used: Vec<usize> = Vec::new();
+    let mut sample_of_notes = [u8::MAX; NUM_NOTES];
+    for (k, sample_of_note) in sample_of_notes.iter_mut().enumerate() {
+        // The keyboard has a note and a sample per IT note; only the sample
+        // is used
+        let it_note = k + NOTE_OFFSET as usize + 1;
+        let sample = r.u8(offset + 64 + 2 * it_note + 1) as usize;
+        if sample == 0 || sample > samples.len() {
+            continue;
+        }
+        let index = match used.iter().position(|&s| s == sample - 1) {
+            Some(index) => index,
+            None => {
+                used.push(sample - 1);
+                used.len() - 1
+            }
+        };
+        *sample_of_note = index as u8;
+    }
+
+    let (volume_envelope, panning_envelope, volume_fadeout, global_volume, default_panning);
+    if old_format {
+        volume_envelope = parse_old_envelope(r, offset);
+        panning_envelope = Envelope::default();
+        volume_fadeout = r.u16(offset + 24).saturating_mul(64);
+        global_volume = 128;
+        default_panning = None;
+    } else {
+        volume_envelope = parse_envelope(r, offset + 304, false);
+        panning_envelope = parse_envelope(r, offset + 386, true);
+        volume_fadeout = r.u16(offset + 20).saturating_mul(32);
+        global_volume = r.u8(offset + 24).min(128);
+        default_panning = match r.u8(offset + 25) {
+            value if value & 128 == 0 => Some(panning(value)),
+            _ => None,
+        };
+    }
+
+    let vibrato = used.first().map_or([0; 4], |&s| samples[s].vibrato);
+    Instrument {
+        name: r.string(offset + 32, 26),
+        instrument_type: 0,
+        sample_of_notes,
+        volume_envelope,
+        panning_envelope,
+        vibrato_type: vibrato[0],
+        vibrato_sweep: vibrato[1],
+        vibrato_depth: vibrato[2],
+        vibrato_rate: vibrato[3],
+        volume_fadeout,
+        samples: used
+            .iter()
+            .map(|&s| {
+                let it_sample = &samples[s];
+                let volume = it_sample.global_volume as u16 * global_volume as u16;
+                let mut sample = scaled(&it_sample.sample, (volume / 128) as u8, 64);
+                if let Some(panning) = default_panning {
+                    sample.panning = panning;
+                }
+                sample
+            })
+            .collect(),
+    }
+}
+
+fn parse_envelope(r: &Reader, offset: usize, panning: bool) -> Envelope {
+    let flags = r.u8(offset);
+    let num_nodes = (r.u8(offset + 1) as usize)
+        .min(MAX_ENVELOPE_NODES)
+        .min(MAX_ENVELOPE_POINTS);
+
+    let points = (0..num_nodes)
+        .map(|j| {
+            let value = r.u8(offset + 6 + 3 * j);
+            EnvelopePoint {
+                frame: r.u16(offset + 7 + 3 * j),
+                // Panning goes from -32 to 32
+                value: if panning {
+                    (value as i8 as i16 + 32).clamp(0, 64) as u16
+                } else {
+                    value.min(64) as u16
+                },
+            }
+        })
+        .collect();
+
+    // XM has a sustain point, IT has a sustain loop: hold its start
+    envelope(
+        points,
+        flags & 1 != 0,
+        (flags & 2 != 0, r.u8(offset + 2), r.u8(offset + 3)),
+        (flags & 4 != 0, r.u8(offset + 4)),
+    )
+}
+
+/// Parses the volume envelope of an instrument from before IT 2.0.
+fn parse_old_envelope(r: &Reader, offset: usize) -> Envelope {
+    let flags = r.u8(offset + 17);
+
+    // Tick and value of each node, until a tick of 255
+    let points = (0..MAX_ENVELOPE_NODES.min(MAX_ENVELOPE_POINTS))
+        .map(|j| (r.u8(offset + 504 + 2 * j), r.u8(offset + 505 + 2 * j)))
+        .take_while(|&(tick, _)| tick != 255)
+        .map(|(tick, value)| EnvelopePoint {
+            frame: tick as u16,
+            value: value.min(64) as u16,
+        })
+        .collect();
+
+    envelope(
+        points,
+        flags & 1 != 0,
+        (flags & 2 != 0, r.u8(offset + 18), r.u8(offset + 19)),
+        (flags & 4 != 0, r.u8(offset + 20)),
+    )
+}
+
+/// Makes an envelope, disabling the loop or sustain if their nodes were
+/// dropped.
+fn envelope(
+    points: Vec<EnvelopePoint>,
+    enabled: bool,
+    (loop_enabled, loop_start_point, loop_end_point): (bool, u8, u8),
+    (sustain_enabled, sustain_point): (bool, u8),
+) -> Envelope {
+    let num_points = points.len();
+    let exists = |point: u8| (point as usize) < num_points;
+
+    Envelope {
+        points,
+        sustain_point,
+        loop_start_point,
+        loop_end_point,
+        enabled: enabled && num_points > 0,
+        sustain_enabled: sustain_enabled && exists(sustain_point),
+        loop_enabled: loop_enabled && exists(loop_end_point) && loop_start_point <= loop_end_point,
+    }
+}
+
+/// Reads numbers of any width, least significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn read(&mut self, width: u32) -> u32 {
+        let mut value = 0;
+        for i in 0..width {
+            let byte = self.data.get(self.position / 8).copied().unwrap_or(0);
+            value |= ((byte >> (self.position % 8)) as u32 & 1) << i;
+            self.position += 1;
+        }
+        value
+    }
+}
+
+/// Decompresses a sample compressed by IT 2.14, or 2.15 when `it215` (which
+/// integrates twice).
+///
+/// The data is a series of blocks, each with its own length, holding up to
+/// 0x8000 8-bit or 0x4000 16-bit samples. Within a block, every value is
+/// stored with a varying bit width. Decompression stops at the first corrupt
+/// block, or at the end of the data.
+fn decompress(data: &[u8], length: usize, sixteen_bit: bool, it215: bool) -> SampleBuffer {
+    let (sample_bits, block_length, header_bits) = if sixteen_bit {
+        (16, 0x4000, 4)
+    } else {
+        (8, 0x8000, 3)
+    };
+    let max_width = sample_bits + 1;
+    // Keeps the low `bits` bits of a value, sign-extended
+    let wrap = |value: i32, bits: u32| (value << (32 - bits)) >> (32 - bits);
+
+    let mut values: Vec<i32> = Vec::new();
+    let mut offset = 0;
+    'blocks: while values.len() < length && offset + 2 <= data.len() {
+        let block_size = u16::from_le_bytes([data[offset], data[offset + 1]]) as usize;
+        let end = (offset + 2 + block_size).min(data.len());
+        let mut bits = BitReader {
+            data: &data[offset + 2..end],
+            position: 0,
+        };
+        offset = end;
+
+        let block_end = (values.len() + block_length).min(length);
+        let mut width = max_width;
+        let (mut d1, mut d2) = (0, 0);
+        while values.len() < block_end {
+            if width == 0 || width > max_width {
+                break 'blocks;
+            }
+            let value = bits.read(width);
+
+            // Some values change the width instead
+            if width < 7 {
+                if value == 1 << (width - 1) {
+                    let new_width = bits.read(header_bits) + 1;
+                    width = if new_width < width {
+                        new_width
+                    } else {
+                        new_width + 1
+                    };
+                    continue;
+                }
+            } else if width < max_width {
+                let border =
+                    (((1 << sample_bits) - 1) >> (max_width - width)) - (1 << (header_bits - 1));
+                if value > border && value <= border + (1 << header_bits) {
+                    let new_width = value - border;
+                    width = if new_width < width {
+                        new_width
+                    } else {
+                        new_width + 1
+                    };
+                    continue;
+                }
+            } else if value & (1 << sample_bits) != 0 {
+                width = (value + 1) & 0xFF;
+                continue;
+            }
+
+            let value = wrap(value as i32, width.min(sample_bits));
+            d1 = wrap(d1 + value, sample_bits);
+            d2 = wrap(d2 + d1, sample_bits);
+            values.push(if it215 { d2 } else { d1 });
+        }
+    }
+
+    if sixteen_bit {
+        SampleBuffer::Bits16(values.into_iter().map(|v| v as i16).collect())
+    } else {
+        SampleBuffer::Bits8(values.into_iter().map(|v| v as i8).collect())
+    }
+}
@@ -0,0 +1,190 @@
+//! ProTracker MOD loader.
+
+use super::{
+    module_error, single_sample_instrument, Cell, FrequencyType, Header, Module, Pattern, Reader,
+    Sample, SampleBuffer,
+};
+use crate::{LoopType, ModuleError, ModuleErrorKind};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const NUM_SAMPLES: usize = 31;
+const NUM_ROWS: usize = 64;
+const MAX_PATTERN_TABLE_LENGTH: usize = 128;
+
+/// Offset of the magic bytes, which tell the number of channels.
+const MAGIC_OFFSET: usize = 1080;
+
+/// Periods of the notes from C-0 to B-4, ProTracker's extended range.
+const PERIODS: [u16; 60] = [
+    1712, 1616, 1525, 1440, 1357, 1281, 1209, 1141, 1077, 1017, 961, 907, // C-0 to B-0
+    856, 808, 762, 720, 678, 640, 604, 570, 538, 508, 480, 453, // C-1 to B-1
+    428, 404, 381, 360, 339, 320, 302, 285, 269, 254, 240, 226, // C-2 to B-2
+    214, 202, 190, 180, 170, 160, 151, 143, 135, 127, 120, 113, // C-3 to B-3
+    107, 101, 95, 90, 85, 80, 76, 71, 67, 64, 60, 57, // C-4 to B-4
+];
+
+/// The XM note of `PERIODS[0]`. ProTracker's C-2 (period 428) plays at the
+/// same frequency as XM's C-4.
+const FIRST_NOTE: u8 = 25;
+
+/// Gets the number of channels from the magic bytes, or `None` if the data
+/// isn't a MOD module.
+pub(super) fn num_channels(data: &[u8]) -> Option<u16> {
+    let magic = data.get(MAGIC_OFFSET..MAGIC_OFFSET + 4)?;
+    let digit = |b: u8| {
+        if b.is_ascii_digit() {
+            Some((b - b'0') as u16)
+        } else {
+            None
+        }
+    };
+
+    let channels = match *magic {
+        [b'M', b'.', b'K', b'.'] | [b'M', b'!', b'K', b'!'] | [b'F', b'L', b'T', b'4'] => 4,
+        [b'F', b'L', b'T', b'8'] | [b'C', b'D', b'8', b'1'] | [b'O', b'K', b'T', b'A'] => 8,
+        [n, b'C', b'H', b'N'] | [b'T', b'D', b'Z', n] => digit(n)?,
+        [a, b, b'C', b'H'] | [a, b, b'C', b'N'] => digit(a)? * 10 + digit(b)?,
+        _ => return None,
+    };
+    if (1..=32).contains(&channels) {
+        Some(channels)
+    } else {
+        None
+    }
+}
+
+pub(super) fn load(data: &[u8], decode_samples: bool) -> Result<Module, ModuleError> {
+    let num_channels = match num_channels(data) {
+        Some(num_channels) => num_channels,
+        None => return Err(module_error(ModuleErrorKind::BadMagic, MAGIC_OFFSET)),
+    };
+    let r = Reader { data };
+
+    let song_length = (r.u8(950) as usize).clamp(1, MAX_PATTERN_TABLE_LENGTH);
+    let mut pattern_table = vec![0; song_length];
+    r.bytes(952, &mut pattern_table);
+
+    // ProTracker counts the patterns of the whole table, past the song length
+    let num_patterns = (0..MAX_PATTERN_TABLE_LENGTH)
+        .map(|i| r.u8(952 + i) as usize + 1)
+        .max()
+        .unwrap_or(1);
+
+    let restart_position = match r.u8(951) as usize {
+        restart if restart < song_length => restart as u16,
+        _ => 0,
+    };
+
+    let header = Header {
+        name: r.string(0, 20),
+        tracker_name: b"ProTracker".to_vec(),
+        version: 0x0104,
+        restart_position,
+        num_channels,
+        frequency_type: FrequencyType::Amiga,
+        tempo: 6,
+        bpm: 125,
+    };
+
+    let mut offset = MAGIC_OFFSET + 4;
+    let num_channels = num_channels as usize;
+    let pattern_size = NUM_ROWS * num_channels * 4;
+
+    let mut patterns = Vec::with_capacity(num_patterns);
+    for pattern in 0..num_patterns {
+        if offset + pattern_size > data.len() {
+            let kind = ModuleErrorKind::PatternSizeMismatch {
+                pattern: pattern as u16,
+            };
+            return Err(module_error(kind, offset));
+        }
+
+        let rows = (0..NUM_ROWS)
+            .map(|row| {
+                (0..num_channels)
+                    .map(|channel| parse_cell(&r, offset + 4 * (row * num_channels + channel)))
+                    .collect()
+            })
+            .collect();
+        patterns.push(Pattern { rows });
+        offset += pattern_size;
+    }
+
+    let mut instruments = Vec::with_capacity(NUM_SAMPLES);
+    for i in 0..NUM_SAMPLES {
+        let header = 20 + 30 * i;
+        let byte_length = r.u16_be(header + 22) as usize * 2;
+
+        // Modules whose last sample is cut short are common; the sample is
+        // shortened accordingly
+        let bytes = r.slice(offset, byte_length);
+        let length = bytes.len() as u32;
+        offset += byte_length;
+
+        let loop_start = (r.u16_be(header + 26) as u32 * 2).min(length);
+        let loop_length = (r.u16_be(header + 28) as u32 * 2).min(length - loop_start);
+
+        let sample = Sample {
+            name: r.string(header, 22),
+            loop_start,
+            loop_length,
+            // A loop of a single word means no loop
+            loop_type: if loop_length > 2 {
+                LoopType::Forward
+            } else {
+                LoopType::NoLoop
+            },
+            volume: r.u8(header + 25).min(64),
+            // A signed nibble, in 1/8ths of a semitone
+            finetune: (r.u8(header + 24) << 4) as i8,
+            panning: 128,
+            relative_note: 0,
+            data: SampleBuffer::Bits8(if decode_samples {
+                bytes.iter().map(|&b| b as i8).collect()
+            } else {
+                Vec::new()
+            }),
+        };
+        instruments.push(single_sample_instrument(sample));
+    }
+
+    Ok(Module {
+        header,
+        pattern_table,
+        patterns,
+        instruments,
+    })
+}
+
+fn parse_cell(r: &Reader, offset: usize) -> Cell {
+    let (a, b, c, d) = (
+        r.u8(offset),
+        r.u8(offset + 1),
+        r.u8(offset + 2),
+        r.u8(offset + 3),
+    );
+    let period = ((a & 0x0F) as u16) << 8 | b as u16;
+
+    // MOD effects are a subset of XM effects
+    Cell {
+        note: note_of_period(period),
+        instrument: (a & 0xF0) | (c >> 4),
+        volume_column: 0,
+        effect_type: c & 0x0F,
+        effect_param: d,
+    }
+}
+
+/// Gets the XM note closest to a period, or 0 for no note.
+fn note_of_period(period: u16) -> u8 {
+    if period == 0 {
+        return 0;
+    }
+
+    PERIODS
+        .iter()
+        .enumerate()
+        .min_by_key(|&(_, &p)| (p as i32 - period as i32).abs())
+        .map_or(0, |(i, _)| FIRST_NOTE + i as u8)
+}
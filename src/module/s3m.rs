@@ -0,0 +1,448 @@
+//! Scream Tracker 3 S3M loader.
+//!
+//! Also has the conversions shared with the IT loader, since IT effects and
+//! order lists work like S3M ones.
+
+use super::{
+    module_error, single_sample_instrument, Cell, FrequencyType, Header, Module, Pattern, Reader,
+    Sample, SampleBuffer, MAX_PATTERN_TABLE_LENGTH, NUM_NOTES,
+};
+use crate::math::{log2f, roundf};
+use crate::{LoopType, ModuleError, ModuleErrorKind};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const NUM_ROWS: usize = 64;
+const MAX_CHANNELS: usize = 32;
+
+/// End of the order list.
+const ORDER_END: u8 = 255;
+/// Skip marker in the order list.
+const ORDER_SKIP: u8 = 254;
+
+// XM effects
+const EFFECT_ARPEGGIO: u8 = 0x0;
+const EFFECT_PORTAMENTO_UP: u8 = 0x1;
+const EFFECT_PORTAMENTO_DOWN: u8 = 0x2;
+const EFFECT_TONE_PORTAMENTO: u8 = 0x3;
+const EFFECT_VIBRATO: u8 = 0x4;
+const EFFECT_TONE_PORTAMENTO_VOLUME_SLIDE: u8 = 0x5;
+const EFFECT_VIBRATO_VOLUME_SLIDE: u8 = 0x6;
+const EFFECT_TREMOLO: u8 = 0x7;
+const EFFECT_SET_PANNING: u8 = 0x8;
+const EFFECT_SAMPLE_OFFSET: u8 = 0x9;
+const EFFECT_VOLUME_SLIDE: u8 = 0xA;
+const EFFECT_POSITION_JUMP: u8 = 0xB;
+const EFFECT_PATTERN_BREAK: u8 = 0xD;
+const EFFECT_EXTENDED: u8 = 0xE;
+const EFFECT_SET_TEMPO_BPM: u8 = 0xF;
+const EFFECT_SET_GLOBAL_VOLUME: u8 = 16; // G
+const EFFECT_GLOBAL_VOLUME_SLIDE: u8 = 17; // H
+const EFFECT_PANNING_SLIDE: u8 = 25; // P
+const EFFECT_MULTI_RETRIG: u8 = 27; // R
+const EFFECT_TREMOR: u8 = 29; // T
+const EFFECT_EXTRA_FINE_PORTAMENTO: u8 = 33; // X
+
+pub(super) fn load(data: &[u8], decode_samples: bool) -> Result<Module, ModuleError> {
+    if data.len() < 96 {
+        return Err(module_error(ModuleErrorKind::TruncatedHeader, 0));
+    }
+    let r = Reader { data };
+
+    let num_orders = r.u16(32) as usize;
+    let num_instruments = r.u16(34) as usize;
+    let num_patterns = (r.u16(36) as usize).min(MAX_PATTERN_TABLE_LENGTH);
+    let signed_samples = r.u16(42) == 1;
+
+    let mut orders = vec![0; num_orders];
+    r.bytes(96, &mut orders);
+    let (pattern_table, positions) = convert_orders(&orders);
+
+    // Parapointers, in 16-byte units
+    let pointers = 96 + num_orders;
+    let instrument_pointer = |i: usize| r.u16(pointers + 2 * i) as usize * 16;
+    let pattern_pointer = |i: usize| r.u16(pointers + 2 * (num_instruments + i)) as usize * 16;
+
+    // Channels 0 to 15 are PCM channels, the others are AdLib or unused.
+    // Channel numbers are kept, so disabled channels are left empty.
+    let enabled: Vec<bool> = (0..MAX_CHANNELS).map(|c| r.u8(64 + c) < 16).collect();
+    let num_channels = enabled.iter().rposition(|&e| e).map_or(1, |c| c + 1);
+
+    let mut patterns: Vec<Pattern> = (0..num_patterns)
+        .map(|p| parse_pattern(&r, pattern_pointer(p), &enabled[..num_channels]))
+        .collect();
+    add_missing_patterns(&mut patterns, &pattern_table, num_channels, NUM_ROWS);
+    remap_jumps(&mut patterns, &positions, pattern_table.len());
+
+    let instruments = (0..num_instruments)
+        .map(|i| {
+            let sample = parse_sample(&r, instrument_pointer(i), signed_samples, decode_samples);
+            single_sample_instrument(sample)
+        })
+        .collect();
+
+    let speed = r.u8(49);
+    let tempo = r.u8(50);
+    let header = Header {
+        name: r.string(0, 28),
+        tracker_name: b"Scream Tracker 3".to_vec(),
+        version: 0x0104,
+        restart_position: 0,
+        num_channels: num_channels as u16,
+        frequency_type: FrequencyType::Amiga,
+        tempo: if speed == 0 { 6 } else { speed as u16 },
+        bpm: if tempo < 32 { 125 } else { tempo as u16 },
+    };
+
+    Ok(Module {
+        header,
+        pattern_table,
+        patterns,
+        instruments,
+    })
+}
+
+fn parse_pattern(r: &Reader, offset: usize, enabled: &[bool]) -> Pattern {
+    let mut rows = vec![vec![Cell::default(); enabled.len()]; NUM_ROWS];
+
+    // A null parapointer means an empty pattern
+    if offset == 0 {
+        return Pattern { rows };
+    }
+
+    // Skip the packed length. Every row ends with a 0, as does the data
+    // past the end of the module.
+    let mut j = offset + 2;
+    for row in rows.iter_mut() {
+        loop {
+            let what = r.u8(j);
+            j += 1;
+            if what == 0 {
+                break;
+            }
+
+            let (mut note, mut instrument, mut volume) = (None, 0, None);
+            let (mut command, mut param) = (0, 0);
+            if what & 32 != 0 {
+                note = Some(r.u8(j));
+                instrument = r.u8(j + 1);
+                j += 2;
+            }
+            if what & 64 != 0 {
+                volume = Some(r.u8(j));
+                j += 1;
+            }
+            if what & 128 != 0 {
+                command = r.u8(j);
+                param = r.u8(j + 1);
+                j += 2;
+            }
+
+            let channel = (what & 31) as usize;
+            if enabled.get(channel) != Some(&true) {
+                continue;
+            }
+
+            let (effect_type, effect_param) = convert_effect(command, param, false);
+            let mut cell = Cell {
+                note: 0,
+                instrument,
+                volume_column: volume.map_or(0, |v| 0x10 + v.min(64)),
+                effect_type,
+                effect_param,
+            };
+            match note {
+                Some(254) => note_cut(&mut cell),
+                Some(note) if note & 0x0F < 12 => {
+                    // Octave and semitone. C-4 is middle C, as in XM.
+                    cell.note = convert_note((note >> 4) * 12 + (note & 0x0F) + 1);
+                }
+                _ => (),
+            }
+            row[channel] = cell;
+        }
+    }
+
+    Pattern { rows }
+}
+
+fn parse_sample(r: &Reader, offset: usize, signed: bool, decode_samples: bool) -> Sample {
+    let mut sample = Sample {
+        name: r.string(offset + 48, 28),
+        loop_start: 0,
+        loop_length: 0,
+        loop_type: LoopType::NoLoop,
+        volume: r.u8(offset + 28).min(64),
+        finetune: 0,
+        panning: 128,
+        relative_note: 0,
+        data: SampleBuffer::Bits8(Vec::new()),
+    };
+
+    // Other types are AdLib instruments, which play nothing here
+    if offset == 0 || r.u8(offset) != 1 {
+        return sample;
+    }
+
+    let flags = r.u8(offset + 31);
+    let sixteen_bit = flags & 4 != 0;
+    let bytes_per_sample = if sixteen_bit { 2 } else { 1 };
+
+    // Stereo samples have the left channel first, which is the one played
+    let data_offset = ((r.u8(offset + 13) as usize) << 16 | r.u16(offset + 14) as usize) * 16;
+    let byte_length = (r.u32(offset + 16) as usize).saturating_mul(bytes_per_sample);
+    let bytes = r.slice(data_offset, byte_length);
+    let length = (bytes.len() / bytes_per_sample) as u32;
+
+    sample.data = decode(bytes, sixteen_bit, signed, decode_samples);
+
+    let loop_start = r.u32(offset + 20).min(length);
+    let loop_end = r.u32(offset + 24).clamp(loop_start, length);
+    if flags & 1 != 0 && loop_end > loop_start {
+        sample.loop_start = loop_start;
+        sample.loop_length = loop_end - loop_start;
+        sample.loop_type = LoopType::Forward;
+    }
+
+    let (relative_note, finetune) = pitch_of_speed(r.u32(offset + 32));
+    sample.relative_note = relative_note;
+    sample.finetune = finetune;
+
+    sample
+}
+
+/// Decodes little-endian PCM data, signed or unsigned.
+pub(super) fn decode(bytes: &[u8], sixteen_bit: bool, signed: bool, decode: bool) -> SampleBuffer {
+    if !decode {
+        return if sixteen_bit {
+            SampleBuffer::Bits16(Vec::new())
+        } else {
+            SampleBuffer::Bits8(Vec::new())
+        };
+    }
+
+    if sixteen_bit {
+        let flip = if signed { 0 } else { 0x8000 };
+        SampleBuffer::Bits16(
+            bytes
+                .chunks_exact(2)
+                .map(|b| (u16::from_le_bytes([b[0], b[1]]) ^ flip) as i16)
+                .collect(),
+        )
+    } else {
+        let flip = if signed { 0 } else { 0x80 };
+        SampleBuffer::Bits8(bytes.iter().map(|&b| (b ^ flip) as i8).collect())
+    }
+}
+
+/// Gets the relative note and finetune of a sample that plays middle C
+/// (C-4 in XM and S3M, C-5 in IT) at `speed` Hz.
+pub(super) fn pitch_of_speed(speed: u32) -> (i8, i8) {
+    if speed == 0 {
+        return (0, 0);
+    }
+
+    // In 1/128ths of a semitone, from 8363 Hz
+    let fine = roundf(log2f(speed as f32 / 8363.0) * 12.0 * 128.0) as i32;
+    let relative_note = (fine + 64).div_euclid(128);
+    let finetune = fine - relative_note * 128;
+    (relative_note.clamp(-96, 95) as i8, finetune as i8)
+}
+
+/// Keeps a note if it is in the XM range.
+pub(super) fn convert_note(note: u8) -> u8 {
+    if (1..=NUM_NOTES as u8).contains(&note) {
+        note
+    } else {
+        0
+    }
+}
+
+/// Cuts the note of a cell. XM has no note cut, but setting the volume to 0
+/// is the same, and a key off is the closest when the volume column is
+/// taken.
+pub(super) fn note_cut(cell: &mut Cell) {
+    if cell.volume_column == 0 {
+        cell.volume_column = 0x10;
+    } else {
+        cell.note = Cell::KEY_OFF;
+    }
+}
+
+/// Converts an S3M or IT order list: it ends at the first 255, and skip
+/// markers are removed.
+///
+/// # Return
+/// The pattern table, and for each entry of the list, the index in the
+/// pattern table at which playback goes on (for position jumps)
+pub(super) fn convert_orders(orders: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut pattern_table = Vec::new();
+    let mut positions = Vec::with_capacity(orders.len());
+
+    for &order in orders {
+        positions.push(pattern_table.len().min(u8::MAX as usize) as u8);
+        match order {
+            ORDER_END => break,
+            ORDER_SKIP => (),
+            pattern if pattern_table.len() < MAX_PATTERN_TABLE_LENGTH => {
+                pattern_table.push(pattern)
+            }
+            _ => (),
+        }
+    }
+
+    if pattern_table.is_empty() {
+        pattern_table.push(0);
+    }
+    (pattern_table, positions)
+}
+
+/// Adds empty patterns for the entries of the pattern table that refer to
+/// patterns the module doesn't have.
+pub(super) fn add_missing_patterns(
+    patterns: &mut Vec<Pattern>,
+    pattern_table: &[u8],
+    num_channels: usize,
+    num_rows: usize,
+) {
+    let needed = pattern_table
+        .iter()
+        .map(|&p| p as usize + 1)
+        .max()
+        .unwrap_or(0);
+    while patterns.len() < needed {
+        patterns.push(Pattern {
+            rows: vec![vec![Cell::default(); num_channels]; num_rows],
+        });
+    }
+}
+
+/// Makes position jumps refer to the pattern table rather than to the
+/// order list.
+pub(super) fn remap_jumps(patterns: &mut [Pattern], positions: &[u8], length: usize) {
+    let end = length.min(u8::MAX as usize) as u8;
+    for cell in patterns
+        .iter_mut()
+        .flat_map(|p| p.rows.iter_mut().flatten())
+    {
+        if cell.effect_type == EFFECT_POSITION_JUMP {
+            let position = positions.get(cell.effect_param as usize);
+            cell.effect_param = position.copied().unwrap_or(end);
+        }
+    }
+}
+
+/// Converts an S3M or IT effect (`command` 1 is A) to an XM effect type
+/// and parameter. Effects without an XM equivalent are dropped.
+pub(super) fn convert_effect(command: u8, param: u8, it: bool) -> (u8, u8) {
+    let none = (0, 0);
+
+    match command {
+        // A: set speed
+        1 if param > 0 => (EFFECT_SET_TEMPO_BPM, param.min(0x1F)),
+        // B: position jump
+        2 => (EFFECT_POSITION_JUMP, param),
+        // C: pattern break. XM has the row in BCD, as S3M; IT has it in
+        // binary.
+        3 if it => (
+            EFFECT_PATTERN_BREAK,
+            if param < 160 {
+                ((param / 10) << 4) | (param % 10)
+            } else {
+                0
+            },
+        ),
+        3 => (EFFECT_PATTERN_BREAK, param),
+        // D: volume slide
+        4 => volume_slide(param),
+        // E, F: portamento down, up
+        5 => portamento(EFFECT_PORTAMENTO_DOWN, param),
+        6 => portamento(EFFECT_PORTAMENTO_UP, param),
+        // G: tone portamento
+        7 => (EFFECT_TONE_PORTAMENTO, param),
+        // H: vibrato
+        8 => (EFFECT_VIBRATO, param),
+        // I: tremor
+        9 => (EFFECT_TREMOR, param),
+        // J: arpeggio
+        10 => (EFFECT_ARPEGGIO, param),
+        // K, L: vibrato or tone portamento, and volume slide. XM has no fine
+        // slides there.
+        11 | 12 => {
+            let effect = if command == 11 {
+                EFFECT_VIBRATO_VOLUME_SLIDE
+            } else {
+                EFFECT_TONE_PORTAMENTO_VOLUME_SLIDE
+            };
+            match volume_slide(param) {
+                (EFFECT_VOLUME_SLIDE, param) => (effect, param),
+                _ => (effect, 0),
+            }
+        }
+        // O: sample offset
+        15 => (EFFECT_SAMPLE_OFFSET, param),
+        // P: panning slide, in the other direction than XM's
+        16 if it => (EFFECT_PANNING_SLIDE, param.rotate_left(4)),
+        // Q: retrigger with volume change
+        17 => (EFFECT_MULTI_RETRIG, param),
+        // R: tremolo
+        18 => (EFFECT_TREMOLO, param),
+        // S: special
+        19 => special(param),
+        // T: set tempo (lower values slide the tempo)
+        20 if param >= 0x20 => (EFFECT_SET_TEMPO_BPM, param),
+        // U: fine vibrato, at a quarter of the depth
+        21 => (EFFECT_VIBRATO, (param & 0xF0) | (param & 0x0F).div_ceil(4)),
+        // V: set global volume, from 0 to 128 in IT
+        22 if it => (EFFECT_SET_GLOBAL_VOLUME, (param / 2).min(64)),
+        22 => (EFFECT_SET_GLOBAL_VOLUME, param.min(64)),
+        // W: global volume slide
+        23 if it => (EFFECT_GLOBAL_VOLUME_SLIDE, param),
+        // X: set panning, from 0 to 128 in S3M
+        24 if it => (EFFECT_SET_PANNING, param),
+        24 => (
+            EFFECT_SET_PANNING,
+            (param.min(0x80) as u16 * 255 / 128) as u8,
+        ),
+        _ => none,
+    }
+}
+
+fn volume_slide(param: u8) -> (u8, u8) {
+    match (param >> 4, param & 0x0F) {
+        // DF0 and D0F are normal slides
+        (0xF, 0) | (0, 0xF) => (EFFECT_VOLUME_SLIDE, param),
+        (0xF, y) => (EFFECT_EXTENDED, 0xB0 | y),
+        (x, 0xF) => (EFFECT_EXTENDED, 0xA0 | x),
+        _ => (EFFECT_VOLUME_SLIDE, param),
+    }
+}
+
+fn portamento(effect: u8, param: u8) -> (u8, u8) {
+    // 1 for up, 2 for down in the fine and extra fine effects too
+    let direction = effect << 4;
+    match param >> 4 {
+        0xF => (EFFECT_EXTENDED, direction | (param & 0x0F)),
+        0xE => (EFFECT_EXTRA_FINE_PORTAMENTO, direction | (param & 0x0F)),
+        _ => (effect, param),
+    }
+}
+
+fn special(param: u8) -> (u8, u8) {
+    let x = param & 0x0F;
+    match param >> 4 {
+        // Glissando, finetune, vibrato and tremolo waveforms
+        0x1 => (EFFECT_EXTENDED, 0x30 | x),
+        0x2 => (EFFECT_EXTENDED, 0x50 | x),
+        0x3 => (EFFECT_EXTENDED, 0x40 | x),
+        0x4 => (EFFECT_EXTENDED, 0x70 | x),
+        // Set panning
+        0x8 => (EFFECT_SET_PANNING, x * 17),
+        // Pattern loop, note cut, note delay, pattern delay
+        0xB => (EFFECT_EXTENDED, 0x60 | x),
+        0xC => (EFFECT_EXTENDED, 0xC0 | x),
+        0xD => (EFFECT_EXTENDED, 0xD0 | x),
+        0xE => (EFFECT_EXTENDED, 0xE0 | x),
+        _ => (0, 0),
+    }
+}
@@ -0,0 +1,184 @@
+//! Integer PCM output.
+//!
+//! libxm mixes in floating point. These conversions clamp to full scale
+//! (`[-1.0, 1.0]`), and can optionally add TPDF (triangular probability
+//! density function) dither before quantizing, which trades the distortion
+//! of truncation for a low level of white noise.
+//!
+//! # Example
+//! ```no_run
+//! use libxm::pcm::Dither;
+//! use libxm::XMContext;
+//!
+//! fn audio_callback(xm: &mut XMContext, buffer: &mut [i16]) {
+//!     xm.generate_samples_i16(buffer, Dither::Tpdf);
+//! }
+//! ```
+
+use crate::math::{round, roundf};
+use crate::XMContext;
+
+/// Number of stereo frames converted at a time.
+const CHUNK_FRAMES: usize = 256;
+
+/// A type of audio sample that `XMContext::generate_samples_as()` can write.
+pub trait Sample: Copy {
+    /// Converts a floating point sample, where `[-1.0, 1.0]` is full scale.
+    /// Values out of this range are clamped.
+    fn from_f32(sample: f32) -> Self;
+
+    /// Like `from_f32()`, adding `noise` (in units of the least significant
+    /// bit) before quantizing. Floating point samples ignore the noise.
+    #[inline]
+    fn from_f32_dithered(sample: f32, noise: f32) -> Self {
+        let _ = noise;
+        Self::from_f32(sample)
+    }
+
+    /// Gets the buffer as `f32`, if `Self` is `f32`, so that libxm can write
+    /// to it directly.
+    #[doc(hidden)]
+    #[inline]
+    fn as_f32_slice(buffer: &mut [Self]) -> Option<&mut [f32]> {
+        let _ = buffer;
+        None
+    }
+}
+
+impl Sample for f32 {
+    #[inline]
+    fn from_f32(sample: f32) -> f32 {
+        sample
+    }
+
+    #[inline]
+    fn as_f32_slice(buffer: &mut [f32]) -> Option<&mut [f32]> {
+        Some(buffer)
+    }
+}
+
+impl Sample for i16 {
+    #[inline]
+    fn from_f32(sample: f32) -> i16 {
+        Self::from_f32_dithered(sample, 0.0)
+    }
+
+    #[inline]
+    fn from_f32_dithered(sample: f32, noise: f32) -> i16 {
+        let v = sample.clamp(-1.0, 1.0) * 32767.0 + noise;
+        roundf(v).clamp(-32768.0, 32767.0) as i16
+    }
+}
+
+impl Sample for i32 {
+    #[inline]
+    fn from_f32(sample: f32) -> i32 {
+        Self::from_f32_dithered(sample, 0.0)
+    }
+
+    #[inline]
+    fn from_f32_dithered(sample: f32, noise: f32) -> i32 {
+        // f32 can't represent every i32
+        let v = sample.clamp(-1.0, 1.0) as f64 * 2147483647.0 + noise as f64;
+        round(v).clamp(-2147483648.0, 2147483647.0) as i32
+    }
+}
+
+/// Unsigned, centered on 128, as in 8-bit WAV files.
+impl Sample for u8 {
+    #[inline]
+    fn from_f32(sample: f32) -> u8 {
+        Self::from_f32_dithered(sample, 0.0)
+    }
+
+    #[inline]
+    fn from_f32_dithered(sample: f32, noise: f32) -> u8 {
+        let v = 128.0 + sample.clamp(-1.0, 1.0) * 127.0 + noise;
+        roundf(v).clamp(0.0, 255.0) as u8
+    }
+}
+
+/// How to quantize samples to integers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Dither {
+    /// Round to the nearest value
+    None,
+    /// Add triangular noise, with a peak amplitude of one least significant
+    /// bit, before rounding
+    Tpdf,
+}
+
+/// A small, fast random number generator for dither noise (xorshift32).
+#[derive(Copy, Clone)]
+pub(crate) struct DitherState(u32);
+
+impl DitherState {
+    pub(crate) fn new() -> DitherState {
+        DitherState(0x2545_f491)
+    }
+
+    /// Returns a random value in `[-0.5, 0.5)`.
+    fn next(&mut self) -> f32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        (x >> 8) as f32 / (1 << 24) as f32 - 0.5
+    }
+
+    /// Returns TPDF noise, in `(-1.0, 1.0)`.
+    fn tpdf(&mut self) -> f32 {
+        self.next() + self.next()
+    }
+}
+
+impl XMContext {
+    /// Plays the module and puts 16-bit sound samples in the specified
+    /// output buffer, like `generate_samples()`.
+    ///
+    /// # Return
+    /// The number of samples written to the output buffer
+    pub fn generate_samples_i16(&mut self, output: &mut [i16], dither: Dither) -> usize {
+        self.generate_samples_dithered(output, dither)
+    }
+
+    /// Plays the module and puts 32-bit sound samples in the specified
+    /// output buffer, like `generate_samples()`.
+    ///
+    /// # Return
+    /// The number of samples written to the output buffer
+    pub fn generate_samples_i32(&mut self, output: &mut [i32], dither: Dither) -> usize {
+        self.generate_samples_dithered(output, dither)
+    }
+
+    /// Plays the module, converting the samples to `T`.
+    pub(crate) fn generate_samples_dithered<T: Sample>(
+        &mut self,
+        output: &mut [T],
+        dither: Dither,
+    ) -> usize {
+        assert!(output.len().is_multiple_of(2));
+
+        let mut buffer = [0.0f32; CHUNK_FRAMES * 2];
+        for chunk in output.chunks_mut(buffer.len()) {
+            let samples = &mut buffer[..chunk.len()];
+            self.generate_samples(samples);
+
+            match dither {
+                Dither::None => {
+                    for (out, &sample) in chunk.iter_mut().zip(samples.iter()) {
+                        *out = T::from_f32(sample);
+                    }
+                }
+                Dither::Tpdf => {
+                    for (out, &sample) in chunk.iter_mut().zip(samples.iter()) {
+                        *out = T::from_f32_dithered(sample, self.dither.tpdf());
+                    }
+                }
+            }
+        }
+
+        output.len()
+    }
+}
@@ -0,0 +1,264 @@
+//! Offline rendering of modules to WAV files.
+//!
+//! # Example
+//! ```no_run
+//! use libxm::render::{render_to_wav, RenderOptions, SampleFormat};
+//! use libxm::XMContext;
+//! use std::fs::File;
+//! use std::io::{BufWriter, Read};
+//! use std::time::Duration;
+//!
+//! let mut data = Vec::new();
+//! File::open("song.xm").unwrap().read_to_end(&mut data).unwrap();
+//! let mut xm = XMContext::new(&data, 48000).unwrap();
+//!
+//! let options = RenderOptions {
+//!     format: SampleFormat::I16,
+//!     max_loops: 2,
+//!     fade_out: Duration::from_secs(5),
+//!     trim_silence: true,
+//! };
+//! let output = BufWriter::new(File::create("song.wav").unwrap());
+//! render_to_wav(&mut xm, output, options).unwrap();
+//! ```
+
+use crate::pcm::Sample;
+use crate::XMContext;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+/// Number of stereo frames written at a time.
+const CHUNK_FRAMES: usize = 256;
+
+/// Samples quieter than this (about -80 dB) count as silence when trimming.
+const SILENCE_THRESHOLD: f32 = 1.0e-4;
+
+/// The sample format of the rendered WAV file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    /// 32-bit IEEE float
+    F32,
+    /// 16-bit signed integer PCM
+    I16,
+    /// 24-bit signed integer PCM
+    I24,
+}
+
+impl SampleFormat {
+    fn bytes_per_sample(self) -> u16 {
+        match self {
+            SampleFormat::F32 => 4,
+            SampleFormat::I16 => 2,
+            SampleFormat::I24 => 3,
+        }
+    }
+}
+
+/// Options for `render_to_wav()`.
+#[derive(Copy, Clone, Debug)]
+pub struct RenderOptions {
+    /// Sample format of the output
+    pub format: SampleFormat,
+    /// Number of times the song is played. Must be at least 1.
+    pub max_loops: u8,
+    /// Once the song has played `max_loops` times, keep playing for this
+    /// long while fading out. Zero stops right at the loop point.
+    pub fade_out: Duration,
+    /// Whether to drop trailing silence at the end of the render
+    pub trim_silence: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            format: SampleFormat::I16,
+            max_loops: 1,
+            fade_out: Duration::from_secs(0),
+            trim_silence: false,
+        }
+    }
+}
+
+/// Renders a module to a stereo WAV file, at the rate of the context.
+///
+/// The song is played until `loop_count()` reaches `options.max_loops`,
+/// followed by the fade out, if any. The context's maximum loop count is
+/// overwritten.
+///
+/// The writer must be seekable, so that the header can be filled in once
+/// the length of the render is known.
+///
+/// # Return
+/// The number of stereo frames written
+pub fn render_to_wav<W: Write + Seek>(
+    ctx: &mut XMContext,
+    mut writer: W,
+    options: RenderOptions,
+) -> io::Result<u64> {
+    assert!(options.max_loops >= 1);
+
+    let rate = ctx.rate();
+    let fade_frames = (options.fade_out.as_secs_f64() * rate as f64).round() as u64;
+
+    // Keep playing past the last loop if there's a fade out to render
+    ctx.set_max_loop_count(if fade_frames > 0 {
+        0
+    } else {
+        options.max_loops
+    });
+
+    let start = writer.stream_position()?;
+    write_header(&mut writer, rate, options.format, 0)?;
+
+    let mut buffer = [0.0; CHUNK_FRAMES * 2];
+    let mut written = 0;
+    // Quiet frames that are only written if something audible follows them
+    let mut pending_silence: Vec<f32> = Vec::new();
+    // The frames left to fade out, once the song has ended
+    let mut fade_remaining = None;
+    let mut finished = false;
+
+    while !finished {
+        // The loop count changes within a chunk, so it is checked after
+        // every frame
+        let mut frames = 0;
+        while frames < CHUNK_FRAMES && !finished {
+            let frame = &mut buffer[2 * frames..2 * frames + 2];
+            ctx.generate_samples(frame);
+
+            // The frame the song ends in is already silent, or the first
+            // one of the fade out
+            if fade_remaining.is_none() && ctx.loop_count() >= options.max_loops {
+                if fade_frames == 0 {
+                    finished = true;
+                    break;
+                }
+                fade_remaining = Some(fade_frames);
+            }
+            if let Some(ref mut remaining) = fade_remaining {
+                let gain = *remaining as f32 / fade_frames as f32;
+                frame[0] *= gain;
+                frame[1] *= gain;
+                *remaining -= 1;
+                finished = *remaining == 0;
+            }
+
+            frames += 1;
+        }
+
+        let chunk = &buffer[..frames * 2];
+        if options.trim_silence {
+            for frame in chunk.chunks(2) {
+                pending_silence.extend_from_slice(frame);
+                if frame.iter().any(|s| s.abs() >= SILENCE_THRESHOLD) {
+                    let frames = pending_silence.len() as u64 / 2;
+                    check_length(options.format, written + frames)?;
+                    write_samples(&mut writer, &pending_silence, options.format)?;
+                    written += frames;
+                    pending_silence.clear();
+                }
+            }
+        } else {
+            check_length(options.format, written + frames as u64)?;
+            write_samples(&mut writer, chunk, options.format)?;
+            written += frames as u64;
+        }
+    }
+
+    let end = writer.stream_position()?;
+    writer.seek(SeekFrom::Start(start))?;
+    write_header(&mut writer, rate, options.format, written)?;
+    writer.seek(SeekFrom::Start(end))?;
+    writer.flush()?;
+
+    Ok(written)
+}
+
+fn write_header<W: Write>(
+    w: &mut W,
+    rate: u32,
+    format: SampleFormat,
+    frames: u64,
+) -> io::Result<()> {
+    const CHANNELS: u16 = 2;
+
+    let block_align = CHANNELS * format.bytes_per_sample();
+    let data_size = frames * block_align as u64;
+    let riff_size = check_length(format, frames)?;
+    let (format_tag, fmt_size, fact_size) = chunk_sizes(format);
+
+    w.write_all(b"RIFF")?;
+    w.write_all(&riff_size.to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&fmt_size.to_le_bytes())?;
+    w.write_all(&format_tag.to_le_bytes())?;
+    w.write_all(&CHANNELS.to_le_bytes())?;
+    w.write_all(&rate.to_le_bytes())?;
+    w.write_all(&(rate * block_align as u32).to_le_bytes())?;
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&(format.bytes_per_sample() * 8).to_le_bytes())?;
+    if fmt_size == 18 {
+        w.write_all(&0u16.to_le_bytes())?;
+    }
+
+    if fact_size > 0 {
+        w.write_all(b"fact")?;
+        w.write_all(&4u32.to_le_bytes())?;
+        w.write_all(&(frames as u32).to_le_bytes())?;
+    }
+
+    w.write_all(b"data")?;
+    w.write_all(&(data_size as u32).to_le_bytes())
+}
+
+/// Gets the format tag and the sizes of the fmt and fact chunks. Float data
+/// needs the extended fmt chunk and a fact chunk.
+fn chunk_sizes(format: SampleFormat) -> (u16, u32, u32) {
+    match format {
+        SampleFormat::F32 => (3, 18, 12),
+        SampleFormat::I16 | SampleFormat::I24 => (1, 16, 0),
+    }
+}
+
+/// Checks that a WAV file of `frames` stereo frames can be written, before
+/// any of them is.
+///
+/// # Return
+/// The size of the RIFF chunk
+fn check_length(format: SampleFormat, frames: u64) -> io::Result<u32> {
+    let (_, fmt_size, fact_size) = chunk_sizes(format);
+    let data_size = frames.saturating_mul(2 * format.bytes_per_sample() as u64);
+    let riff_size = 4 + (8 + fmt_size as u64) + fact_size as u64 + 8 + data_size;
+
+    u32::try_from(riff_size).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "render is too long for a WAV file",
+        )
+    })
+}
+
+pub(crate) fn write_samples<W: Write>(
+    w: &mut W,
+    samples: &[f32],
+    format: SampleFormat,
+) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(samples.len() * format.bytes_per_sample() as usize);
+
+    for &sample in samples {
+        match format {
+            SampleFormat::F32 => bytes.extend_from_slice(&sample.to_le_bytes()),
+            SampleFormat::I16 => {
+                bytes.extend_from_slice(&i16::from_f32(sample).to_le_bytes());
+            }
+            SampleFormat::I24 => {
+                let v = (sample.clamp(-1.0, 1.0) * 8388607.0).round() as i32;
+                bytes.extend_from_slice(&v.to_le_bytes()[..3]);
+            }
+        }
+    }
+
+    w.write_all(&bytes)
+}
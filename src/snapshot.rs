@@ -0,0 +1,258 @@
+//! Saving and restoring the playback state.
+//!
+//! The pure-Rust engine copies its playback state. libxm can't: its
+//! snapshots keep the calls that changed the playback since the module last
+//! played from the start (see `XMContext::seek_to_sample()`), and the module
+//! is played again up to the saved point, as fast as it can be, to restore
+//! them or to clone the context.
+//!
+//! # Example
+//! ```no_run
+//! use libxm::XMContext;
+//!
+//! # let data = Vec::new();
+//! let mut xm = XMContext::new(&data, 48000).unwrap();
+//! let mut buffer = [0.0; 4096];
+//!
+//! let checkpoint = xm.snapshot().unwrap();
+//! xm.generate_samples(&mut buffer);
+//!
+//! // Rewind
+//! xm.restore(&checkpoint).unwrap();
+//!
+//! // Render ahead without disturbing `xm`
+//! let mut lookahead = xm.try_clone().unwrap();
+//! lookahead.generate_samples(&mut buffer);
+//! ```
+
+use crate::backend::Player;
+use crate::events::EventState;
+use crate::pcm::DitherState;
+use crate::{replay, try_to_vec, Command, Settings, XMContext, XMError};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// The playback state of an `XMContext`, as returned by
+/// `XMContext::snapshot()`.
+///
+/// This is a copy of everything the engine keeps track of: position, tempo,
+/// notes, envelopes, effect memory, the random waveform generator and the
+/// counters returned by the `latest_trigger_*()` methods. The dither noise
+/// of `generate_samples_i16()` and `generate_samples_i32()` is saved too.
+/// The module itself is shared, not copied.
+///
+/// With libxm, this is what played the module up to that point instead.
+/// Playing it again gives the same state, except for the random vibrato and
+/// tremolo waveforms: libxm keeps a single random generator for every
+/// context.
+pub struct PlaybackState {
+    mod_data: Arc<[u8]>,
+    rate: u32,
+    // A copy of the player, if it can copy itself. Otherwise, `history` is
+    // played again up to `samples`.
+    player: Option<Box<dyn Player>>,
+    samples: u64,
+    history: Option<Vec<(u64, Command)>>,
+    settings: Settings,
+    playthrough: bool,
+    dither: DitherState,
+    event_state: Option<EventState>,
+    free_channels: u16,
+}
+
+impl PlaybackState {
+    /// Gets the number of samples that had been generated when the snapshot
+    /// was taken.
+    #[inline]
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// Copies the playback state.
+    ///
+    /// # Errors
+    /// `XMError::MemoryAllocationFailed` if the copy can't be allocated.
+    pub fn try_clone(&self) -> Result<PlaybackState, XMError> {
+        Ok(PlaybackState {
+            mod_data: Arc::clone(&self.mod_data),
+            rate: self.rate,
+            player: try_clone_player(&self.player)?,
+            samples: self.samples,
+            history: try_clone_history(&self.history)?,
+            settings: self.settings,
+            playthrough: self.playthrough,
+            dither: self.dither,
+            event_state: try_clone_event_state(&self.event_state)?,
+            free_channels: self.free_channels,
+        })
+    }
+}
+
+impl XMContext {
+    /// Saves the playback state.
+    ///
+    /// This copies the state of the engine, whose size depends on the
+    /// number of channels and instruments, not on how long the module has
+    /// been playing. With libxm, this copies the calls that changed the
+    /// playback instead.
+    ///
+    /// # Errors
+    /// `XMError::MemoryAllocationFailed` if the copy can't be allocated.
+    /// `XMError::Unsupported` with libxm, if too many calls changed the
+    /// playback since the module last played from the start to keep them.
+    pub fn snapshot(&self) -> Result<PlaybackState, XMError> {
+        let player = self.player.try_clone()?;
+        if player.is_none() && self.history.is_none() {
+            return Err(history_dropped());
+        }
+
+        Ok(PlaybackState {
+            mod_data: Arc::clone(&self.mod_data),
+            rate: self.rate,
+            player,
+            samples: self.position().samples,
+            history: try_clone_history(&self.history)?,
+            settings: self.settings,
+            playthrough: self.playthrough,
+            dither: self.dither,
+            event_state: try_clone_event_state(&self.event_state)?,
+            free_channels: self.free_channels,
+        })
+    }
+
+    /// Restores a playback state saved by `snapshot()`, on this context or
+    /// on a clone of it. Muted channels and instruments, the mixer settings
+    /// and the interpolation are left as they are; the free channels and
+    /// the other settings (maximum loop count, speed, pitch) are those of
+    /// the saved state.
+    ///
+    /// With libxm, the module is played again from the start up to the
+    /// saved state, which takes about as long as generating the samples in
+    /// between.
+    ///
+    /// # Errors
+    /// `XMError::MemoryAllocationFailed` if the copy of the state can't be
+    /// allocated. `XMError::Unsupported` with libxm in an arena, which
+    /// can't hold the context the module is played again with. The context
+    /// is then left unchanged.
+    pub fn restore(&mut self, state: &PlaybackState) -> Result<(), XMError> {
+        assert!(
+            Arc::ptr_eq(&self.mod_data, &state.mod_data) && self.rate == state.rate,
+            "the playback state was saved from a different context"
+        );
+
+        let mut player = match try_clone_player(&state.player)? {
+            Some(player) => player,
+            None => self.play_again_to(&state.history, state.samples)?,
+        };
+        let history = try_clone_history(&state.history)?;
+        let event_state = try_clone_event_state(&state.event_state)?;
+
+        let num_channels = (self.number_of_channels() + state.free_channels) as usize;
+        let mut muted_channels = try_to_vec(&self.muted_channels)?;
+        muted_channels
+            .try_reserve(num_channels.saturating_sub(muted_channels.len()))
+            .map_err(|_| XMError::MemoryAllocationFailed)?;
+        muted_channels.resize(num_channels, false);
+
+        player.set_interpolation(self.interpolation)?;
+        player.set_mixer(&self.mixer)?;
+        for (i, &muted) in muted_channels.iter().enumerate() {
+            player.mute_channel(i as u16 + 1, muted);
+        }
+        for (i, &muted) in self.muted_instruments.iter().enumerate() {
+            player.mute_instrument(i as u16 + 1, muted);
+        }
+
+        self.player = player;
+        self.history = history;
+        self.settings = state.settings;
+        self.playthrough = state.playthrough;
+        self.dither = state.dither;
+        self.event_state = event_state;
+        self.free_channels = state.free_channels;
+        self.muted_channels = muted_channels;
+        // The state may have been played with another interpolation or mixer
+        self.settings_changed();
+        Ok(())
+    }
+
+    /// Clones the context, including its playback state, like `snapshot()`
+    /// then `restore()`. The module is shared between both contexts.
+    ///
+    /// # Errors
+    /// `XMError::MemoryAllocationFailed` if the copy can't be allocated.
+    /// `XMError::Unsupported` with libxm in an arena, which can't hold a
+    /// second context, or if too many calls changed the playback to play
+    /// them again (see `snapshot()`).
+    pub fn try_clone(&self) -> Result<XMContext, XMError> {
+        let player = match self.player.try_clone()? {
+            Some(player) => player,
+            None => self.play_again_to(&self.history, self.position().samples)?,
+        };
+
+        Ok(XMContext {
+            player,
+            rate: self.rate,
+            mod_data: Arc::clone(&self.mod_data),
+            headers: Arc::clone(&self.headers),
+            muted_channels: try_to_vec(&self.muted_channels)?,
+            muted_instruments: try_to_vec(&self.muted_instruments)?,
+            settings: self.settings,
+            history: try_clone_history(&self.history)?,
+            playthrough: self.playthrough,
+            event_state: try_clone_event_state(&self.event_state)?,
+            dither: self.dither,
+            interpolation: self.interpolation,
+            format: self.format,
+            free_channels: self.free_channels,
+            mixer: self.mixer.try_clone()?,
+        })
+    }
+}
+
+impl XMContext {
+    /// Creates a player at the start of the module, and plays it up to
+    /// `samples` generated samples with the commands of `history`, for
+    /// players that can't copy themselves.
+    fn play_again_to(
+        &self,
+        history: &Option<Vec<(u64, Command)>>,
+        samples: u64,
+    ) -> Result<Box<dyn Player>, XMError> {
+        let history = history.as_ref().ok_or_else(history_dropped)?;
+        let mut player = self.player.try_new()?;
+        player.set_interpolation(self.interpolation)?;
+        player.set_mixer(&self.mixer)?;
+        replay(
+            &mut *player,
+            history,
+            samples,
+            &self.muted_channels,
+            &self.muted_instruments,
+        )?;
+        Ok(player)
+    }
+}
+
+fn try_clone_player(player: &Option<Box<dyn Player>>) -> Result<Option<Box<dyn Player>>, XMError> {
+    match player {
+        Some(player) => player.try_clone(),
+        None => Ok(None),
+    }
+}
+
+fn history_dropped() -> XMError {
+    XMError::Unsupported("too many calls changed the playback of libxm to play them again")
+}
+
+fn try_clone_history(
+    history: &Option<Vec<(u64, Command)>>,
+) -> Result<Option<Vec<(u64, Command)>>, XMError> {
+    history.as_deref().map(try_to_vec).transpose()
+}
+
+fn try_clone_event_state(state: &Option<EventState>) -> Result<Option<EventState>, XMError> {
+    state.as_ref().map(EventState::try_clone).transpose()
+}
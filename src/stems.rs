@@ -0,0 +1,57 @@
+use crate::{XMContext, XMError};
+
+impl XMContext {
+    /// Plays the module, putting the master mix and the sound of each
+    /// channel in separate output buffers. All outputs are in stereo.
+    ///
+    /// `outputs[0]` receives the master mix, the same as `generate_samples()`
+    /// would produce. `outputs[n]` receives what channel `n` contributes to
+    /// the mix. There can be fewer outputs than channels, in which case the
+    /// remaining channels aren't rendered on their own.
+    ///
+    /// Muted channels and instruments are silent in the channel outputs
+    /// too, so the channel outputs add up to the master mix (up to rounding),
+    /// minus the free channels added by `reserve_channels()`.
+    ///
+    /// Only built with the pure-Rust engine (the `pure-rust` feature).
+    ///
+    /// # Note
+    /// The engine mixes the channels once, and keeps what each of them
+    /// contributes on the way. This costs little more than
+    /// `generate_samples()`.
+    ///
+    /// # Return
+    /// The number of samples written to each output
+    ///
+    /// # Errors
+    /// `XMError::Unsupported` with `Backend::Libxm` (`libxm-reference`),
+    /// which can't render channels separately.
+    pub fn generate_samples_per_channel(
+        &mut self,
+        outputs: &mut [&mut [f32]],
+    ) -> Result<usize, XMError> {
+        assert!(!outputs.is_empty());
+        assert!(outputs.len() <= self.number_of_channels() as usize + 1);
+
+        let length = outputs[0].len();
+        assert!(outputs.iter().all(|output| output.len() == length));
+        // Output buffers must have a multiple-of-two length.
+        assert!(length.is_multiple_of(2));
+
+        let (master, channels) = outputs.split_first_mut().unwrap();
+        for output in channels.iter_mut() {
+            output.fill(0.0);
+        }
+        self.player.generate_samples_per_channel(
+            master,
+            &mut |frame, channel, left, right, _| {
+                // Free channels have no output
+                if let Some(output) = channels.get_mut(channel) {
+                    output[frame * 2] = left;
+                    output[frame * 2 + 1] = right;
+                }
+            },
+        )?;
+        Ok(length)
+    }
+}
@@ -0,0 +1,145 @@
+//! Rendered audio as an iterator of frames, or as a stream of bytes.
+//!
+//! # Example
+//! ```no_run
+//! use libxm::render::SampleFormat;
+//! use libxm::stream::XmReader;
+//! use libxm::XMContext;
+//! use std::fs::File;
+//! use std::io;
+//!
+//! # let data = Vec::new();
+//! let mut xm = XMContext::new(&data, 48000).unwrap();
+//! xm.set_max_loop_count(1);
+//!
+//! // Raw 16-bit stereo PCM
+//! let mut reader = XmReader::new(xm, SampleFormat::I16);
+//! io::copy(&mut reader, &mut File::create("song.raw").unwrap()).unwrap();
+//! ```
+
+use crate::render::{write_samples, SampleFormat};
+use crate::XMContext;
+use std::io::{self, Read};
+
+/// Number of stereo frames generated at a time.
+const CHUNK_FRAMES: usize = 1024;
+
+/// An iterator over the stereo frames of a module, as returned by
+/// `XMContext::frames()`.
+pub struct Frames<'a> {
+    xm: &'a mut XMContext,
+    buffer: Box<[f32]>,
+    // Index of the next sample of `buffer` to return
+    position: usize,
+    // Number of samples of `buffer` that were generated
+    length: usize,
+}
+
+impl XMContext {
+    /// Plays the module, as an iterator of `(left, right)` frames.
+    ///
+    /// The iterator ends once the module has looped as many times as set by
+    /// `set_max_loop_count()`. If the maximum loop count is 0, it never
+    /// ends.
+    ///
+    /// # Note
+    /// Samples are generated in chunks, and the end is only checked between
+    /// chunks, so the last frames can be silence generated after the loop.
+    pub fn frames(&mut self) -> Frames<'_> {
+        Frames {
+            xm: self,
+            buffer: vec![0.0; CHUNK_FRAMES * 2].into_boxed_slice(),
+            position: 0,
+            length: 0,
+        }
+    }
+}
+
+impl Iterator for Frames<'_> {
+    type Item = (f32, f32);
+
+    #[inline]
+    fn next(&mut self) -> Option<(f32, f32)> {
+        if self.position == self.length {
+            if self.xm.finished() {
+                return None;
+            }
+            self.length = self.xm.generate_samples(&mut self.buffer[..]);
+            self.position = 0;
+        }
+
+        let frame = (self.buffer[self.position], self.buffer[self.position + 1]);
+        self.position += 2;
+        Some(frame)
+    }
+}
+
+/// Plays a module as raw, interleaved stereo PCM, with little-endian
+/// samples.
+///
+/// Reads return 0 bytes (end of file) once the module has looped as many
+/// times as set by `XMContext::set_max_loop_count()`. As with
+/// `XMContext::frames()`, the last samples can be silence generated after
+/// the loop.
+pub struct XmReader {
+    xm: XMContext,
+    format: SampleFormat,
+    samples: Box<[f32]>,
+    bytes: Vec<u8>,
+    // Index of the next byte of `bytes` to read
+    position: usize,
+}
+
+impl XmReader {
+    /// Creates a reader playing `xm` from its current position.
+    pub fn new(xm: XMContext, format: SampleFormat) -> XmReader {
+        XmReader {
+            xm,
+            format,
+            samples: vec![0.0; CHUNK_FRAMES * 2].into_boxed_slice(),
+            bytes: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Gets the context being played.
+    #[inline]
+    pub fn context(&self) -> &XMContext {
+        &self.xm
+    }
+
+    /// Gets the context being played, to change its playback state.
+    ///
+    /// Changes are heard once the bytes already generated have been read.
+    #[inline]
+    pub fn context_mut(&mut self) -> &mut XMContext {
+        &mut self.xm
+    }
+
+    /// Gets back the context.
+    #[inline]
+    pub fn into_inner(self) -> XMContext {
+        self.xm
+    }
+}
+
+impl Read for XmReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.bytes.len() {
+            if self.xm.finished() {
+                return Ok(0);
+            }
+            self.xm.generate_samples(&mut self.samples[..]);
+            self.bytes.clear();
+            self.position = 0;
+            write_samples(&mut self.bytes, &self.samples, self.format)?;
+        }
+
+        let available = &self.bytes[self.position..];
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.position += length;
+
+        Ok(length)
+    }
+}
@@ -0,0 +1,394 @@
+//! Song timeline analysis: duration, loop point, and the time at which
+//! each row is played.
+//!
+//! The timeline is computed by running the sequencer the same way libxm
+//! does, tick by tick, without mixing any audio. It takes tempo and BPM
+//! changes, position jumps, pattern breaks, pattern loops and pattern delays
+//! into account.
+//!
+//! # Example
+//! ```no_run
+//! use libxm::XMContext;
+//!
+//! # let data = Vec::new();
+//! let xm = XMContext::new(&data, 48000).unwrap();
+//! let timeline = xm.analyze_timeline();
+//!
+//! let elapsed = xm.position().samples as f64 / timeline.rate as f64;
+//! println!("{:.0}s / {:.0}s", elapsed, timeline.duration_secs());
+//! ```
+
+use crate::math::ceilf;
+use crate::module::{self, Module};
+use crate::{XMContext, XMError};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Upper bound on the number of rows simulated, in case a module never
+/// loops in a way that libxm would detect.
+const MAX_ROWS: usize = 1 << 20;
+
+/// When a row is played.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RowTime {
+    /// Pattern index in the POT (pattern order table)
+    pub pattern_index: u8,
+    /// Pattern number
+    pub pattern: u8,
+    /// Row number
+    pub row: u8,
+    /// Number of samples generated before the row starts playing
+    pub samples: u64,
+}
+
+/// The timeline of a song, as returned by `XMContext::analyze_timeline()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Timeline {
+    /// The play rate in Hz the timeline was computed for
+    pub rate: u32,
+    /// Number of samples until the song loops (the first time
+    /// `loop_count()` would become 1)
+    pub duration: u64,
+    /// Where playback continues once the song loops, if it does: the first
+    /// time the row that is played again was played
+    pub loop_start: Option<RowTime>,
+    /// Every row, in playing order, up to the loop. Rows repeated by
+    /// pattern loops (E6y) appear once per repetition.
+    pub rows: Vec<RowTime>,
+}
+
+impl Timeline {
+    /// Analyzes a module from its data, like `XMContext::new()` would load it.
+    ///
+    /// # Parameters
+    /// * `mod_data` - The contents of the module.
+    /// * `rate` - The play rate in Hz.
+    pub fn from_bytes(mod_data: &[u8], rate: u32) -> Result<Timeline, XMError> {
+        Ok(analyze(&module::parse_headers(mod_data)?, rate))
+    }
+
+    /// Gets the duration of the song, in seconds.
+    #[inline]
+    pub fn duration_secs(&self) -> f64 {
+        self.duration as f64 / self.rate as f64
+    }
+
+    /// Gets the number of samples generated before a row starts playing for
+    /// the first time.
+    pub fn samples_of_position(&self, pattern_index: u8, row: u8) -> Option<u64> {
+        self.rows
+            .iter()
+            .find(|t| t.pattern_index == pattern_index && t.row == row)
+            .map(|t| t.samples)
+    }
+
+    /// Gets the row playing after a given number of generated samples.
+    ///
+    /// Past the end of the song, the song is assumed to loop forever.
+    pub fn position_at(&self, samples: u64) -> Option<RowTime> {
+        let samples = match self.loop_start {
+            Some(loop_start) if samples >= self.duration && self.duration > loop_start.samples => {
+                let loop_length = self.duration - loop_start.samples;
+                loop_start.samples + (samples - self.duration) % loop_length
+            }
+            _ => samples,
+        };
+
+        let i = self.rows.partition_point(|t| t.samples <= samples);
+        if i == 0 {
+            None
+        } else {
+            Some(self.rows[i - 1])
+        }
+    }
+}
+
+impl XMContext {
+    /// Computes the timeline of the module, at the rate and speed
+    /// multiplier of this context.
+    ///
+    /// This doesn't change the playback state.
+    pub fn analyze_timeline(&self) -> Timeline {
+        analyze_at_speed(&self.headers, self.rate, self.speed_multiplier())
+    }
+}
+
+/// Computes the timeline of a module.
+///
+/// # Parameters
+/// * `module` - The module. Its sample data isn't needed.
+/// * `rate` - The play rate in Hz.
+pub fn analyze(module: &Module, rate: u32) -> Timeline {
+    analyze_at_speed(module, rate, 1.0)
+}
+
+/// Computes the timeline of a module, with ticks shortened by `speed`.
+fn analyze_at_speed(module: &Module, rate: u32, speed: f32) -> Timeline {
+    let mut timeline = Timeline {
+        rate,
+        duration: 0,
+        loop_start: None,
+        rows: Vec::new(),
+    };
+    let mut sequencer = Sequencer::new(module, rate, speed);
+
+    while timeline.rows.len() < MAX_ROWS {
+        let step = match sequencer.next_row() {
+            Some(step) => step,
+            None => break,
+        };
+
+        if step.loop_count > 0 {
+            timeline.duration = step.time.samples;
+            timeline.loop_start = timeline
+                .rows
+                .iter()
+                .find(|t| t.pattern_index == step.time.pattern_index && t.row == step.time.row)
+                .copied();
+            return timeline;
+        }
+        timeline.rows.push(step.time);
+    }
+
+    // The song ended without looping
+    timeline.duration = sequencer.samples;
+    timeline
+}
+
+/// A row that was just processed.
+pub(crate) struct RowStep {
+    pub(crate) time: RowTime,
+    /// What libxm sets its loop count to when processing the row
+    pub(crate) loop_count: u8,
+}
+
+/// The parts of libxm's playback state that decide which row plays when.
+pub(crate) struct Sequencer<'a> {
+    module: &'a Module,
+    rate: u32,
+    // Multiplies the BPM
+    speed: f32,
+    tempo: u16,
+    bpm: u16,
+    current_table_index: u8,
+    current_row: u8,
+    current_tick: u16,
+    extra_ticks: u16,
+    position_jump: bool,
+    pattern_break: bool,
+    jump_dest: u8,
+    jump_row: u8,
+    remaining_samples_in_tick: f32,
+    // Per channel
+    pattern_loop_origin: Vec<u8>,
+    pattern_loop_count: Vec<u8>,
+    // Indexed by [table index * 256 + row]
+    row_loop_count: Vec<u8>,
+    /// Number of samples generated so far
+    pub(crate) samples: u64,
+}
+
+impl<'a> Sequencer<'a> {
+    pub(crate) fn new(module: &'a Module, rate: u32, speed: f32) -> Sequencer<'a> {
+        let num_channels = module.num_channels() as usize;
+
+        Sequencer {
+            module,
+            rate,
+            speed,
+            tempo: module.header.tempo,
+            bpm: module.header.bpm,
+            current_table_index: 0,
+            current_row: 0,
+            current_tick: 0,
+            extra_ticks: 0,
+            position_jump: false,
+            pattern_break: false,
+            jump_dest: 0,
+            jump_row: 0,
+            remaining_samples_in_tick: 0.0,
+            pattern_loop_origin: vec![0; num_channels],
+            pattern_loop_count: vec![0; num_channels],
+            row_loop_count: vec![0; module::MAX_PATTERN_TABLE_LENGTH * 256],
+            samples: 0,
+        }
+    }
+
+    /// Runs until the next row is processed.
+    ///
+    /// Returns None if the module has nothing to play.
+    pub(crate) fn next_row(&mut self) -> Option<RowStep> {
+        if self.module.pattern_table.is_empty() || self.bpm == 0 {
+            return None;
+        }
+
+        loop {
+            // Like xm_sample(): tick when the current tick is used up, then
+            // generate one sample
+            let step = if self.remaining_samples_in_tick <= 0.0 {
+                self.tick()
+            } else {
+                None
+            };
+
+            // Skip ahead to the sample of the next tick
+            let frames = ceilf(self.remaining_samples_in_tick).max(1.0);
+            let start = self.samples;
+            self.remaining_samples_in_tick -= frames;
+            self.samples += frames as u64;
+
+            if let Some(mut step) = step {
+                step.time.samples = start;
+                return Some(step);
+            }
+        }
+    }
+
+    fn tick(&mut self) -> Option<RowStep> {
+        let step = if self.current_tick == 0 {
+            Some(self.row())
+        } else {
+            None
+        };
+
+        self.current_tick += 1;
+        if self.current_tick >= self.tempo.saturating_add(self.extra_ticks) {
+            self.current_tick = 0;
+            self.extra_ticks = 0;
+        }
+
+        // FT2 manual says number of ticks / second = BPM * 0.4
+        self.remaining_samples_in_tick += self.rate as f32 / (self.bpm as f32 * 0.4 * self.speed);
+
+        step
+    }
+
+    fn row(&mut self) -> RowStep {
+        if self.position_jump {
+            self.current_table_index = self.jump_dest;
+            self.current_row = self.jump_row;
+            self.position_jump = false;
+            self.pattern_break = false;
+            self.jump_row = 0;
+            self.post_pattern_change();
+        } else if self.pattern_break {
+            self.current_table_index = self.current_table_index.wrapping_add(1);
+            self.current_row = self.jump_row;
+            self.pattern_break = false;
+            self.jump_row = 0;
+            self.post_pattern_change();
+        }
+
+        let module = self.module;
+        let pattern = module.pattern_table[self.current_table_index as usize];
+        let rows = module
+            .patterns
+            .get(pattern as usize)
+            .map_or(&[][..], |pattern| &pattern.rows[..]);
+        if self.current_row as usize >= rows.len() {
+            // A pattern break past the end of the pattern
+            self.current_row = 0;
+        }
+
+        let time = RowTime {
+            pattern_index: self.current_table_index,
+            pattern,
+            row: self.current_row,
+            samples: 0,
+        };
+
+        let mut in_a_loop = false;
+        if let Some(cells) = rows.get(self.current_row as usize) {
+            for (i, cell) in cells.iter().enumerate() {
+                self.handle_effect(i, cell.effect_type, cell.effect_param);
+                if self.pattern_loop_count[i] > 0 {
+                    in_a_loop = true;
+                }
+            }
+        }
+
+        let index = self.current_table_index as usize * 256 + self.current_row as usize;
+        let loop_count = if in_a_loop {
+            0
+        } else {
+            // No E6y loop is in effect (or we are in the first pass)
+            let count = self.row_loop_count[index];
+            self.row_loop_count[index] = count.wrapping_add(1);
+            count
+        };
+
+        self.current_row = self.current_row.wrapping_add(1);
+        if !self.position_jump
+            && !self.pattern_break
+            && (self.current_row as usize >= rows.len() || self.current_row == 0)
+        {
+            self.current_table_index = self.current_table_index.wrapping_add(1);
+            // This will be 0 most of the time, except when E60 is used
+            self.current_row = self.jump_row;
+            self.jump_row = 0;
+            self.post_pattern_change();
+        }
+
+        RowStep { time, loop_count }
+    }
+
+    fn handle_effect(&mut self, channel: usize, effect_type: u8, effect_param: u8) {
+        match effect_type {
+            // Bxx: Position jump
+            0xB if (effect_param as usize) < self.module.pattern_table.len() => {
+                self.position_jump = true;
+                self.jump_dest = effect_param;
+                self.jump_row = 0;
+            }
+            // Dxx: Pattern break
+            0xD => {
+                self.pattern_break = true;
+                self.jump_row = (effect_param >> 4) * 10 + (effect_param & 0x0F);
+            }
+            // E6y: Pattern loop
+            0xE if effect_param >> 4 == 0x6 => {
+                let y = effect_param & 0x0F;
+                if y != 0 {
+                    if y == self.pattern_loop_count[channel] {
+                        // Loop is over
+                        self.pattern_loop_count[channel] = 0;
+                    } else {
+                        // Jump to the beginning of the loop
+                        self.pattern_loop_count[channel] += 1;
+                        self.position_jump = true;
+                        self.jump_row = self.pattern_loop_origin[channel];
+                        self.jump_dest = self.current_table_index;
+                    }
+                } else {
+                    // Set loop start point
+                    self.pattern_loop_origin[channel] = self.current_row;
+                    // Replicate FT2 E60 bug
+                    self.jump_row = self.current_row;
+                }
+            }
+            // EEy: Pattern delay
+            0xE if effect_param >> 4 == 0xE => {
+                self.extra_ticks = ((effect_param & 0x0F) as u16).saturating_mul(self.tempo);
+            }
+            // Fxx: Set tempo/BPM
+            0xF if effect_param > 0 => {
+                if effect_param <= 0x1F {
+                    self.tempo = effect_param as u16;
+                } else {
+                    self.bpm = effect_param as u16;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn post_pattern_change(&mut self) {
+        // Loop if necessary
+        if self.current_table_index as usize >= self.module.pattern_table.len() {
+            self.current_table_index = self.module.header.restart_position as u8;
+            if self.current_table_index as usize >= self.module.pattern_table.len() {
+                self.current_table_index = 0;
+            }
+        }
+    }
+}
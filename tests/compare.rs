@@ -0,0 +1,587 @@
+//! Compares the pure-Rust engine with libxm, sample by sample.
+//!
+//! Both play the same synthetic modules, each exercising a group of
+//! effects. The output, the position and the trigger times must match.
+
+// Both backends are only built together with `libxm-reference`
+#![cfg(feature = "libxm-reference")]
+
+use libxm::module::{
+    Cell, Envelope, EnvelopePoint, FrequencyType, Header, Instrument, Module, Pattern, Sample,
+    SampleBuffer,
+};
+use libxm::{Backend, LoopType, XMContext};
+
+const RATE: u32 = 48000;
+
+// Both players compute in f32, and with `std` both take pow and sin from
+// the C library, so they only differ where the C compiler orders or fuses
+// (FMA) operations differently. These differences stay in the last bits of
+// frequencies and volumes, but sample positions accumulate them. 1e-4 is
+// -80 dBFS, 3 steps of 16-bit output: well below anything audible, and
+// well above rounding noise, while a wrong effect is off by far more.
+const TOLERANCE: f32 = 1e-4;
+
+fn cell(note: u8, instrument: u8, volume_column: u8, effect_type: u8, effect_param: u8) -> Cell {
+    Cell {
+        note,
+        instrument,
+        volume_column,
+        effect_type,
+        effect_param,
+    }
+}
+
+fn effect(effect_type: u8, effect_param: u8) -> Cell {
+    cell(0, 0, 0, effect_type, effect_param)
+}
+
+/// A pattern of `rows` empty rows, with the given cells set.
+fn pattern(rows: usize, num_channels: u16, cells: &[(usize, usize, Cell)]) -> Pattern {
+    let mut rows = vec![vec![Cell::default(); num_channels as usize]; rows];
+    for &(row, channel, cell) in cells {
+        rows[row][channel] = cell;
+    }
+    Pattern { rows }
+}
+
+fn envelope(
+    points: &[(u16, u16)],
+    sustain_point: Option<u8>,
+    loop_points: Option<(u8, u8)>,
+) -> Envelope {
+    Envelope {
+        points: points
+            .iter()
+            .map(|&(frame, value)| EnvelopePoint { frame, value })
+            .collect(),
+        sustain_point: sustain_point.unwrap_or(0),
+        loop_start_point: loop_points.map_or(0, |(start, _)| start),
+        loop_end_point: loop_points.map_or(0, |(_, end)| end),
+        enabled: true,
+        sustain_enabled: sustain_point.is_some(),
+        loop_enabled: loop_points.is_some(),
+    }
+}
+
+fn sample(data: SampleBuffer, loop_type: LoopType, loop_start: u32, loop_length: u32) -> Sample {
+    Sample {
+        name: b"sample".to_vec(),
+        loop_start,
+        loop_length,
+        loop_type,
+        volume: 48,
+        finetune: -16,
+        panning: 100,
+        relative_note: 3,
+        data,
+    }
+}
+
+/// An 8-bit sine, a 16-bit saw and a short 16-bit noise burst.
+fn samples() -> Vec<Sample> {
+    let sine = (0..256)
+        .map(|i| ((i as f32 / 32.0 * std::f32::consts::PI).sin() * 127.0) as i8)
+        .collect();
+    let saw = (0..1000).map(|i| (i * 65 - 32500) as i16).collect();
+    let mut seed = 1u32;
+    let noise = (0..3000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as i16
+        })
+        .collect();
+
+    vec![
+        sample(SampleBuffer::Bits8(sine), LoopType::Forward, 64, 128),
+        sample(SampleBuffer::Bits16(saw), LoopType::PingPong, 100, 700),
+        sample(SampleBuffer::Bits16(noise), LoopType::NoLoop, 0, 0),
+    ]
+}
+
+/// Instrument 1 plays the three samples over the keyboard, with
+/// envelopes. Instrument 2 plays the saw alone, with autovibrato.
+fn instruments() -> Vec<Instrument> {
+    let mut sample_of_notes = [0; 96];
+    for (note, sample) in sample_of_notes.iter_mut().enumerate() {
+        *sample = (note / 32) as u8;
+    }
+
+    let samples = samples();
+    let saw = samples[1].clone();
+
+    vec![
+        Instrument {
+            name: b"enveloped".to_vec(),
+            instrument_type: 0,
+            sample_of_notes,
+            volume_envelope: envelope(
+                &[(0, 0), (4, 64), (10, 32), (20, 48), (40, 0)],
+                Some(2),
+                Some((2, 3)),
+            ),
+            panning_envelope: envelope(&[(0, 0), (8, 64), (16, 32)], None, None),
+            vibrato_type: 0,
+            vibrato_sweep: 0,
+            vibrato_depth: 0,
+            vibrato_rate: 0,
+            volume_fadeout: 2048,
+            samples,
+        },
+        Instrument {
+            name: b"autovibrato".to_vec(),
+            instrument_type: 0,
+            sample_of_notes: [0; 96],
+            volume_envelope: Envelope::default(),
+            panning_envelope: Envelope::default(),
+            vibrato_type: 1,
+            vibrato_sweep: 8,
+            vibrato_depth: 6,
+            vibrato_rate: 20,
+            volume_fadeout: 0,
+            samples: vec![saw],
+        },
+    ]
+}
+
+fn module(frequency_type: FrequencyType, patterns: Vec<Pattern>, pattern_table: Vec<u8>) -> Module {
+    let num_channels = patterns[0].rows[0].len() as u16;
+    Module {
+        header: Header {
+            name: b"compare".to_vec(),
+            tracker_name: b"libxm-rs".to_vec(),
+            version: 0x0104,
+            restart_position: 0,
+            num_channels,
+            frequency_type,
+            tempo: 6,
+            bpm: 125,
+        },
+        pattern_table,
+        patterns,
+        instruments: instruments(),
+    }
+}
+
+/// Plays `module` with both players for `frames` frames, and checks that
+/// they agree.
+fn compare(module: &Module, frames: usize) {
+    compare_playing(module, frames, |_, _| {});
+}
+
+/// Like `compare()`, calling `play` on both contexts with the number of
+/// frames played so far, every 1024 frames.
+fn compare_playing(module: &Module, frames: usize, play: impl Fn(&mut XMContext, usize)) {
+    let data = module.to_bytes().unwrap();
+    let mut xm = XMContext::with_backend(&data, RATE, Backend::Libxm).unwrap();
+    let mut engine = XMContext::with_backend(&data, RATE, Backend::PureRust).unwrap();
+    xm.set_max_loop_count(2);
+    engine.set_max_loop_count(2);
+
+    let mut expected = vec![0.0f32; 1024];
+    let mut actual = vec![0.0f32; 1024];
+    let mut done = 0;
+    while done < frames {
+        play(&mut xm, done);
+        play(&mut engine, done);
+        xm.generate_samples(&mut expected[..]);
+        engine.generate_samples(&mut actual[..]);
+
+        for (i, (e, a)) in expected.iter().zip(&actual).enumerate() {
+            assert!(
+                (e - a).abs() <= TOLERANCE,
+                "frame {}: libxm played {}, the engine played {}",
+                done + i / 2,
+                e,
+                a
+            );
+        }
+
+        let (position, engine_position) = (xm.position(), engine.position());
+        assert_eq!(
+            (
+                position.pattern_index,
+                position.pattern,
+                position.row,
+                position.samples
+            ),
+            (
+                engine_position.pattern_index,
+                engine_position.pattern,
+                engine_position.row,
+                engine_position.samples
+            )
+        );
+        assert_eq!(xm.loop_count(), engine.loop_count());
+        for channel in 1..=xm.number_of_channels() {
+            assert_eq!(
+                xm.latest_trigger_of_channel(channel),
+                engine.latest_trigger_of_channel(channel)
+            );
+        }
+        for instrument in 1..=xm.number_of_instruments() {
+            assert_eq!(
+                xm.latest_trigger_of_instrument(instrument),
+                engine.latest_trigger_of_instrument(instrument)
+            );
+        }
+
+        done += expected.len() / 2;
+    }
+}
+
+#[test]
+fn notes_and_samples() {
+    let patterns = vec![pattern(
+        32,
+        4,
+        &[
+            (0, 0, cell(25, 1, 0, 0, 0)),
+            (0, 1, cell(49, 1, 0, 0, 0)),
+            (0, 2, cell(73, 1, 0, 0, 0)),
+            (0, 3, cell(49, 2, 0, 0, 0)),
+            (4, 0, cell(Cell::KEY_OFF, 0, 0, 0, 0)),
+            (6, 1, cell(Cell::KEY_OFF, 0, 0, 0, 0)),
+            (8, 0, cell(37, 0, 0, 0, 0)),
+            (8, 3, cell(61, 0, 0, 0, 0)),
+            (12, 1, cell(0, 1, 0, 0, 0)),
+            // Instrument 9 doesn't exist, this cuts the note
+            (16, 0, cell(30, 9, 0, 0, 0)),
+            (16, 2, cell(80, 1, 0x30, 9, 4)),
+            (20, 3, cell(Cell::KEY_OFF, 0, 0, 0, 0)),
+            (24, 0, cell(40, 1, 0, 20, 3)),
+        ],
+    )];
+
+    compare(
+        &module(FrequencyType::Linear, patterns.clone(), vec![0]),
+        200_000,
+    );
+    compare(&module(FrequencyType::Amiga, patterns, vec![0]), 200_000);
+}
+
+#[test]
+fn pitch_effects() {
+    let patterns = vec![pattern(
+        48,
+        4,
+        &[
+            (0, 0, cell(49, 2, 0, 0, 0x37)),
+            (0, 1, cell(37, 1, 0, 1, 8)),
+            (0, 2, cell(61, 1, 0, 4, 0x46)),
+            (0, 3, cell(49, 1, 0xB4, 0, 0)),
+            (4, 0, effect(0, 0x47)),
+            (4, 1, effect(2, 16)),
+            (4, 2, effect(4, 0)),
+            (8, 0, cell(56, 0, 0, 3, 12)),
+            (8, 1, effect(0xE, 0x13)),
+            (8, 2, effect(0xE, 0x42)),
+            (9, 2, effect(4, 0x83)),
+            (10, 0, effect(3, 0)),
+            (12, 1, effect(0xE, 0x24)),
+            (12, 3, cell(40, 0, 0xF8, 0, 0)),
+            (16, 0, cell(44, 0, 0, 5, 0x02)),
+            (16, 1, effect(33, 0x17)),
+            (16, 2, cell(0, 0, 0, 6, 0x20)),
+            (20, 1, effect(33, 0x25)),
+            (24, 0, cell(49, 1, 0, 0xE, 0x58)),
+            (24, 2, cell(49, 2, 0xA8, 0, 0)),
+            (26, 2, cell(0, 0, 0xB6, 0, 0)),
+            (28, 1, cell(52, 1, 0, 0, 0x0C)),
+            (32, 0, cell(52, 1, 0, 0xE, 0x40)),
+            (33, 0, effect(4, 0x8F)),
+            (36, 0, cell(52, 1, 0, 4, 0x28)),
+        ],
+    )];
+
+    compare(
+        &module(FrequencyType::Linear, patterns.clone(), vec![0]),
+        250_000,
+    );
+    compare(&module(FrequencyType::Amiga, patterns, vec![0]), 250_000);
+}
+
+#[test]
+fn volume_and_panning_effects() {
+    let patterns = vec![pattern(
+        48,
+        4,
+        &[
+            (0, 0, cell(49, 1, 0x50, 0xA, 0x04)),
+            (0, 1, cell(49, 2, 0x20, 0xA, 0x30)),
+            (0, 2, cell(37, 2, 0, 7, 0x48)),
+            (0, 3, cell(61, 1, 0, 8, 0x20)),
+            (4, 0, effect(0xE, 0xA4)),
+            (4, 1, effect(0xE, 0xB2)),
+            (4, 3, effect(25, 0x40)),
+            (8, 0, cell(0, 0, 0x64, 0xC, 0x30)),
+            (8, 1, cell(0, 0, 0x73, 0, 0)),
+            (8, 2, effect(0xE, 0x72)),
+            (9, 2, effect(7, 0x94)),
+            (12, 0, cell(0, 0, 0x85, 0, 0)),
+            (12, 1, cell(0, 0, 0x92, 0, 0)),
+            (12, 3, cell(0, 0, 0xD4, 25, 0x03)),
+            (16, 0, effect(16, 0x20)),
+            (16, 3, cell(0, 0, 0xE4, 0, 0)),
+            (17, 0, effect(17, 0x02)),
+            (20, 0, effect(17, 0x30)),
+            (20, 1, cell(0, 0, 0xC3, 0, 0)),
+            (24, 2, cell(49, 2, 0, 29, 0x21)),
+            (28, 0, cell(49, 1, 0, 21, 12)),
+            (32, 1, cell(49, 2, 0, 27, 0x62)),
+            (32, 2, cell(49, 2, 0x40, 27, 0xE3)),
+            (36, 1, effect(27, 0x03)),
+            (40, 0, cell(49, 1, 0, 27, 0x11)),
+        ],
+    )];
+
+    compare(&module(FrequencyType::Linear, patterns, vec![0]), 250_000);
+}
+
+#[test]
+fn note_effects() {
+    let patterns = vec![pattern(
+        32,
+        3,
+        &[
+            (0, 0, cell(49, 2, 0, 0xE, 0x92)),
+            (0, 1, cell(37, 2, 0, 0xE, 0xC3)),
+            (0, 2, cell(61, 1, 0, 0xE, 0xD4)),
+            (4, 0, effect(0xE, 0x93)),
+            (4, 1, cell(40, 2, 0, 0xE, 0xD2)),
+            (4, 2, effect(0xE, 0xD0)),
+            (8, 0, cell(49, 2, 0, 9, 2)),
+            (8, 1, cell(0, 0, 0, 0xE, 0xD3)),
+            (8, 2, cell(Cell::KEY_OFF, 0, 0, 0, 0)),
+            (12, 0, cell(49, 2, 0, 9, 0xFF)),
+            (12, 2, cell(0, 1, 0, 20, 0)),
+            // Instrument 3 doesn't exist, this cuts the note
+            (16, 0, cell(49, 3, 0, 0, 0)),
+            (16, 1, cell(52, 2, 0, 0xE, 0x50)),
+        ],
+    )];
+
+    compare(&module(FrequencyType::Linear, patterns, vec![0]), 150_000);
+}
+
+#[test]
+fn live_notes() {
+    let patterns = vec![pattern(
+        16,
+        2,
+        &[
+            (0, 0, cell(49, 1, 0, 0, 0)),
+            (4, 1, cell(37, 2, 0, 4, 0x46)),
+            (8, 0, effect(0xA, 0x04)),
+            (12, 1, cell(61, 1, 0, 0, 0)),
+        ],
+    )];
+
+    // A row is 5760 frames long, a tick 960
+    compare_playing(
+        &module(FrequencyType::Linear, patterns, vec![0]),
+        150_000,
+        |xm, frame| match frame / 1024 {
+            3 => xm.note_on(1, 2, 61, 0.75).unwrap(),
+            10 => xm.note_on(2, 1, 25, 1.0).unwrap(),
+            17 => xm.note_off(1),
+            // On the first tick of row 8, where channel 1 has an effect
+            45 => {
+                xm.note_on(1, 1, 73, 0.5).unwrap();
+                xm.note_on(2, 2, 44, 1.0).unwrap();
+            }
+            60 => xm.note_off(2),
+            // On the first tick of row 0, in place of its note
+            90 => xm.note_on(1, 2, 30, 0.25).unwrap(),
+            _ => {}
+        },
+    );
+}
+
+// libxm only takes the settings of the channels with the `ramping` feature
+#[cfg(feature = "ramping")]
+#[test]
+fn mixer_settings() {
+    let patterns = vec![pattern(
+        32,
+        4,
+        &[
+            (0, 0, cell(49, 1, 0, 0xA, 0x02)),
+            (0, 1, cell(49, 2, 0, 0, 0)),
+            (0, 2, cell(37, 2, 0, 8, 0x20)),
+            (0, 3, cell(61, 1, 0, 25, 0x03)),
+            (8, 1, cell(0, 0, 0xD4, 0, 0)),
+            (16, 0, cell(49, 1, 0, 0, 0)),
+            (16, 2, cell(37, 2, 0, 0, 0)),
+            (24, 3, cell(61, 1, 0, 8, 0xE0)),
+        ],
+    )];
+
+    compare_playing(
+        &module(FrequencyType::Linear, patterns, vec![0]),
+        250_000,
+        |xm, frame| match frame / 1024 {
+            5 => xm.set_channel_volume(1, 0.5).unwrap(),
+            20 => xm.set_channel_panning(2, 0.1).unwrap(),
+            40 => xm.set_stereo_separation(0.6).unwrap(),
+            60 => xm.set_master_volume(0.25).unwrap(),
+            80 => xm.set_amiga_panning(true).unwrap(),
+            100 => xm.reset_channel_panning(2).unwrap(),
+            120 => {
+                xm.set_channel_volume(1, 1.0).unwrap();
+                xm.set_stereo_separation(1.0).unwrap();
+                xm.set_amiga_panning(false).unwrap();
+                xm.set_master_volume(1.0).unwrap();
+            }
+            _ => {}
+        },
+    );
+}
+
+/// Jumps, breaks, pattern loops, tempo changes, and a restart position.
+fn song_flow_module() -> Module {
+    let patterns = vec![
+        pattern(
+            16,
+            2,
+            &[
+                (0, 0, cell(49, 2, 0, 0xF, 4)),
+                (2, 1, cell(37, 1, 0, 0xE, 0x60)),
+                (5, 1, effect(0xE, 0x62)),
+                (6, 0, effect(0xE, 0xE2)),
+                (8, 0, effect(0xF, 150)),
+                (10, 1, effect(0xD, 0x04)),
+            ],
+        ),
+        pattern(
+            8,
+            2,
+            &[
+                (0, 0, cell(37, 2, 0, 0xF, 3)),
+                (4, 1, cell(61, 1, 0, 0xF, 90)),
+                (6, 0, effect(0xB, 3)),
+            ],
+        ),
+        pattern(
+            64,
+            2,
+            &[
+                (0, 0, cell(44, 2, 0, 0, 0)),
+                (2, 1, cell(0, 0, 0, 0xD, 0x99)),
+            ],
+        ),
+        pattern(
+            4,
+            2,
+            &[(0, 0, cell(49, 1, 0, 0, 0)), (3, 1, effect(0xB, 2))],
+        ),
+    ];
+
+    let mut module = module(FrequencyType::Linear, patterns, vec![0, 1, 2, 2, 3]);
+    module.header.restart_position = 1;
+    module
+}
+
+#[test]
+fn song_flow() {
+    compare(&song_flow_module(), 600_000);
+}
+
+/// Both players report the same events, for the rows they process and the
+/// notes their channels play, live notes included.
+#[test]
+fn events_match() {
+    let data = song_flow_module().to_bytes().unwrap();
+    let mut xm = XMContext::with_backend(&data, RATE, Backend::Libxm).unwrap();
+    let mut engine = XMContext::with_backend(&data, RATE, Backend::PureRust).unwrap();
+
+    let mut output = vec![0.0f32; 2 * 1000];
+    for chunk in 0..200 {
+        let (mut expected, mut actual) = (Vec::new(), Vec::new());
+        for (context, events) in [(&mut xm, &mut expected), (&mut engine, &mut actual)] {
+            match chunk {
+                20 => context.note_on(1, 2, 61, 1.0).unwrap(),
+                45 => context.note_off(1),
+                _ => {}
+            }
+            context.generate_samples_with_events(&mut output, events);
+        }
+        assert_eq!(expected, actual, "chunk {}", chunk);
+    }
+}
+
+/// Seeking plays exactly what a straight playthrough plays, with either
+/// player, even after the playback was changed on the way.
+#[test]
+fn seeking_matches_a_straight_playthrough() {
+    let data = song_flow_module().to_bytes().unwrap();
+
+    for backend in [Backend::Libxm, Backend::PureRust] {
+        let mut reference = vec![0.0f32; 2 * 200_000];
+        let mut xm = XMContext::with_backend(&data, RATE, backend).unwrap();
+        xm.set_max_loop_count(2);
+        xm.generate_samples(&mut reference);
+
+        let mut xm = XMContext::with_backend(&data, RATE, backend).unwrap();
+        xm.set_max_loop_count(2);
+        let mut output = vec![0.0f32; 2 * 20_000];
+        xm.generate_samples(&mut output);
+        xm.seek(2, 10, 0);
+        xm.set_tempo(3);
+        xm.generate_samples(&mut output);
+
+        // Backward, backward, forward, then on from where it is
+        for &(from, frames) in &[
+            (30_000, 20_000),
+            (10_000, 5_000),
+            (150_000, 20_000),
+            (170_000, 10_000),
+        ] {
+            xm.seek_to_sample(from as u64).unwrap();
+            let output = &mut output[..2 * frames];
+            xm.generate_samples(output);
+            assert!(
+                *output == reference[2 * from..2 * (from + frames)],
+                "{:?}: seeking to {} doesn't play the same samples",
+                backend,
+                from
+            );
+        }
+    }
+}
+
+/// Restoring a snapshot, or cloning the context, plays on exactly as the
+/// context did, with either player.
+#[test]
+fn snapshots_play_on_the_same() {
+    let data = song_flow_module().to_bytes().unwrap();
+
+    for backend in [Backend::Libxm, Backend::PureRust] {
+        let mut xm = XMContext::with_backend(&data, RATE, backend).unwrap();
+        xm.set_max_loop_count(2);
+        let mut output = vec![0.0f32; 2 * 50_000];
+        xm.generate_samples(&mut output[..2 * 20_000]);
+        xm.seek(2, 10, 0);
+        xm.set_tempo(3);
+        xm.generate_samples(&mut output[..2 * 5_000]);
+
+        let state = xm.snapshot().unwrap();
+        let mut clone = xm.try_clone().unwrap();
+        let mut expected = vec![0.0f32; 2 * 50_000];
+        xm.generate_samples(&mut expected);
+
+        clone.generate_samples(&mut output);
+        assert!(
+            output == expected,
+            "{:?}: the clone plays otherwise",
+            backend
+        );
+        xm.restore(&state).unwrap();
+        xm.generate_samples(&mut output);
+        assert!(
+            output == expected,
+            "{:?}: the snapshot plays otherwise",
+            backend
+        );
+    }
+}
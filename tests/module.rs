@@ -0,0 +1,243 @@
+//! Checks `libxm::module`: modules must survive a round trip through the
+//! XM format, and `Module::parse` must accept the same data as libxm and
+//! `XMContext::new` do, and find the same structure in it as libxm.
+
+use libxm::module::{
+    Cell, Envelope, FrequencyType, Header, Instrument, Module, Pattern, Sample, SampleBuffer,
+};
+use libxm::LoopType;
+use std::io::ErrorKind;
+
+#[cfg(any(not(feature = "pure-rust"), feature = "libxm-reference"))]
+const RATE: u32 = 48000;
+
+fn sample(data: SampleBuffer, loop_type: LoopType, loop_start: u32, loop_length: u32) -> Sample {
+    Sample {
+        name: b"sample".to_vec(),
+        loop_start,
+        loop_length,
+        loop_type,
+        volume: 64,
+        finetune: 0,
+        panning: 128,
+        relative_note: 0,
+        data,
+    }
+}
+
+fn instrument(samples: Vec<Sample>) -> Instrument {
+    Instrument {
+        name: b"instrument".to_vec(),
+        instrument_type: 0,
+        sample_of_notes: [0; 96],
+        volume_envelope: Envelope::default(),
+        panning_envelope: Envelope::default(),
+        vibrato_type: 0,
+        vibrato_sweep: 0,
+        vibrato_depth: 0,
+        vibrato_rate: 0,
+        volume_fadeout: 0,
+        samples,
+    }
+}
+
+/// A small module with packed and empty patterns, and instruments with 8
+/// and 16-bit samples, or none.
+fn module() -> Module {
+    let note = Cell {
+        note: 49,
+        instrument: 1,
+        volume_column: 0x40,
+        effect_type: 0,
+        effect_param: 0,
+    };
+    let mut rows = vec![vec![Cell::default(); 2]; 8];
+    rows[0][0] = note;
+    rows[4][1] = Cell {
+        instrument: 2,
+        ..note
+    };
+
+    Module {
+        header: Header {
+            name: b"module".to_vec(),
+            tracker_name: b"libxm-rs".to_vec(),
+            version: 0x0104,
+            restart_position: 0,
+            num_channels: 2,
+            frequency_type: FrequencyType::Linear,
+            tempo: 6,
+            bpm: 125,
+        },
+        pattern_table: vec![0, 1, 0],
+        patterns: vec![
+            Pattern { rows },
+            Pattern {
+                rows: vec![vec![Cell::default(); 2]; 16],
+            },
+        ],
+        instruments: vec![
+            instrument(vec![
+                sample(
+                    SampleBuffer::Bits8((0..100).map(|i| i as i8).collect()),
+                    LoopType::Forward,
+                    20,
+                    60,
+                ),
+                sample(
+                    SampleBuffer::Bits16((0..100).map(|i| i * 300).collect()),
+                    LoopType::PingPong,
+                    10,
+                    80,
+                ),
+            ]),
+            instrument(Vec::new()),
+        ],
+    }
+}
+
+#[test]
+fn round_trip() {
+    let module = module();
+    let data = module.to_bytes().unwrap();
+    let parsed = Module::parse(&data).unwrap();
+    assert_eq!(parsed, module);
+    assert_eq!(parsed.to_bytes().unwrap(), data);
+}
+
+/// Whether `to_bytes()` refuses a module changed by `change`.
+fn rejected(change: fn(&mut Module)) -> bool {
+    let mut module = module();
+    change(&mut module);
+    module.to_bytes().map_err(|err| err.kind()) == Err(ErrorKind::InvalidInput)
+}
+
+#[test]
+fn rejects_what_xm_cant_represent() {
+    assert!(rejected(|module| module.pattern_table.push(2)));
+    assert!(rejected(|module| {
+        module.patterns[1].rows = vec![vec![Cell::default(); 2]; 257];
+    }));
+    assert!(rejected(|module| {
+        module.patterns[0].rows[3].pop();
+    }));
+    assert!(rejected(|module| {
+        module.instruments[0].samples[1].loop_length = 91;
+    }));
+    assert!(rejected(|module| {
+        module.instruments[0].samples[0].loop_start = u32::MAX;
+    }));
+}
+
+/// Whether libxm loads the data.
+#[cfg(any(not(feature = "pure-rust"), feature = "libxm-reference"))]
+fn libxm_accepts(data: &[u8]) -> bool {
+    use libxm::ffi;
+
+    unsafe {
+        let mut context = std::ptr::null_mut();
+        let result = ffi::xm_create_context_safe(
+            &mut context,
+            data.as_ptr() as *const ffi::c_char,
+            data.len(),
+            RATE,
+        );
+        if result == 0 {
+            ffi::xm_free_context(context);
+        }
+        result == 0
+    }
+}
+
+/// Checks that `Module::parse` accepts `data` if and only if libxm and
+/// `XMContext::new` do, for the same reason, and that both find the same
+/// patterns, instruments and samples as libxm. `validate` may only be
+/// stricter.
+#[cfg(any(not(feature = "pure-rust"), feature = "libxm-reference"))]
+fn check(data: &[u8]) {
+    use libxm::module::validate;
+    use libxm::{Backend, XMContext, XMError};
+
+    let parsed = Module::parse(data);
+    assert_eq!(
+        parsed.is_ok(),
+        libxm_accepts(data),
+        "Module::parse returned {:?}",
+        parsed.as_ref().err()
+    );
+    if validate(data).is_ok() {
+        assert!(parsed.is_ok());
+    }
+
+    let (module, xm) = match (parsed, XMContext::with_backend(data, RATE, Backend::Libxm)) {
+        (Ok(module), Ok(xm)) => (module, xm),
+        (Err(XMError::InvalidModule(expected)), Err(XMError::InvalidModule(err))) => {
+            assert_eq!(err, expected);
+            return;
+        }
+        (parsed, loaded) => panic!(
+            "Module::parse returned {:?}, XMContext::new returned {:?}",
+            parsed.err(),
+            loaded.err()
+        ),
+    };
+
+    assert_eq!(xm.number_of_channels(), module.num_channels());
+    assert_eq!(xm.module_length() as usize, module.pattern_table.len());
+    assert_eq!(xm.number_of_patterns() as usize, module.patterns.len());
+    for (i, pattern) in module.patterns.iter().enumerate() {
+        assert_eq!(xm.number_of_rows(i as u16) as usize, pattern.rows.len());
+    }
+    assert_eq!(
+        xm.number_of_instruments() as usize,
+        module.instruments.len()
+    );
+    for (i, instrument) in module.instruments.iter().enumerate() {
+        assert_eq!(
+            xm.number_of_samples(i as u16 + 1) as usize,
+            instrument.samples.len()
+        );
+    }
+}
+
+// libxm is only built without `pure-rust`, or with `libxm-reference`
+#[cfg(any(not(feature = "pure-rust"), feature = "libxm-reference"))]
+#[test]
+fn accepts_the_same_data_as_xm_context() {
+    use libxm::module::validate;
+
+    let data = module().to_bytes().unwrap();
+    check(&data);
+
+    // Cut short anywhere: libxm reads the missing data as zeros, only
+    // `validate` tells
+    for length in 0..data.len() {
+        check(&data[..length]);
+        assert!(validate(&data[..length]).is_err());
+    }
+
+    let corruptions = [
+        // Magic, version
+        (0, b'e'),
+        (37, 0),
+        (58, 0x03),
+        // A header size pointing elsewhere, or past the end
+        (60, 0x20),
+        (62, 0xFF),
+        // More channels than the packed data fills
+        (68, 3),
+        (69, 1),
+        // More patterns and instruments than the data holds
+        (70, 3),
+        (72, 3),
+        // Nonexistent patterns in the order table: libxm drops the last
+        // entry if it is one, and rejects any other
+        (82, 2),
+        (80, 2),
+    ];
+    for (offset, value) in corruptions {
+        let mut corrupted = data.clone();
+        corrupted[offset] = value;
+        check(&corrupted);
+    }
+}